@@ -1,26 +1,153 @@
 use {
+    futures::{
+        stream,
+        Stream,
+    },
     http::{
-        header::AUTHORIZATION,
+        header::{
+            AUTHORIZATION,
+            CONNECTION,
+            CONTENT_TYPE,
+            ETAG,
+            IF_MATCH,
+            IF_NONE_MATCH,
+        },
+        HeaderMap,
+        Method,
+        StatusCode,
         Uri,
     },
     htwrap::{
         htreq,
         url::UriJoin,
     },
-    loga::Log,
+    loga::{
+        ea,
+        Log,
+    },
+    serde::{
+        de::DeserializeOwned,
+        Deserialize,
+        Serialize,
+    },
     std::{
         collections::HashMap,
         env,
-        sync::Arc,
+        sync::{
+            Arc,
+            Mutex,
+        },
+        time::{
+            Duration,
+            Instant,
+        },
     },
 };
 
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
-pub struct Error(pub String);
+/// Details the server sent with an error response.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ErrorResponse {
+    pub message: String,
+    /// The data path the error relates to. For JSON patches this is the path of the
+    /// failing operation.
+    #[serde(default)]
+    pub path: Option<Vec<String>>,
+    /// For `Error::SchemaViolation`, where the data doesn't match the schema.
+    #[serde(default)]
+    pub violations: Vec<SchemaViolation>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SchemaViolation {
+    /// JSON pointer to the invalid data, from the root of the database.
+    pub pointer: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.path {
+            Some(path) => return write!(f, "{} (at /{})", self.message, path.join("/")),
+            None => return self.message.fmt(f),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The request was invalid, ex: bad JSON or a path that can't be written.
+    BadRequest(ErrorResponse),
+    /// The token is missing or unknown to the server.
+    Unauthorized(ErrorResponse),
+    /// The token isn't granted access to the path.
+    Forbidden(ErrorResponse),
+    /// Returned for paths with no data when a missing value can't be represented
+    /// by the method's return type.
+    NotFound(ErrorResponse),
+    /// A JSON patch `Test` operation failed.
+    Conflict(ErrorResponse),
+    /// The data at the path was modified (or deleted) since the etag was retrieved.
+    PreconditionFailed(ErrorResponse),
+    /// The write would make the data not match the server's schema. The violations
+    /// are in the response.
+    SchemaViolation(ErrorResponse),
+    /// Any other error response from the server.
+    Status {
+        status: u16,
+        response: ErrorResponse,
+    },
+    /// The request couldn't be sent or the response couldn't be received.
+    Transport(String),
+    /// The server sent a successful response that couldn't be understood.
+    InvalidResponse(String),
+    /// Data from the server didn't match the requested type. `path` is the full data
+    /// path of the value that failed to deserialize.
+    Deserialize {
+        path: Vec<String>,
+        message: String,
+    },
+    /// The value couldn't be converted to JSON.
+    Serialize {
+        path: Vec<String>,
+        message: String,
+    },
+    /// Client configuration or request construction failed.
+    Other(String),
+}
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        return self.0.fmt(f);
+        match self {
+            Error::BadRequest(e) => return write!(f, "Bad request: {}", e),
+            Error::Unauthorized(e) => return write!(f, "Unauthorized: {}", e),
+            Error::Forbidden(e) => return write!(f, "Forbidden: {}", e),
+            Error::NotFound(e) => return write!(f, "Not found: {}", e),
+            Error::Conflict(e) => return write!(f, "Conflict: {}", e),
+            Error::PreconditionFailed(e) => return write!(f, "Precondition failed: {}", e),
+            Error::SchemaViolation(e) => {
+                write!(f, "Schema violation: {}", e)?;
+                for v in &e.violations {
+                    write!(f, "\n- at [{}]: {}", v.pointer, v.message)?;
+                }
+                return Ok(());
+            },
+            Error::Status { status, response } => return write!(f, "Received error response {}: {}", status, response),
+            Error::Transport(e) => return write!(f, "Error communicating with server: {}", e),
+            Error::InvalidResponse(e) => return write!(f, "Received invalid response: {}", e),
+            Error::Deserialize { path, message } => return write!(
+                f,
+                "Error deserializing data at /{}: {}",
+                path.join("/"),
+                message
+            ),
+            Error::Serialize { path, message } => return write!(
+                f,
+                "Error serializing data for /{}: {}",
+                path.join("/"),
+                message
+            ),
+            Error::Other(e) => return e.fmt(f),
+        }
     }
 }
 
@@ -28,7 +155,174 @@ impl std::error::Error for Error { }
 
 impl From<loga::Error> for Error {
     fn from(value: loga::Error) -> Self {
-        return Self(value.to_string());
+        return Self::Transport(value.to_string());
+    }
+}
+
+/// Deserialize data retrieved from `path`, reporting the full path of the value
+/// that failed.
+fn from_value<T: DeserializeOwned>(path: &[String], data: serde_json::Value) -> Result<T, Error> {
+    return serde_path_to_error::deserialize(data).map_err(|e| {
+        let mut full_path = path.to_vec();
+        for seg in e.path().iter() {
+            full_path.push(match seg {
+                serde_path_to_error::Segment::Seq { index } => index.to_string(),
+                serde_path_to_error::Segment::Map { key } => key.clone(),
+                serde_path_to_error::Segment::Enum { variant } => variant.clone(),
+                serde_path_to_error::Segment::Unknown => "?".to_string(),
+            });
+        }
+        return Error::Deserialize {
+            path: full_path,
+            message: e.into_inner().to_string(),
+        };
+    });
+}
+
+fn user_path<T: AsRef<str>>(user: &str, path: &[T]) -> Vec<String> {
+    let mut out = vec!["user".to_string(), user.to_string()];
+    out.extend(path.iter().map(|x| x.as_ref().to_string()));
+    return out;
+}
+
+fn to_value<T: Serialize>(path: &[String], data: &T) -> Result<serde_json::Value, Error> {
+    return serde_json::to_value(data).map_err(|e| Error::Serialize {
+        path: path.to_vec(),
+        message: e.to_string(),
+    });
+}
+
+/// Convert a non-success response to an error.
+fn response_error(code: StatusCode, body: &[u8]) -> Error {
+    let response = match serde_json::from_slice::<ErrorResponse>(body) {
+        Ok(r) => r,
+        Err(_) => ErrorResponse {
+            message: String::from_utf8_lossy(body).to_string(),
+            path: None,
+            violations: vec![],
+        },
+    };
+    return status_error(code, response);
+}
+
+fn status_error(code: StatusCode, response: ErrorResponse) -> Error {
+    match code {
+        StatusCode::BAD_REQUEST => return Error::BadRequest(response),
+        StatusCode::UNAUTHORIZED => return Error::Unauthorized(response),
+        StatusCode::FORBIDDEN => return Error::Forbidden(response),
+        StatusCode::NOT_FOUND => return Error::NotFound(response),
+        StatusCode::CONFLICT => return Error::Conflict(response),
+        StatusCode::PRECONDITION_FAILED => return Error::PreconditionFailed(response),
+        StatusCode::UNPROCESSABLE_ENTITY => return Error::SchemaViolation(response),
+        c => return Error::Status {
+            status: c.as_u16(),
+            response: response,
+        },
+    }
+}
+
+#[derive(Deserialize)]
+struct BatchError {
+    code: String,
+    #[serde(flatten)]
+    response: ErrorResponse,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum BatchResult {
+    Ok {
+        data: serde_json::Value,
+        etag: String,
+    },
+    Error(BatchError),
+}
+
+/// The status the server uses for each error code.
+fn code_status(code: &str) -> StatusCode {
+    match code {
+        "bad_request" => return StatusCode::BAD_REQUEST,
+        "unauthorized" => return StatusCode::UNAUTHORIZED,
+        "forbidden" => return StatusCode::FORBIDDEN,
+        "not_found" => return StatusCode::NOT_FOUND,
+        "method_not_allowed" => return StatusCode::METHOD_NOT_ALLOWED,
+        "conflict" => return StatusCode::CONFLICT,
+        "gone" => return StatusCode::GONE,
+        "precondition_failed" => return StatusCode::PRECONDITION_FAILED,
+        "unsupported_media_type" => return StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        "schema_violation" => return StatusCode::UNPROCESSABLE_ENTITY,
+        _ => return StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// An operation in a JSON patch (RFC 6902). Pointers (`path`, `from`) are relative
+/// to the path the patch is sent to.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "snake_case", tag = "op")]
+pub enum PatchOp {
+    Add {
+        path: String,
+        value: serde_json::Value,
+    },
+    Remove {
+        path: String,
+    },
+    Replace {
+        path: String,
+        value: serde_json::Value,
+    },
+    Move {
+        from: String,
+        path: String,
+    },
+    Copy {
+        from: String,
+        path: String,
+    },
+    /// Abort the whole patch unless the value at `path` equals `value`.
+    Test {
+        path: String,
+        value: serde_json::Value,
+    },
+}
+
+struct PooledConn {
+    conn: htreq::Conn,
+    idle_since: Instant,
+}
+
+#[derive(Clone)]
+struct CacheEntry {
+    data: serde_json::Value,
+    etag: String,
+    /// When the server last confirmed this is current.
+    validated: Instant,
+}
+
+struct Cache {
+    /// Keyed by request url.
+    entries: Mutex<HashMap<String, CacheEntry>>,
+    stale_ttl: Duration,
+    max_entries: usize,
+}
+
+impl Cache {
+    /// Add or replace an entry. If the cache is full, the entries confirmed longest
+    /// ago are evicted to make room.
+    fn insert(&self, key: String, entry: CacheEntry) {
+        if self.max_entries == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(&key) {
+            while entries.len() >= self.max_entries {
+                let Some(oldest) = entries.iter().min_by_key(|(_, e)| e.validated).map(|(k, _)| k.clone()) else {
+                    break;
+                };
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(key, entry);
     }
 }
 
@@ -36,10 +330,47 @@ struct Client_ {
     log: Log,
     base_url: Uri,
     headers: HashMap<String, String>,
+    /// Idle keep-alive connections, most recently used last.
+    pool: Mutex<Vec<PooledConn>>,
+    pool_size: usize,
+    pool_idle_timeout: Duration,
+    cache: Option<Cache>,
+}
+
+/// How `GET` requests are retried when the server can't be reached or is
+/// temporarily unavailable. Other methods are never retried.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt. 0 disables retries.
+    pub max_retries: usize,
+    /// Delay before the first retry, doubled for each further retry.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        return RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        };
+    }
 }
 
 #[derive(Clone)]
-pub struct Client(Arc<Client_>);
+struct RequestOptions {
+    limits: htreq::Limits,
+    /// For the whole request, including connecting and reading the response.
+    timeout: Duration,
+    connect_timeout: Duration,
+    retry: RetryPolicy,
+}
+
+/// A handle to a client. Clones share connections and cache. Methods like
+/// `with_timeout` return a handle with different request options.
+#[derive(Clone)]
+pub struct Client(Arc<Client_>, RequestOptions);
 
 impl Client {
     pub fn builder() -> ClientBuilder {
@@ -57,94 +388,651 @@ impl Client {
         return self.0.base_url.join(subpath);
     }
 
-    /// Replace all data under `path`.
+    fn build_user_path<'a>(&self, user: &'a dyn AsRef<str>, path: impl Iterator<Item = &'a dyn AsRef<str>>) -> Uri {
+        return self.build_path(Iterator::chain([&"user" as &dyn AsRef<str>, user].into_iter(), path));
+    }
+
+    /// Make a request with extra headers and return the status, headers, and body
+    /// regardless of status. `GET` requests are retried with backoff on transport
+    /// errors and 502/503/504 responses, per the client's retry policy.
+    async fn request(
+        &self,
+        method: Method,
+        url: &Uri,
+        extra_headers: &[(&str, String)],
+        body: Vec<u8>,
+    ) -> Result<(StatusCode, HeaderMap, Vec<u8>), Error> {
+        let options = &self.1;
+        let mut backoff = options.retry.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            let res = self.request_once(method.clone(), url, extra_headers, body.clone()).await;
+            let retryable = match &res {
+                Ok((code, _, _)) => matches!(
+                    *code,
+                    StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+                ),
+                Err(Error::Transport(_)) => true,
+                Err(_) => false,
+            };
+            if !retryable || method != Method::GET || attempt >= options.retry.max_retries {
+                return res;
+            }
+            attempt += 1;
+            self
+                .0
+                .log
+                .log_with(
+                    loga::DEBUG,
+                    "Request failed, retrying",
+                    ea!(url = url, attempt = attempt, backoff = backoff.as_millis()),
+                );
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(options.retry.max_backoff);
+        }
+    }
+
+    async fn request_once(
+        &self,
+        method: Method,
+        url: &Uri,
+        extra_headers: &[(&str, String)],
+        body: Vec<u8>,
+    ) -> Result<(StatusCode, HeaderMap, Vec<u8>), Error> {
+        let options = &self.1;
+        let mut headers = self.0.headers.clone();
+        for (k, v) in extra_headers {
+            headers.insert(k.to_string(), v.clone());
+        }
+        return tokio::time::timeout(options.timeout, async {
+            // Only `GET`s can be safely resent if an idle connection turns out to have been
+            // closed by the server, so other requests always use a new connection
+            let pooled = match method == Method::GET {
+                true => self.take_conn(),
+                false => None,
+            };
+            let (mut conn, reused) = match pooled {
+                Some(c) => (c, true),
+                None => (self.connect(url).await?, false),
+            };
+            let resp =
+                match htreq::send(
+                    &self.0.log,
+                    options.limits,
+                    &mut conn,
+                    url,
+                    method.clone(),
+                    &headers,
+                    body.clone(),
+                ).await {
+                    Ok(r) => r,
+                    Err(e) => {
+                        if !reused {
+                            return Err(e.into());
+                        }
+
+                        // The server may have closed the idle connection, retry once on a new one
+                        self
+                            .0
+                            .log
+                            .log_err(loga::DEBUG, e.context("Request on pooled connection failed, reconnecting"));
+                        conn = self.connect(url).await?;
+                        htreq::send(&self.0.log, options.limits, &mut conn, url, method, &headers, body).await?
+                    },
+                };
+            let keep_alive =
+                !resp
+                    .headers
+                    .get(CONNECTION)
+                    .and_then(|v| v.to_str().ok())
+                    .is_some_and(|v| v.eq_ignore_ascii_case("close"));
+            let code = resp.code;
+            let resp_headers = resp.headers;
+            let body = htreq::receive(resp.body, options.limits).await?;
+            if keep_alive {
+                self.return_conn(conn);
+            }
+            return Ok((code, resp_headers, body));
+        })
+            .await
+            .unwrap_or_else(|_| Err(Error::Transport(format!("Request timed out after {:?}", options.timeout))));
+    }
+
+    async fn connect(&self, url: &Uri) -> Result<htreq::Conn, Error> {
+        let options = &self.1;
+        return tokio::time::timeout(options.connect_timeout, htreq::connect(options.limits, url))
+            .await
+            .map_err(|_| Error::Transport(format!("Connecting timed out after {:?}", options.connect_timeout)))?
+            .map_err(|e| e.into());
+    }
+
+    /// Get a handle to the same client (sharing connections and cache) that uses
+    /// `limits` for requests.
+    pub fn with_limits(&self, limits: htreq::Limits) -> Client {
+        let mut out = self.clone();
+        out.1.limits = limits;
+        return out;
+    }
+
+    /// Get a handle to the same client (sharing connections and cache) with a
+    /// different request timeout.
+    pub fn with_timeout(&self, timeout: Duration) -> Client {
+        let mut out = self.clone();
+        out.1.timeout = timeout;
+        return out;
+    }
+
+    /// Get a handle to the same client (sharing connections and cache) with a
+    /// different retry policy.
+    pub fn with_retry(&self, retry: RetryPolicy) -> Client {
+        let mut out = self.clone();
+        out.1.retry = retry;
+        return out;
+    }
+
+    /// Get the most recently used idle connection that hasn't timed out, dropping
+    /// any that have.
+    fn take_conn(&self) -> Option<htreq::Conn> {
+        let mut pool = self.0.pool.lock().unwrap();
+        let now = Instant::now();
+        pool.retain(|c| now.duration_since(c.idle_since) < self.0.pool_idle_timeout);
+        return pool.pop().map(|c| c.conn);
+    }
+
+    fn return_conn(&self, conn: htreq::Conn) {
+        let mut pool = self.0.pool.lock().unwrap();
+        if pool.len() >= self.0.pool_size {
+            // Drop the oldest
+            if self.0.pool_size == 0 {
+                return;
+            }
+            pool.remove(0);
+        }
+        pool.push(PooledConn {
+            conn: conn,
+            idle_since: Instant::now(),
+        });
+    }
+
+    /// Make a request, returning an error for any non-success status.
+    async fn request_ok(
+        &self,
+        method: Method,
+        url: &Uri,
+        extra_headers: &[(&str, String)],
+        body: Vec<u8>,
+    ) -> Result<(HeaderMap, Vec<u8>), Error> {
+        let (code, headers, body) = self.request(method, url, extra_headers, body).await?;
+        if !code.is_success() {
+            return Err(response_error(code, &body));
+        }
+        return Ok((headers, body));
+    }
+
+    async fn get_url(&self, url: &Uri) -> Result<Option<serde_json::Value>, Error> {
+        return Ok(self.get_url_with_etag(url).await?.map(|(data, _)| data));
+    }
+
+    async fn get_url_with_etag(
+        &self,
+        url: &Uri,
+    ) -> Result<Option<(serde_json::Value, Option<String>)>, Error> {
+        let Some(cache) = &self.0.cache else {
+            let (headers, body) = match self.request_ok(Method::GET, url, &[], vec![]).await {
+                Ok(r) => r,
+                Err(Error::NotFound(_)) => {
+                    return Ok(None);
+                },
+                Err(e) => {
+                    return Err(e);
+                },
+            };
+            let etag = headers.get(ETAG).and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+            let data =
+                serde_json::from_slice(&body).map_err(|e| Error::InvalidResponse(format!("Invalid json: {}", e)))?;
+            return Ok(Some((data, etag)));
+        };
+        let key = url.to_string();
+        let cached = cache.entries.lock().unwrap().get(&key).cloned();
+        let mut extra_headers = vec![];
+        if let Some(cached) = &cached {
+            extra_headers.push((IF_NONE_MATCH.as_str(), cached.etag.clone()));
+        }
+        let e = match self.request(Method::GET, url, &extra_headers, vec![]).await {
+            Ok((code, headers, body)) => {
+                if code == StatusCode::NOT_MODIFIED {
+                    if let Some(cached) = cached {
+                        if let Some(e) = cache.entries.lock().unwrap().get_mut(&key) {
+                            e.validated = Instant::now();
+                        }
+                        return Ok(Some((cached.data, Some(cached.etag))));
+                    }
+                }
+                if code == StatusCode::NOT_FOUND {
+                    cache.entries.lock().unwrap().remove(&key);
+                    return Ok(None);
+                }
+                if code.is_success() {
+                    let etag = headers.get(ETAG).and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+                    let data =
+                        serde_json::from_slice::<serde_json::Value>(
+                            &body,
+                        ).map_err(|e| Error::InvalidResponse(format!("Invalid json: {}", e)))?;
+                    if let Some(etag) = &etag {
+                        cache.insert(key, CacheEntry {
+                            data: data.clone(),
+                            etag: etag.clone(),
+                            validated: Instant::now(),
+                        });
+                    }
+                    return Ok(Some((data, etag)));
+                }
+                response_error(code, &body)
+            },
+            Err(e) => e,
+        };
+        let unreachable = match &e {
+            Error::Transport(_) => true,
+            Error::Status { status, .. } => *status >= 500,
+            _ => false,
+        };
+        if unreachable {
+            if let Some(cached) = cached {
+                if cached.validated.elapsed() < cache.stale_ttl {
+                    self.0.log.log_with(loga::DEBUG, "Server unreachable, using cached value", ea!(url = url, err = e));
+                    return Ok(Some((cached.data, Some(cached.etag))));
+                }
+            }
+        }
+        return Err(e);
+    }
+
+    async fn set_url(&self, url: &Uri, data: serde_json::Value) -> Result<(), Error> {
+        self
+            .request_ok(
+                Method::POST,
+                url,
+                &[(CONTENT_TYPE.as_str(), CONTENT_TYPE_JSON.to_string())],
+                serde_json::to_vec(&data).unwrap(),
+            )
+            .await?;
+        return Ok(());
+    }
+
+    async fn delete_url(&self, url: &Uri) -> Result<(), Error> {
+        self.request_ok(Method::DELETE, url, &[], vec![]).await?;
+        return Ok(());
+    }
+
+    /// Get all data under `path`, or `None` if there's no data there.
     pub async fn get<
         T: AsRef<str>,
         I: AsRef<[T]>,
-    >(&self, limits: htreq::Limits, path: I) -> Result<Option<serde_json::Value>, Error> {
+    >(&self, path: I) -> Result<Option<serde_json::Value>, Error> {
         let url = self.build_path(path.as_ref().iter().map(|x| x as &dyn AsRef<str>));
-        let mut conn = htreq::connect(limits, &url).await?;
-        return Ok(htreq::get_json(&self.0.log, limits, &mut conn, &url, &self.0.headers).await?);
+        return self.get_url(&url).await;
+    }
+
+    /// Get all data under `path` deserialized as `T`, or `None` if there's no data
+    /// there.
+    pub async fn get_typed<
+        O: DeserializeOwned,
+        T: AsRef<str>,
+        I: AsRef<[T]>,
+    >(&self, path: I) -> Result<Option<O>, Error> {
+        let path = path.as_ref().iter().map(|x| x.as_ref().to_string()).collect::<Vec<_>>();
+        let Some(data) = self.get(&path).await? else {
+            return Ok(None);
+        };
+        return Ok(Some(from_value(&path, data)?));
+    }
+
+    /// Get the data under each of `paths` in one request, along with its etag (see
+    /// `get_with_etag`). The outer error is for the request as a whole, the inner
+    /// results are for each path in order. Access is checked for each path
+    /// individually, so some may fail while others succeed.
+    pub async fn get_many<
+        T: AsRef<str>,
+        I: AsRef<[T]>,
+    >(&self, paths: &[I]) -> Result<Vec<Result<Option<(serde_json::Value, String)>, Error>>, Error> {
+        let url = self.build_path([&BATCH_ROOT as &dyn AsRef<str>].into_iter());
+        let paths =
+            paths
+                .iter()
+                .map(|p| p.as_ref().iter().map(|x| x.as_ref().to_string()).collect::<Vec<_>>())
+                .collect::<Vec<_>>();
+        let (_, body) =
+            self
+                .request_ok(
+                    Method::POST,
+                    &url,
+                    &[(CONTENT_TYPE.as_str(), CONTENT_TYPE_JSON.to_string())],
+                    serde_json::to_vec(&serde_json::json!({
+                        "paths": paths
+                    })).unwrap(),
+                )
+                .await?;
+        let results =
+            serde_json::from_slice::<Vec<BatchResult>>(
+                &body,
+            ).map_err(|e| Error::InvalidResponse(format!("Invalid batch response: {}", e)))?;
+        if results.len() != paths.len() {
+            return Err(
+                Error::InvalidResponse(
+                    format!("Batch response has {} results for {} paths", results.len(), paths.len()),
+                ),
+            );
+        }
+        return Ok(results.into_iter().map(|r| match r {
+            BatchResult::Ok { data, etag } => return Ok(Some((data, etag))),
+            BatchResult::Error(e) => {
+                let status = code_status(&e.code);
+                if status == StatusCode::NOT_FOUND {
+                    return Ok(None);
+                }
+                return Err(status_error(status, e.response));
+            },
+        }).collect());
+    }
+
+    /// Get all data under `path` along with its etag, for use with `set_if_match` and
+    /// `delete_if_match`.
+    pub async fn get_with_etag<
+        T: AsRef<str>,
+        I: AsRef<[T]>,
+    >(&self, path: I) -> Result<Option<(serde_json::Value, String)>, Error> {
+        let url = self.build_path(path.as_ref().iter().map(|x| x as &dyn AsRef<str>));
+        let Some((data, etag)) = self.get_url_with_etag(&url).await? else {
+            return Ok(None);
+        };
+        let Some(etag) = etag else {
+            return Err(Error::InvalidResponse(format!("Response is missing etag")));
+        };
+        return Ok(Some((data, etag)));
     }
 
     /// Replace all data under `path`.
     pub async fn set<
         T: AsRef<str>,
         I: AsRef<[T]>,
-    >(&self, limits: htreq::Limits, path: I, data: serde_json::Value) -> Result<(), Error> {
+    >(&self, path: I, data: serde_json::Value) -> Result<(), Error> {
+        let url = self.build_path(path.as_ref().iter().map(|x| x as &dyn AsRef<str>));
+        return self.set_url(&url, data).await;
+    }
+
+    /// Replace all data under `path` with `data` serialized as JSON.
+    pub async fn set_typed<
+        D: Serialize,
+        T: AsRef<str>,
+        I: AsRef<[T]>,
+    >(&self, path: I, data: &D) -> Result<(), Error> {
+        let path = path.as_ref().iter().map(|x| x.as_ref().to_string()).collect::<Vec<_>>();
+        let data = to_value(&path, data)?;
+        return self.set(&path, data).await;
+    }
+
+    /// Replace all data under `path` if it hasn't changed since `etag` was retrieved,
+    /// otherwise fails with `Error::PreconditionFailed`.
+    pub async fn set_if_match<
+        T: AsRef<str>,
+        I: AsRef<[T]>,
+    >(&self, path: I, data: serde_json::Value, etag: &str) -> Result<(), Error> {
         let url = self.build_path(path.as_ref().iter().map(|x| x as &dyn AsRef<str>));
-        let mut conn = htreq::connect(limits, &url).await?;
-        htreq::post_json::<()>(&self.0.log, limits, &mut conn, &url, &self.0.headers, data).await?;
+        self
+            .request_ok(
+                Method::POST,
+                &url,
+                &[
+                    (CONTENT_TYPE.as_str(), CONTENT_TYPE_JSON.to_string()),
+                    (IF_MATCH.as_str(), etag.to_string()),
+                ],
+                serde_json::to_vec(&data).unwrap(),
+            )
+            .await?;
         return Ok(());
     }
 
-    /// Delete all data under `path`.
-    pub async fn delete<T: AsRef<str>, I: AsRef<[T]>>(&self, limits: htreq::Limits, path: I) -> Result<(), Error> {
+    /// Delete all data under `path` if it hasn't changed since `etag` was retrieved,
+    /// otherwise fails with `Error::PreconditionFailed`.
+    pub async fn delete_if_match<
+        T: AsRef<str>,
+        I: AsRef<[T]>,
+    >(&self, path: I, etag: &str) -> Result<(), Error> {
         let url = self.build_path(path.as_ref().iter().map(|x| x as &dyn AsRef<str>));
-        let mut conn = htreq::connect(limits, &url).await?;
-        htreq::delete(&self.0.log, limits, &mut conn, &url, &self.0.headers).await?;
+        self.request_ok(Method::DELETE, &url, &[(IF_MATCH.as_str(), etag.to_string())], vec![]).await?;
         return Ok(());
     }
 
+    /// Merge `data` into the data under `path` per JSON merge patch (RFC 7396):
+    /// objects are merged recursively, `null` values delete keys, and anything else
+    /// replaces what's there.
+    pub async fn patch<
+        T: AsRef<str>,
+        I: AsRef<[T]>,
+    >(&self, path: I, data: serde_json::Value) -> Result<(), Error> {
+        let url = self.build_path(path.as_ref().iter().map(|x| x as &dyn AsRef<str>));
+        return self.send_merge_patch(&url, data).await;
+    }
+
+    /// Apply a JSON patch (RFC 6902) to the data under `path`. Either all operations
+    /// are applied or, if any fails, none are. Failed `Test` operations return
+    /// `Error::Conflict`.
+    pub async fn json_patch<
+        T: AsRef<str>,
+        I: AsRef<[T]>,
+    >(&self, path: I, ops: &[PatchOp]) -> Result<(), Error> {
+        let url = self.build_path(path.as_ref().iter().map(|x| x as &dyn AsRef<str>));
+        self
+            .request_ok(
+                Method::PATCH,
+                &url,
+                &[(CONTENT_TYPE.as_str(), CONTENT_TYPE_JSON_PATCH.to_string())],
+                serde_json::to_vec(ops).unwrap(),
+            )
+            .await?;
+        return Ok(());
+    }
+
+    async fn send_merge_patch(&self, url: &Uri, data: serde_json::Value) -> Result<(), Error> {
+        self
+            .request_ok(
+                Method::PATCH,
+                url,
+                &[(CONTENT_TYPE.as_str(), CONTENT_TYPE_MERGE_PATCH.to_string())],
+                serde_json::to_vec(&data).unwrap(),
+            )
+            .await?;
+        return Ok(());
+    }
+
+    /// Delete all data under `path`.
+    pub async fn delete<T: AsRef<str>, I: AsRef<[T]>>(&self, path: I) -> Result<(), Error> {
+        let url = self.build_path(path.as_ref().iter().map(|x| x as &dyn AsRef<str>));
+        return self.delete_url(&url).await;
+    }
+
+    /// Watch data under `path` for changes. The stream yields the current value
+    /// immediately, then a new value each time the data changes (`None` if the path
+    /// is deleted or doesn't exist). The same value may occasionally be yielded twice
+    /// in a row.
+    ///
+    /// This long-polls the server, waiting up to 60s per request. The request timeout
+    /// is extended to allow for that, but the limits should also allow reads that
+    /// long. The stream ends after yielding an error.
+    pub fn watch<
+        T: AsRef<str>,
+        I: AsRef<[T]>,
+    >(&self, path: I) -> impl Stream<Item = Result<Option<serde_json::Value>, Error>> {
+        struct WatchState {
+            client: Client,
+            url: Uri,
+            wait_url: Result<Uri, Error>,
+            etag: Option<String>,
+            first: bool,
+            done: bool,
+        }
+
+        let url = self.build_path(path.as_ref().iter().map(|x| x as &dyn AsRef<str>));
+        let wait_url =
+            Uri::try_from(
+                format!("{}?wait={}", url, WATCH_WAIT.as_secs()),
+            ).map_err(|e| Error::Other(format!("Error building watch url: {}", e)));
+        let mut client = self.clone();
+        client.1.timeout = client.1.timeout.max(WATCH_WAIT + WATCH_TIMEOUT_MARGIN);
+        return stream::unfold(WatchState {
+            client: client,
+            url: url,
+            wait_url: wait_url,
+            etag: None,
+            first: true,
+            done: false,
+        }, move |mut state| async move {
+            if state.done {
+                return None;
+            }
+            loop {
+                let res = match if state.first {
+                    Ok(&state.url)
+                } else {
+                    state.wait_url.as_ref().map_err(|e| e.clone())
+                } {
+                    Ok(url) => {
+                        let mut extra_headers = vec![];
+                        if let Some(etag) = &state.etag {
+                            extra_headers.push((IF_NONE_MATCH.as_str(), etag.clone()));
+                        }
+                        state.client.request(Method::GET, url, &extra_headers, vec![]).await
+                    },
+                    Err(e) => Err(e),
+                };
+                let (code, headers, body) = match res {
+                    Ok(r) => r,
+                    Err(e) => {
+                        state.done = true;
+                        return Some((Err(e), state));
+                    },
+                };
+                let first = state.first;
+                state.first = false;
+                match code {
+                    StatusCode::NOT_MODIFIED => {
+                        continue;
+                    },
+                    StatusCode::NOT_FOUND => {
+                        if !first && state.etag.is_none() {
+                            // Wait timed out and the path is still missing
+                            continue;
+                        }
+                        state.etag = None;
+                        return Some((Ok(None), state));
+                    },
+                    c if c.is_success() => {
+                        state.etag = headers.get(ETAG).and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+                        match serde_json::from_slice(&body) {
+                            Ok(v) => {
+                                return Some((Ok(Some(v)), state));
+                            },
+                            Err(e) => {
+                                state.done = true;
+                                return Some((Err(Error::InvalidResponse(format!("Invalid json: {}", e))), state));
+                            },
+                        }
+                    },
+                    c => {
+                        state.done = true;
+                        return Some((Err(response_error(c, &body)), state));
+                    },
+                }
+            }
+        });
+    }
+
     /// Helper for getting under a user path.
     pub async fn user_get<
         T: AsRef<str>,
         I: AsRef<[T]>,
-    >(&self, limits: htreq::Limits, user: impl AsRef<str>, path: I) -> Result<Option<serde_json::Value>, Error> {
-        let url =
-            self.build_path(
-                Iterator::chain(
-                    [&"user" as &dyn AsRef<str>, &user].into_iter(),
-                    path.as_ref().iter().map(|x| x as &dyn AsRef<str>),
-                ),
-            );
-        let mut conn = htreq::connect(limits, &url).await?;
-        return Ok(htreq::get_json(&self.0.log, limits, &mut conn, &url, &self.0.headers).await?);
+    >(&self, user: impl AsRef<str>, path: I) -> Result<Option<serde_json::Value>, Error> {
+        let url = self.build_user_path(&user, path.as_ref().iter().map(|x| x as &dyn AsRef<str>));
+        return self.get_url(&url).await;
     }
 
     /// Helper for setting under a user path.
     pub async fn user_set<
         T: AsRef<str>,
         I: AsRef<[T]>,
-    >(&self, limits: htreq::Limits, user: impl AsRef<str>, path: I, data: serde_json::Value) -> Result<(), Error> {
-        let url =
-            self.build_path(
-                Iterator::chain(
-                    [&"user" as &dyn AsRef<str>, &user].into_iter(),
-                    path.as_ref().iter().map(|x| x as &dyn AsRef<str>),
-                ),
-            );
-        let mut conn = htreq::connect(limits, &url).await?;
-        htreq::post_json::<()>(&self.0.log, limits, &mut conn, &url, &self.0.headers, data).await?;
-        return Ok(());
+    >(&self, user: impl AsRef<str>, path: I, data: serde_json::Value) -> Result<(), Error> {
+        let url = self.build_user_path(&user, path.as_ref().iter().map(|x| x as &dyn AsRef<str>));
+        return self.set_url(&url, data).await;
+    }
+
+    /// Helper for getting typed data under a user path.
+    pub async fn user_get_typed<
+        O: DeserializeOwned,
+        T: AsRef<str>,
+        I: AsRef<[T]>,
+    >(&self, user: impl AsRef<str>, path: I) -> Result<Option<O>, Error> {
+        let path = user_path(user.as_ref(), path.as_ref());
+        let Some(data) = self.get(&path).await? else {
+            return Ok(None);
+        };
+        return Ok(Some(from_value(&path, data)?));
+    }
+
+    /// Helper for setting typed data under a user path.
+    pub async fn user_set_typed<
+        D: Serialize,
+        T: AsRef<str>,
+        I: AsRef<[T]>,
+    >(&self, user: impl AsRef<str>, path: I, data: &D) -> Result<(), Error> {
+        let path = user_path(user.as_ref(), path.as_ref());
+        let data = to_value(&path, data)?;
+        return self.set(&path, data).await;
+    }
+
+    /// Helper for merge-patching under a user path.
+    pub async fn user_patch<
+        T: AsRef<str>,
+        I: AsRef<[T]>,
+    >(&self, user: impl AsRef<str>, path: I, data: serde_json::Value) -> Result<(), Error> {
+        let url = self.build_user_path(&user, path.as_ref().iter().map(|x| x as &dyn AsRef<str>));
+        return self.send_merge_patch(&url, data).await;
     }
 
     /// Helper for deleting under a user path.
     pub async fn user_delete<
         T: AsRef<str>,
         I: AsRef<[T]>,
-    >(&self, limits: htreq::Limits, user: impl AsRef<str>, path: I) -> Result<(), Error> {
-        let url =
-            self.build_path(
-                Iterator::chain(
-                    [&"user" as &dyn AsRef<str>, &user].into_iter(),
-                    path.as_ref().iter().map(|x| x as &dyn AsRef<str>),
-                ),
-            );
-        let mut conn = htreq::connect(limits, &url).await?;
-        htreq::delete(&self.0.log, limits, &mut conn, &url, &self.0.headers).await?;
-        return Ok(());
+    >(&self, user: impl AsRef<str>, path: I) -> Result<(), Error> {
+        let url = self.build_user_path(&user, path.as_ref().iter().map(|x| x as &dyn AsRef<str>));
+        return self.delete_url(&url).await;
     }
 }
 
+const CONTENT_TYPE_JSON: &str = "application/json";
+const CONTENT_TYPE_MERGE_PATCH: &str = "application/merge-patch+json";
+const CONTENT_TYPE_JSON_PATCH: &str = "application/json-patch+json";
+const BATCH_ROOT: &str = "fdap_batch";
+const WATCH_WAIT: Duration = Duration::from_secs(60);
+const WATCH_TIMEOUT_MARGIN: Duration = Duration::from_secs(10);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 pub const ENV_BASE_URL: &str = "FDAP_BASE_URL";
 pub const ENV_TOKEN: &str = "FDAP_TOKEN";
+const DEFAULT_POOL_SIZE: usize = 8;
+const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Default)]
 pub struct ClientBuilder {
     log: Option<Log>,
     base_url: Option<Uri>,
     token: Option<String>,
+    pool_size: Option<usize>,
+    pool_idle_timeout: Option<Duration>,
+    cache: Option<(Duration, usize)>,
+    limits: Option<htreq::Limits>,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    retry: Option<RetryPolicy>,
 }
 
 impl ClientBuilder {
@@ -169,6 +1057,64 @@ impl ClientBuilder {
         return self;
     }
 
+    /// Set the maximum number of idle connections kept open for reuse (default 8).
+    /// Set to 0 to use a new connection for every request. Idle connections are only
+    /// reused for `GET`s, since other requests can't be safely resent if the server
+    /// closed the connection.
+    pub fn with_pool_size(mut self, size: usize) -> Self {
+        self.pool_size = Some(size);
+        return self;
+    }
+
+    /// Set how long an idle connection is kept for reuse (default 30s). This should be
+    /// shorter than the server's keep-alive timeout.
+    pub fn with_pool_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.pool_idle_timeout = Some(timeout);
+        return self;
+    }
+
+    /// Cache the last value retrieved from each path. Gets revalidate cached values
+    /// with the server (sending `If-None-Match`) so unchanged data isn't transferred
+    /// again.
+    ///
+    /// If the server can't be reached, a cached value is returned instead of an error
+    /// as long as it was confirmed current within `stale_ttl`. Use `Duration::ZERO`
+    /// to never return stale values.
+    ///
+    /// At most `max_entries` values are cached. When full, the values confirmed
+    /// longest ago are evicted first.
+    pub fn with_cache(mut self, stale_ttl: Duration, max_entries: usize) -> Self {
+        self.cache = Some((stale_ttl, max_entries));
+        return self;
+    }
+
+    /// Set the default limits for requests (default `htreq::Limits::default()`).
+    /// Override for individual calls with `Client::with_limits`.
+    pub fn with_limits(mut self, limits: htreq::Limits) -> Self {
+        self.limits = Some(limits);
+        return self;
+    }
+
+    /// Set the default timeout for a whole request, including connecting (default
+    /// 30s).
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        return self;
+    }
+
+    /// Set the timeout for establishing a connection (default 10s).
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        return self;
+    }
+
+    /// Set how `GET` requests are retried (default 3 retries, with backoff starting at
+    /// 100ms).
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = Some(retry);
+        return self;
+    }
+
     pub fn build(self) -> Result<Client, Error> {
         let base_url;
         match self.base_url {
@@ -181,7 +1127,7 @@ impl ClientBuilder {
                         env::var(
                             ENV_BASE_URL,
                         ).map_err(
-                            |e| Error(
+                            |e| Error::Other(
                                 format!(
                                     "No base URL explicitly set and unable to resolve env var {}: {}",
                                     ENV_BASE_URL,
@@ -190,7 +1136,7 @@ impl ClientBuilder {
                             ),
                         )?,
                     ).map_err(
-                        |e| Error(
+                        |e| Error::Other(
                             format!("Found base url in environment variable, but it is not a valid URL: {}", e),
                         ),
                     )?;
@@ -206,7 +1152,7 @@ impl ClientBuilder {
                     env::var(
                         ENV_TOKEN,
                     ).map_err(
-                        |e| Error(
+                        |e| Error::Other(
                             format!("No token explicitly set and unable to resolve env var {}: {}", ENV_TOKEN, e),
                         ),
                     )?;
@@ -219,6 +1165,62 @@ impl ClientBuilder {
             headers: [(AUTHORIZATION.to_string(), format!("{}{}", htwrap::constants::HEADER_BEARER_PREFIX, token))]
                 .into_iter()
                 .collect(),
-        })));
+            pool: Mutex::new(vec![]),
+            pool_size: self.pool_size.unwrap_or(DEFAULT_POOL_SIZE),
+            pool_idle_timeout: self.pool_idle_timeout.unwrap_or(DEFAULT_POOL_IDLE_TIMEOUT),
+            cache: self.cache.map(|(stale_ttl, max_entries)| Cache {
+                entries: Mutex::new(HashMap::new()),
+                stale_ttl: stale_ttl,
+                max_entries: max_entries,
+            }),
+        }), RequestOptions {
+            limits: self.limits.unwrap_or_default(),
+            timeout: self.timeout.unwrap_or(DEFAULT_TIMEOUT),
+            connect_timeout: self.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+            retry: self.retry.unwrap_or_default(),
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            Cache,
+            CacheEntry,
+        },
+        std::{
+            collections::HashMap,
+            sync::Mutex,
+            time::{
+                Duration,
+                Instant,
+            },
+        },
+    };
+
+    #[test]
+    fn test_cache_evicts_oldest_confirmed() {
+        let cache = Cache {
+            entries: Mutex::new(HashMap::new()),
+            stale_ttl: Duration::ZERO,
+            max_entries: 2,
+        };
+        let start = Instant::now();
+        let entry = |secs| CacheEntry {
+            data: serde_json::Value::Null,
+            etag: format!("\"{}\"", secs),
+            validated: start + Duration::from_secs(secs),
+        };
+        cache.insert("a".to_string(), entry(2));
+        cache.insert("b".to_string(), entry(1));
+
+        // Replacing doesn't evict
+        cache.insert("a".to_string(), entry(3));
+        assert_eq!(cache.entries.lock().unwrap().len(), 2);
+        cache.insert("c".to_string(), entry(4));
+        let mut keys = cache.entries.lock().unwrap().keys().cloned().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, vec!["a".to_string(), "c".to_string()]);
     }
 }
@@ -0,0 +1,264 @@
+use {
+    crate::{
+        dball::DbVersion,
+        sync_dir,
+        DataPath,
+    },
+    chrono::{
+        DateTime,
+        Utc,
+    },
+    flowcontrol::shed,
+    loga::{
+        ea,
+        ResultContext,
+    },
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    std::{
+        borrow::Cow,
+        fs::{
+            File,
+            OpenOptions,
+        },
+        io::{
+            Read,
+            Seek,
+            SeekFrom,
+            Write,
+        },
+        path::{
+            Path,
+            PathBuf,
+        },
+    },
+};
+
+/// The before and after data at the request path.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct AuditDiff {
+    pub old: Option<serde_json::Value>,
+    pub new: Option<serde_json::Value>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct AuditEntry {
+    pub timestamp: DateTime<Utc>,
+    /// Identifies the token without revealing it, see `token_id`. This is the same
+    /// as the hashed form of the token used in `users`, without the `sha256:`
+    /// prefix.
+    pub token: String,
+    pub method: String,
+    pub path: DataPath,
+    pub old_version: DbVersion,
+    pub new_version: DbVersion,
+    /// Only recorded if `audit_diff` is enabled in the config.
+    pub diff: Option<AuditDiff>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum AuditRecord<'a> {
+    V1(Cow<'a, AuditEntry>),
+}
+
+/// How much of the audit log to read at a time when searching backwards from the
+/// end.
+const READ_CHUNK: u64 = 64 * 1024;
+
+/// Append-only log of writes.
+pub struct Audit {
+    pub path: PathBuf,
+    file: File,
+    pub include_diff: bool,
+}
+
+impl Audit {
+    pub fn open(dir: &Path, include_diff: bool) -> Result<Audit, loga::Error> {
+        let path = dir.join("audit.jsonl");
+        let mut file =
+            OpenOptions::new()
+                .create(true)
+                .read(true)
+                .append(true)
+                .open(&path)
+                .context_with("Error opening audit log", ea!(path = path.display()))?;
+        sync_dir(dir)?;
+
+        // Terminate any partial entry from a crash so new entries start on a new line
+        let len =
+            file.metadata().context_with("Error reading audit log metadata", ea!(path = path.display()))?.len();
+        if len > 0 {
+            let mut last = [0u8];
+            file.seek(SeekFrom::Start(len - 1)).context_with("Error reading audit log", ea!(path = path.display()))?;
+            file.read_exact(&mut last).context_with("Error reading audit log", ea!(path = path.display()))?;
+            if last[0] != b'\n' {
+                file.write_all(b"\n").context_with("Error writing audit log", ea!(path = path.display()))?;
+            }
+        }
+        return Ok(Audit {
+            path: path,
+            file: file,
+            include_diff: include_diff,
+        });
+    }
+
+    pub fn append(&mut self, entry: &AuditEntry) -> Result<(), loga::Error> {
+        let mut line = serde_json::to_vec(&AuditRecord::V1(Cow::Borrowed(entry))).unwrap();
+        line.push(b'\n');
+        self.file.write_all(&line).context_with("Error writing audit log", ea!(path = self.path.display()))?;
+        self.file.sync_data().context_with("Error syncing audit log", ea!(path = self.path.display()))?;
+        return Ok(());
+    }
+}
+
+/// Read the most recent `limit` entries after version `since` that affected `path`
+/// (including its ancestors and descendants), oldest first. This reads the file
+/// directly (from `Audit::path`) so it doesn't block writes. Entries are appended
+/// in version order, so the file is read backwards from the end until enough
+/// entries are found or an entry at `since` is reached.
+pub fn read(
+    audit_path: &Path,
+    path: &DataPath,
+    since: DbVersion,
+    limit: usize,
+) -> Result<Vec<AuditEntry>, loga::Error> {
+    let mut file = File::open(audit_path).context_with("Error opening audit log", ea!(path = audit_path.display()))?;
+
+    // Start of the data in `pending`
+    let mut pos =
+        file.metadata().context_with("Error reading audit log metadata", ea!(path = audit_path.display()))?.len();
+
+    // Data read but not yet parsed, ending at the start of the last line parsed
+    let mut pending = vec![];
+    let mut out = vec![];
+    while out.len() < limit {
+        let (line, first) = match pending.iter().rposition(|c| *c == b'\n') {
+            Some(i) => {
+                let line = pending.split_off(i + 1);
+                pending.pop();
+                (line, false)
+            },
+            None => {
+                if pos > 0 {
+                    let start = pos.saturating_sub(READ_CHUNK);
+                    let mut chunk = vec![0u8; (pos - start) as usize];
+                    file
+                        .seek(SeekFrom::Start(start))
+                        .and_then(|_| file.read_exact(&mut chunk))
+                        .context_with("Error reading audit log", ea!(path = audit_path.display()))?;
+                    chunk.append(&mut pending);
+                    pending = chunk;
+                    pos = start;
+                    continue;
+                }
+                (std::mem::take(&mut pending), true)
+            },
+        };
+        shed!{
+            if line.is_empty() {
+                break;
+            }
+            let Ok(AuditRecord::V1(entry)) = serde_json::from_slice::<AuditRecord>(&line) else {
+                // Partial entry from a crash, or one being written
+                break;
+            };
+            if entry.new_version <= since {
+                return Ok(finish_read(out));
+            }
+            if !entry.path.starts_with(path) && !path.starts_with(&entry.path) {
+                break;
+            }
+            out.push(entry.into_owned());
+        }
+        if first {
+            break;
+        }
+    }
+    return Ok(finish_read(out));
+}
+
+fn finish_read(mut out: Vec<AuditEntry>) -> Vec<AuditEntry> {
+    out.reverse();
+    return out;
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            read,
+            Audit,
+            AuditEntry,
+        },
+        crate::dball::DbVersion,
+        chrono::Utc,
+        std::io::Write,
+    };
+
+    fn entry(version: DbVersion, path: &[&str]) -> AuditEntry {
+        return AuditEntry {
+            timestamp: Utc::now(),
+            token: "token".to_string(),
+            method: "POST".to_string(),
+            path: path.iter().map(|s| s.to_string()).collect(),
+            old_version: version - 1,
+            new_version: version,
+            diff: None,
+        };
+    }
+
+    fn versions(entries: Vec<AuditEntry>) -> Vec<DbVersion> {
+        return entries.iter().map(|e| e.new_version).collect();
+    }
+
+    #[test]
+    fn test_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut audit = Audit::open(dir.path(), false).unwrap();
+
+        // Enough entries to span several read chunks
+        let paths: [&[&str]; 4] = [&["a"], &["a", "x"], &["b"], &[]];
+        let count = 4000;
+        for version in 1 ..= count {
+            audit.append(&entry(version, paths[version % paths.len()])).unwrap();
+        }
+        let p = |path: &[&str]| path.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        // Ancestors and descendants overlap, siblings don't
+        let got = versions(read(&audit.path, &p(&["a"]), 0, usize::MAX).unwrap());
+        assert_eq!(got, (1 ..= count).filter(|v| v % 4 != 2).collect::<Vec<_>>());
+        let got = versions(read(&audit.path, &p(&["a", "x"]), 0, usize::MAX).unwrap());
+        assert_eq!(got, (1 ..= count).filter(|v| v % 4 != 2).collect::<Vec<_>>());
+        let got = versions(read(&audit.path, &p(&["b"]), 0, usize::MAX).unwrap());
+        assert_eq!(got, (1 ..= count).filter(|v| matches!(v % 4, 2 | 3)).collect::<Vec<_>>());
+
+        // Most recent entries after `since`, oldest first
+        assert_eq!(versions(read(&audit.path, &p(&["b"]), 0, 3).unwrap()), vec![3995, 3998, 3999]);
+        assert_eq!(versions(read(&audit.path, &p(&["b"]), 3997, 10).unwrap()), vec![3998, 3999]);
+        assert_eq!(versions(read(&audit.path, &p(&[]), count, 10).unwrap()), Vec::<DbVersion>::new());
+        assert_eq!(versions(read(&audit.path, &p(&[]), 0, 0).unwrap()), Vec::<DbVersion>::new());
+    }
+
+    #[test]
+    fn test_read_partial_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut audit = Audit::open(dir.path(), false).unwrap();
+        audit.append(&entry(1, &["a"])).unwrap();
+        audit.append(&entry(2, &["a"])).unwrap();
+
+        // Being written
+        std::fs::OpenOptions::new().append(true).open(&audit.path).unwrap().write_all(br#"{"v1":{"time"#).unwrap();
+        assert_eq!(versions(read(&audit.path, &vec![], 0, 10).unwrap()), vec![1, 2]);
+
+        // Terminated when reopened after a crash
+        drop(audit);
+        let mut audit = Audit::open(dir.path(), false).unwrap();
+        audit.append(&entry(3, &["a"])).unwrap();
+        assert_eq!(versions(read(&audit.path, &vec![], 0, 10).unwrap()), vec![1, 2, 3]);
+    }
+}
@@ -0,0 +1,1422 @@
+use {
+    crate::{
+        atomic_write,
+        commit,
+        dball::DbVersion,
+        sync_dir,
+        dbv1,
+        latest,
+        storage::Storage,
+        replicate::install_snapshot,
+        token_id,
+        DataPath,
+        State,
+        Writer,
+    },
+    http::{
+        header::{
+            AUTHORIZATION,
+            CONTENT_TYPE,
+        },
+        Method,
+        Uri,
+    },
+    htwrap::{
+        htreq,
+        url::UriJoin,
+    },
+    loga::{
+        ea,
+        ErrContext,
+        Log,
+        ResultContext,
+    },
+    openfdap::interface::config::ClusterConfig,
+    rand::Rng,
+    serde::{
+        de::DeserializeOwned,
+        Deserialize,
+        Serialize,
+    },
+    std::{
+        borrow::Cow,
+        collections::HashMap,
+        fs::{
+            File,
+            OpenOptions,
+        },
+        io::{
+            ErrorKind,
+            Write,
+        },
+        path::{
+            Path,
+            PathBuf,
+        },
+        sync::{
+            Arc,
+            Mutex,
+        },
+        time::Duration,
+    },
+    taskmanager::TaskManager,
+    tokio::{
+        select,
+        sync::{
+            oneshot,
+            watch,
+        },
+        time::{
+            sleep,
+            sleep_until,
+            timeout,
+            timeout_at,
+            Instant,
+        },
+    },
+};
+
+/// Root key of the endpoints cluster nodes use to talk to each other.
+pub const RAFT_ROOT: &str = "fdap_raft";
+pub const RAFT_VOTE: &str = "vote";
+pub const RAFT_APPEND: &str = "append";
+pub const RAFT_SNAPSHOT: &str = "snapshot";
+
+const HEARTBEAT_PERIOD: Duration = Duration::from_millis(250);
+const ELECTION_TIMEOUT_MIN_MS: u64 = 1000;
+const ELECTION_TIMEOUT_MAX_MS: u64 = 2000;
+const RPC_TIMEOUT: Duration = Duration::from_secs(1);
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(30);
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most entries sent in one append request.
+const APPEND_MAX_ENTRIES: usize = 100;
+
+/// Applied entries kept in the log, so peers that fell behind can catch up with
+/// appends rather than a snapshot. Smaller in tests so falling further behind is
+/// quick to test.
+const APPLIED_ENTRIES_KEPT: usize = if cfg!(test) {
+    10
+} else {
+    1000
+};
+
+pub type Term = u64;
+
+/// Who made a change, for the audit log on each node.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct EntryWriter {
+    pub token_id: String,
+    pub method: String,
+    pub path: DataPath,
+}
+
+/// A mutation in the cluster log. Its index in the log is the database version
+/// after it's applied.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct RaftEntry {
+    pub term: Term,
+    pub writer: EntryWriter,
+    pub mutation: dbv1::Mutation,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct VoteRequest {
+    pub term: Term,
+    pub candidate: String,
+    pub last_index: DbVersion,
+    pub last_term: Term,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct VoteResponse {
+    pub term: Term,
+    pub granted: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct AppendRequest {
+    pub term: Term,
+    pub leader: String,
+    pub prev_index: DbVersion,
+    pub prev_term: Term,
+    pub entries: Vec<RaftEntry>,
+    pub leader_commit: DbVersion,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct AppendResponse {
+    pub term: Term,
+    pub success: bool,
+    /// On success, the last index matching the leader. Otherwise, the last index in
+    /// the node's log, as a hint for where to retry from.
+    pub last_index: DbVersion,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct SnapshotRequest {
+    pub term: Term,
+    pub leader: String,
+    /// The term of the last entry included in the snapshot.
+    pub last_term: Term,
+    pub database: latest::Database,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct SnapshotResponse {
+    pub term: Term,
+}
+
+/// Raft state other than the log that must survive restarts. Entries up to
+/// `base_index` have been applied to the database and are dropped from the log.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+struct RaftStoredV1 {
+    term: Term,
+    voted_for: Option<String>,
+    base_index: DbVersion,
+    base_term: Term,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum RaftStored<'a> {
+    V1(Cow<'a, RaftStoredV1>),
+}
+
+/// A line in the log file.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum RaftLogRecord<'a> {
+    V1 {
+        index: DbVersion,
+        entry: Cow<'a, RaftEntry>,
+    },
+}
+
+/// Serialize records for `entries`, the first of which is at `index`. Returns the
+/// data and where each record starts in it.
+fn encode_log_records(index: DbVersion, entries: &[RaftEntry]) -> (Vec<u8>, Vec<u64>) {
+    let mut data = vec![];
+    let mut offsets = vec![];
+    for (i, entry) in entries.iter().enumerate() {
+        offsets.push(data.len() as u64);
+        serde_json::to_writer(&mut data, &RaftLogRecord::V1 {
+            index: index + i,
+            entry: Cow::Borrowed(entry),
+        }).unwrap();
+        data.push(b'\n');
+    }
+    return (data, offsets);
+}
+
+/// The entries after the base, in an append-only file so adding entries doesn't
+/// rewrite the ones before them.
+struct RaftLog {
+    path: PathBuf,
+    file: File,
+    entries: Vec<RaftEntry>,
+    /// Where each entry's record starts in the file.
+    offsets: Vec<u64>,
+}
+
+impl RaftLog {
+    /// Load the entries after `base_index`. Records at or before it are left over
+    /// from compaction that stopped partway and are skipped.
+    fn open(log: &Log, path: PathBuf, base_index: DbVersion) -> Result<RaftLog, loga::Error> {
+        let data = match std::fs::read(&path) {
+            Ok(d) => d,
+            Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.context_with("Error reading cluster log", ea!(path = path.display()))),
+        };
+        let mut entries = vec![];
+        let mut offset = 0;
+        while offset < data.len() {
+            let Some(end) = data[offset..].iter().position(|c| *c == b'\n').map(|len| offset + len) else {
+                // Interrupted while appending, the entry wasn't acknowledged
+                log.log_with(
+                    loga::WARN,
+                    "Dropping incomplete final record from cluster log",
+                    ea!(path = path.display(), offset = offset),
+                );
+                break;
+            };
+            let RaftLogRecord::V1 { index, entry } =
+                serde_json::from_slice::<RaftLogRecord>(
+                    &data[offset .. end],
+                ).context_with("Cluster log record is corrupt", ea!(path = path.display(), offset = offset))?;
+            offset = end + 1;
+            if index <= base_index {
+                continue;
+            }
+            if index != base_index + entries.len() + 1 {
+                return Err(
+                    loga::err_with(
+                        "Cluster log is missing entries",
+                        ea!(path = path.display(), expected_index = base_index + entries.len() + 1, index = index),
+                    ),
+                );
+            }
+            entries.push(entry.into_owned());
+        }
+
+        // Rewrite to drop skipped and incomplete records
+        let (data, offsets) = encode_log_records(base_index + 1, &entries);
+        atomic_write(&path, &data).context("Error rewriting cluster log")?;
+        let file = open_log(&path)?;
+        return Ok(RaftLog {
+            path: path,
+            file: file,
+            entries: entries,
+            offsets: offsets,
+        });
+    }
+
+    /// Durably add entries to the end. The first new entry is at `index`.
+    fn append(&mut self, index: DbVersion, entries: Vec<RaftEntry>) -> Result<(), loga::Error> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let start =
+            self
+                .file
+                .metadata()
+                .context_with("Error reading cluster log metadata", ea!(path = self.path.display()))?
+                .len();
+        let (data, offsets) = encode_log_records(index, &entries);
+        match self.file.write_all(&data).and_then(|_| self.file.sync_data()) {
+            Ok(_) => { },
+            Err(e) => {
+                // Remove any partial record so later records aren't appended to it
+                _ = self.file.set_len(start);
+                return Err(e.context_with("Error writing cluster log", ea!(path = self.path.display())));
+            },
+        }
+        self.entries.extend(entries);
+        self.offsets.extend(offsets.into_iter().map(|o| start + o));
+        return Ok(());
+    }
+
+    /// Durably drop all but the first `len` entries.
+    fn truncate(&mut self, len: usize) -> Result<(), loga::Error> {
+        let Some(start) = self.offsets.get(len).cloned() else {
+            return Ok(());
+        };
+        self
+            .file
+            .set_len(start)
+            .and_then(|_| self.file.sync_data())
+            .context_with("Error truncating cluster log", ea!(path = self.path.display()))?;
+        self.entries.truncate(len);
+        self.offsets.truncate(len);
+        return Ok(());
+    }
+
+    /// Drop the first `count` entries, now at or before the new `base_index`. If
+    /// rewriting the file fails it still has their records, which is okay since
+    /// they're skipped when loading.
+    fn drop_front(&mut self, base_index: DbVersion, count: usize) -> Result<(), loga::Error> {
+        self.entries.drain(.. count);
+        self.offsets.drain(.. count);
+        let (data, offsets) = encode_log_records(base_index + 1, &self.entries);
+        atomic_write(&self.path, &data).context("Error compacting cluster log")?;
+        self.file = open_log(&self.path)?;
+        self.offsets = offsets;
+        return Ok(());
+    }
+}
+
+fn open_log(path: &Path) -> Result<File, loga::Error> {
+    let file =
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context_with("Error opening cluster log", ea!(path = path.display()))?;
+    sync_dir(path.parent().unwrap())?;
+    return Ok(file);
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+struct Raft {
+    stored: RaftStoredV1,
+    log: RaftLog,
+    role: Role,
+    /// The current leader, if known.
+    leader: Option<String>,
+    commit_index: DbVersion,
+    election_deadline: Instant,
+    /// Leader only, the next entry to send to each peer.
+    next_index: HashMap<String, DbVersion>,
+    /// Leader only, the last entry known to be replicated to each peer.
+    match_index: HashMap<String, DbVersion>,
+    /// Writes on this node waiting for their entries to be applied, by index.
+    pending: HashMap<DbVersion, (Term, oneshot::Sender<Result<DbVersion, String>>)>,
+}
+
+fn election_deadline() -> Instant {
+    return Instant::now() +
+        Duration::from_millis(rand::thread_rng().gen_range(ELECTION_TIMEOUT_MIN_MS .. ELECTION_TIMEOUT_MAX_MS));
+}
+
+impl Raft {
+    fn last_index(&self) -> DbVersion {
+        return self.stored.base_index + self.log.entries.len();
+    }
+
+    fn last_term(&self) -> Term {
+        return self.log.entries.last().map(|e| e.term).unwrap_or(self.stored.base_term);
+    }
+
+    /// The term of the entry at `index`, if it's the base or still in the log.
+    fn term_at(&self, index: DbVersion) -> Option<Term> {
+        if index == self.stored.base_index {
+            return Some(self.stored.base_term);
+        }
+        return self.entry(index).map(|e| e.term);
+    }
+
+    fn entry(&self, index: DbVersion) -> Option<&RaftEntry> {
+        if index <= self.stored.base_index {
+            return None;
+        }
+        return self.log.entries.get(index - self.stored.base_index - 1);
+    }
+
+    /// Drop entries from `index` on, failing any writes waiting for them.
+    fn truncate_from(&mut self, index: DbVersion) -> Result<(), loga::Error> {
+        self.log.truncate(index - self.stored.base_index - 1)?;
+        let dropped = self.pending.keys().filter(|i| **i >= index).cloned().collect::<Vec<_>>();
+        for i in dropped {
+            let (_, tx) = self.pending.remove(&i).unwrap();
+            _ = tx.send(Err(format!("The change may have been discarded by a new cluster leader")));
+        }
+        return Ok(());
+    }
+
+    /// Become a follower, in a newer term if `term` is newer. Writes waiting on this
+    /// node as leader are failed, since it can no longer tell when (or if) they'll
+    /// be committed. Returns true if the term changed, so the state needs to be
+    /// persisted.
+    fn step_down(&mut self, term: Term) -> bool {
+        let newer = term > self.stored.term;
+        if newer {
+            self.stored.term = term;
+            self.stored.voted_for = None;
+            self.leader = None;
+        }
+        if self.role == Role::Leader {
+            for (_, (_, tx)) in self.pending.drain() {
+                _ = tx.send(
+                    Err(format!("This node stopped being the cluster leader, the change may or may not be applied")),
+                );
+            }
+        }
+        self.role = Role::Follower;
+        return newer;
+    }
+}
+
+pub struct Cluster {
+    log: Log,
+    node_id: String,
+    /// Other nodes by id.
+    peers: HashMap<String, Uri>,
+    token: String,
+    /// `token_id` of `token`, for checking requests from other nodes.
+    token_hash: String,
+    path: PathBuf,
+    raft: Mutex<Raft>,
+    /// Sent when entries are added to the log, to wake up replication to peers.
+    log_changed: watch::Sender<()>,
+    /// Sent when the commit index advances, to wake up applying entries.
+    commit_changed: watch::Sender<()>,
+    /// Held for the duration of each write on the leader, so each write is checked
+    /// against the data after all previous writes.
+    propose_lock: tokio::sync::Mutex<()>,
+}
+
+/// Where writes should go.
+pub enum ClusterLeader {
+    This,
+    Other(Uri),
+    Unknown,
+}
+
+impl Cluster {
+    /// Load the cluster state from the data dir. `version` is the current database
+    /// version, all entries up to it have already been applied.
+    pub fn open(log: &Log, dir: &Path, config: ClusterConfig, version: DbVersion) -> Result<Cluster, loga::Error> {
+        let mut peers = HashMap::new();
+        for (id, url) in config.nodes {
+            if id == config.node_id {
+                continue;
+            }
+            peers.insert(
+                id.clone(),
+                Uri::try_from(&url).context_with("Cluster node URL is invalid", ea!(node = id, url = url))?,
+            );
+        }
+        let path = dir.join("raft.json");
+        let mut stored = match std::fs::read(&path) {
+            Ok(data) => match serde_json::from_slice::<RaftStored>(
+                &data,
+            ).context_with("Error parsing cluster state", ea!(path = path.display()))? {
+                RaftStored::V1(s) => s.into_owned(),
+            },
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    return Err(e.context_with("Error reading cluster state", ea!(path = path.display())));
+                }
+                RaftStoredV1 {
+                    term: 0,
+                    voted_for: None,
+                    base_index: version,
+                    base_term: 0,
+                }
+            },
+        };
+        if stored.base_index > version {
+            return Err(
+                loga::err_with(
+                    "Cluster state is ahead of the database",
+                    ea!(path = path.display(), state_version = stored.base_index, database_version = version),
+                ),
+            );
+        }
+        let mut raft_log = RaftLog::open(log, dir.join("raft_log.jsonl"), stored.base_index)?;
+        if version > stored.base_index + raft_log.entries.len() {
+            // A snapshot was installed but the log wasn't updated before stopping
+            stored.base_term = raft_log.entries.last().map(|e| e.term).unwrap_or(stored.base_term);
+            stored.base_index = version;
+            raft_log.truncate(0)?;
+        }
+        let mut raft = Raft {
+            commit_index: version,
+            stored: stored,
+            log: raft_log,
+            role: Role::Follower,
+            leader: None,
+            election_deadline: election_deadline(),
+            next_index: Default::default(),
+            match_index: Default::default(),
+            pending: Default::default(),
+        };
+        if peers.is_empty() {
+            // Nobody else to wait for
+            raft.election_deadline = Instant::now();
+        }
+        let out = Cluster {
+            log: log.fork(ea!(node = config.node_id)),
+            node_id: config.node_id,
+            peers: peers,
+            token_hash: token_id(&config.token),
+            token: config.token,
+            path: path,
+            raft: Mutex::new(raft),
+            log_changed: watch::channel(()).0,
+            commit_changed: watch::channel(()).0,
+            propose_lock: tokio::sync::Mutex::new(()),
+        };
+        out.persist(&out.raft.lock().unwrap().stored)?;
+        return Ok(out);
+    }
+
+    /// Whether a request has the cluster token. The hashes are compared so the
+    /// comparison time doesn't reveal how much of the token matched.
+    pub fn check_token(&self, token: &str) -> bool {
+        return token_id(token) == self.token_hash;
+    }
+
+    /// Save the term, vote and base. Entries are saved separately as they're added.
+    fn persist(&self, stored: &RaftStoredV1) -> Result<(), loga::Error> {
+        atomic_write(
+            &self.path,
+            &serde_json::to_vec(&RaftStored::V1(Cow::Borrowed(stored))).unwrap(),
+        ).context("Error writing cluster state")?;
+        return Ok(());
+    }
+
+    fn majority(&self) -> usize {
+        return (self.peers.len() + 1) / 2 + 1;
+    }
+
+    /// Advance the commit index to the newest entry from this term on a majority of
+    /// nodes. Returns true if it advanced.
+    fn advance_commit(&self, raft: &mut Raft) -> bool {
+        let mut n = raft.last_index();
+        while n > raft.commit_index {
+            if raft.term_at(n) != Some(raft.stored.term) {
+                // Entries from earlier terms are only committed along with a newer one
+                break;
+            }
+            let count = 1 + raft.match_index.values().filter(|m| **m >= n).count();
+            if count >= self.majority() {
+                raft.commit_index = n;
+                return true;
+            }
+            n -= 1;
+        }
+        return false;
+    }
+
+    pub fn leader(&self) -> ClusterLeader {
+        let raft = self.raft.lock().unwrap();
+        match &raft.leader {
+            Some(l) if *l == self.node_id => return ClusterLeader::This,
+            Some(l) => match self.peers.get(l) {
+                Some(url) => return ClusterLeader::Other(url.clone()),
+                None => return ClusterLeader::Unknown,
+            },
+            None => return ClusterLeader::Unknown,
+        }
+    }
+
+    /// Wait for a turn to write. Returns once this node (the leader) has applied all
+    /// entries in its log, so the write can be checked against current data.
+    pub async fn start_write(&self, self0: &State) -> Result<tokio::sync::MutexGuard<'_, ()>, String> {
+        let guard = self.propose_lock.lock().await;
+        let deadline = Instant::now() + PROPOSE_TIMEOUT;
+        let mut changes = self0.changes.subscribe();
+        loop {
+            let version = self0.database.read().unwrap().version();
+            {
+                let raft = self.raft.lock().unwrap();
+                if raft.role != Role::Leader {
+                    return Err(format!("This node is no longer the cluster leader"));
+                }
+                if version == raft.last_index() {
+                    return Ok(guard);
+                }
+            }
+            if timeout_at(deadline, changes.changed()).await.is_err() {
+                return Err(format!("Timed out waiting for the cluster to commit earlier changes"));
+            }
+        }
+    }
+
+    /// Add a mutation to the log and wait for it to be committed and applied,
+    /// returning the new version. `based_on` is the version the mutation was checked
+    /// against.
+    pub async fn propose(
+        &self,
+        based_on: DbVersion,
+        writer: EntryWriter,
+        mutation: dbv1::Mutation,
+    ) -> Result<DbVersion, String> {
+        let rx = {
+            let mut raft = self.raft.lock().unwrap();
+            if raft.role != Role::Leader {
+                return Err(format!("This node is no longer the cluster leader"));
+            }
+            if raft.last_index() != based_on {
+                return Err(format!("The data changed while the write was being checked, retry"));
+            }
+            let term = raft.stored.term;
+            let index = raft.last_index() + 1;
+            if let Err(e) = raft.log.append(index, vec![RaftEntry {
+                term: term,
+                writer: writer,
+                mutation: mutation,
+            }]) {
+                self.log.log_err(loga::WARN, e);
+                return Err(format!("Error logging change"));
+            }
+            let (tx, rx) = oneshot::channel();
+            raft.pending.insert(index, (term, tx));
+            if self.advance_commit(&mut raft) {
+                self.commit_changed.send_replace(());
+            }
+            rx
+        };
+        self.log_changed.send_replace(());
+        match timeout(PROPOSE_TIMEOUT, rx).await {
+            Ok(Ok(res)) => return res,
+            Ok(Err(_)) => return Err(format!("Write was dropped")),
+            Err(_) => return Err(
+                format!("Timed out waiting for the cluster to commit the change, it may still be applied later"),
+            ),
+        }
+    }
+
+    pub fn handle_vote(&self, req: VoteRequest) -> Result<VoteResponse, loga::Error> {
+        let mut raft = self.raft.lock().unwrap();
+        if req.term < raft.stored.term {
+            return Ok(VoteResponse {
+                term: raft.stored.term,
+                granted: false,
+            });
+        }
+        let mut changed = false;
+        if req.term > raft.stored.term {
+            raft.step_down(req.term);
+            changed = true;
+        }
+        let up_to_date = (req.last_term, req.last_index) >= (raft.last_term(), raft.last_index());
+        let granted = up_to_date && raft.stored.voted_for.as_ref().map_or(true, |v| *v == req.candidate);
+        if granted {
+            changed = changed || raft.stored.voted_for.is_none();
+            raft.stored.voted_for = Some(req.candidate);
+            raft.election_deadline = election_deadline();
+        }
+        if changed {
+            self.persist(&raft.stored)?;
+        }
+        return Ok(VoteResponse {
+            term: raft.stored.term,
+            granted: granted,
+        });
+    }
+
+    pub fn handle_append(&self, req: AppendRequest) -> Result<AppendResponse, loga::Error> {
+        let mut raft = self.raft.lock().unwrap();
+        if req.term < raft.stored.term {
+            return Ok(AppendResponse {
+                term: raft.stored.term,
+                success: false,
+                last_index: raft.last_index(),
+            });
+        }
+        if raft.step_down(req.term) {
+            self.persist(&raft.stored)?;
+        }
+        raft.leader = Some(req.leader);
+        raft.election_deadline = election_deadline();
+        let last_new = req.prev_index + req.entries.len();
+        let mut prev_index = req.prev_index;
+        let mut entries = req.entries;
+        if prev_index < raft.stored.base_index {
+            // Entries up to the base were committed, so already match
+            let skip = (raft.stored.base_index - prev_index).min(entries.len());
+            entries.drain(.. skip);
+            prev_index += skip;
+            if prev_index < raft.stored.base_index {
+                return Ok(AppendResponse {
+                    term: raft.stored.term,
+                    success: true,
+                    last_index: last_new,
+                });
+            }
+        }
+        if prev_index > raft.last_index() {
+            return Ok(AppendResponse {
+                term: raft.stored.term,
+                success: false,
+                last_index: raft.last_index(),
+            });
+        }
+        if prev_index > raft.stored.base_index && raft.term_at(prev_index) != Some(req.prev_term) {
+            return Ok(AppendResponse {
+                term: raft.stored.term,
+                success: false,
+                last_index: prev_index - 1,
+            });
+        }
+        let mut new_entries = vec![];
+        for (i, entry) in entries.into_iter().enumerate() {
+            let index = prev_index + 1 + i;
+            match raft.term_at(index) {
+                Some(t) if t == entry.term => {
+                    continue;
+                },
+                Some(_) => {
+                    raft.truncate_from(index)?;
+                },
+                None => { },
+            }
+            new_entries.push(entry);
+        }
+        let index = raft.last_index() + 1;
+        raft.log.append(index, new_entries)?;
+        if req.leader_commit > raft.commit_index {
+            let commit_index = req.leader_commit.min(last_new);
+            if commit_index > raft.commit_index {
+                raft.commit_index = commit_index;
+                self.commit_changed.send_replace(());
+            }
+        }
+        return Ok(AppendResponse {
+            term: raft.stored.term,
+            success: true,
+            last_index: last_new,
+        });
+    }
+
+    pub fn handle_snapshot(&self, self0: &State, req: SnapshotRequest) -> Result<SnapshotResponse, loga::Error> {
+        let mut db = self0.database.write().unwrap();
+        let mut raft = self.raft.lock().unwrap();
+        if req.term < raft.stored.term {
+            return Ok(SnapshotResponse { term: raft.stored.term });
+        }
+        if raft.step_down(req.term) {
+            self.persist(&raft.stored)?;
+        }
+        raft.leader = Some(req.leader);
+        raft.election_deadline = election_deadline();
+        let version = req.database.version;
+        if version <= db.version() {
+            // Already have everything in it
+            return Ok(SnapshotResponse { term: raft.stored.term });
+        }
+        self.log.log_with(loga::INFO, "Replacing database with snapshot from cluster leader", ea!(version = version));
+        install_snapshot(self0, &mut **db, req.database)?;
+
+        // Clear the log before moving the base, so a crash in between doesn't leave
+        // entries that follow the old base after the new one
+        let after_base = raft.stored.base_index + 1;
+        raft.truncate_from(after_base)?;
+        raft.stored.base_index = version;
+        raft.stored.base_term = req.last_term;
+        raft.commit_index = raft.commit_index.max(version);
+        self.persist(&raft.stored)?;
+        drop(raft);
+        drop(db);
+        self0.changes.send_replace(version);
+        return Ok(SnapshotResponse { term: req.term });
+    }
+}
+
+/// Make a request to another node, reusing the connection if there is one. The
+/// connection is cleared on errors.
+async fn rpc<
+    Req: Serialize,
+    Resp: DeserializeOwned,
+>(
+    log: &Log,
+    conn: &mut Option<htreq::Conn>,
+    base_url: &Uri,
+    token: &str,
+    method: &str,
+    req: &Req,
+    limit: Duration,
+) -> Result<Resp, loga::Error> {
+    let url = base_url.join(format!("{}/{}", RAFT_ROOT, method));
+    let body = serde_json::to_vec(req).unwrap();
+    let headers =
+        HashMap::from(
+            [
+                (AUTHORIZATION.to_string(), format!("Bearer {}", token)),
+                (CONTENT_TYPE.to_string(), "application/json".to_string()),
+            ],
+        );
+    let limits = htreq::Limits::default();
+    let res = timeout(limit, async {
+        if conn.is_none() {
+            *conn = Some(htreq::connect(limits, &url).await.context("Error connecting to cluster node")?);
+        }
+        let resp = htreq::send(log, limits, conn.as_mut().unwrap(), &url, Method::POST, &headers, body).await?;
+        let code = resp.code;
+        let body = htreq::receive(resp.body, limits).await?;
+        if !code.is_success() {
+            return Err(
+                loga::err_with(
+                    "Cluster node rejected request",
+                    ea!(status = code, body = String::from_utf8_lossy(&body)),
+                ),
+            );
+        }
+        return Ok(serde_json::from_slice::<Resp>(&body).context("Cluster node sent invalid response")?);
+    }).await.unwrap_or_else(|_| Err(loga::err("Request to cluster node timed out")));
+    if res.is_err() {
+        *conn = None;
+    }
+    return res;
+}
+
+/// Start an election if the leader hasn't been heard from in time.
+async fn elect(tm: TaskManager, state: Arc<State>) {
+    let cluster = state.cluster.as_ref().unwrap();
+    loop {
+        let deadline = {
+            let raft = cluster.raft.lock().unwrap();
+            if raft.role == Role::Leader {
+                Instant::now() + HEARTBEAT_PERIOD
+            } else {
+                raft.election_deadline
+            }
+        };
+        let Some(_) = tm.if_alive(sleep_until(deadline)).await else {
+            break;
+        };
+        let req = {
+            let mut raft = cluster.raft.lock().unwrap();
+            if raft.role == Role::Leader || Instant::now() < raft.election_deadline {
+                continue;
+            }
+            raft.stored.term += 1;
+            raft.stored.voted_for = Some(cluster.node_id.clone());
+            raft.role = Role::Candidate;
+            raft.leader = None;
+            raft.election_deadline = election_deadline();
+            if let Err(e) = cluster.persist(&raft.stored) {
+                cluster.log.log_err(loga::WARN, e.context("Error starting election"));
+                continue;
+            }
+            VoteRequest {
+                term: raft.stored.term,
+                candidate: cluster.node_id.clone(),
+                last_index: raft.last_index(),
+                last_term: raft.last_term(),
+            }
+        };
+        cluster.log.log_with(loga::DEBUG, "Starting election", ea!(term = req.term));
+        let Some(responses) = tm.if_alive(futures::future::join_all(cluster.peers.iter().map(|(id, url)| {
+            let req = &req;
+            async move {
+                let res =
+                    rpc::<VoteRequest, VoteResponse>(
+                        &cluster.log,
+                        &mut None,
+                        url,
+                        &cluster.token,
+                        RAFT_VOTE,
+                        req,
+                        RPC_TIMEOUT,
+                    ).await;
+                if let Err(e) = &res {
+                    cluster.log.log_with(loga::DEBUG, "Error requesting vote", ea!(peer = id, err = e));
+                }
+                res
+            }
+        }))).await else {
+            break;
+        };
+        let mut raft = cluster.raft.lock().unwrap();
+        let mut votes = 1;
+        for resp in responses.into_iter().flatten() {
+            if resp.term > raft.stored.term {
+                raft.step_down(resp.term);
+                if let Err(e) = cluster.persist(&raft.stored) {
+                    cluster.log.log_err(loga::WARN, e);
+                }
+                break;
+            }
+            if resp.granted {
+                votes += 1;
+            }
+        }
+        if raft.role != Role::Candidate || raft.stored.term != req.term || votes < cluster.majority() {
+            continue;
+        }
+
+        // Won, mark the new term with an entry so earlier entries get committed
+        raft.role = Role::Leader;
+        raft.leader = Some(cluster.node_id.clone());
+        let last_index = raft.last_index();
+        raft.next_index = cluster.peers.keys().map(|id| (id.clone(), last_index + 1)).collect();
+        raft.match_index = cluster.peers.keys().map(|id| (id.clone(), 0)).collect();
+        let term = raft.stored.term;
+        if let Err(e) = raft.log.append(last_index + 1, vec![RaftEntry {
+            term: term,
+            writer: EntryWriter {
+                token_id: String::new(),
+                method: "LEADER".to_string(),
+                path: vec![],
+            },
+            mutation: dbv1::Mutation::Noop,
+        }]) {
+            cluster.log.log_err(loga::WARN, e.context("Error recording leadership, stepping down"));
+            raft.role = Role::Follower;
+            raft.leader = None;
+            continue;
+        }
+        if cluster.advance_commit(&mut raft) {
+            cluster.commit_changed.send_replace(());
+        }
+        drop(raft);
+        cluster.log.log_with(loga::INFO, "Elected cluster leader", ea!(term = term));
+        cluster.log_changed.send_replace(());
+    }
+}
+
+enum PeerRequest {
+    Append(AppendRequest),
+    Snapshot(SnapshotRequest),
+}
+
+/// The next request to send to a peer, if this is the leader. The database is
+/// locked first, like when applying entries, so the log can't be compacted past
+/// the snapshot while it's being made.
+fn peer_request(self0: &State, cluster: &Cluster, peer: &str) -> Option<PeerRequest> {
+    let db = self0.database.read().unwrap();
+    let raft = cluster.raft.lock().unwrap();
+    if raft.role != Role::Leader {
+        return None;
+    }
+    let next = raft.next_index[peer];
+    if next <= raft.stored.base_index {
+        // The peer is further behind than the applied entries kept in the log
+        let database = match db.snapshot() {
+            Ok(d) => d,
+            Err(e) => {
+                cluster.log.log_err(loga::WARN, e.context("Error reading database to send snapshot"));
+                return None;
+            },
+        };
+        return Some(PeerRequest::Snapshot(SnapshotRequest {
+            term: raft.stored.term,
+            leader: cluster.node_id.clone(),
+            last_term: raft.term_at(database.version).unwrap(),
+            database: database,
+        }));
+    }
+    let start = next - raft.stored.base_index - 1;
+    let end = raft.log.entries.len().min(start + APPEND_MAX_ENTRIES);
+    return Some(PeerRequest::Append(AppendRequest {
+        term: raft.stored.term,
+        leader: cluster.node_id.clone(),
+        prev_index: next - 1,
+        prev_term: raft.term_at(next - 1).unwrap(),
+        entries: raft.log.entries[start .. end].to_vec(),
+        leader_commit: raft.commit_index,
+    }));
+}
+
+/// Send log entries (or heartbeats) to a peer while this is the leader.
+async fn replicate_to(tm: TaskManager, state: Arc<State>, peer: String, url: Uri) {
+    let cluster = state.cluster.as_ref().unwrap();
+    let mut conn = None;
+    let mut log_changed = cluster.log_changed.subscribe();
+    loop {
+        let mut more = false;
+        match peer_request(&state, cluster, &peer) {
+            None => { },
+            Some(PeerRequest::Append(req)) => {
+                let Some(res) =
+                    tm
+                        .if_alive(
+                            rpc::<AppendRequest, AppendResponse>(
+                                &cluster.log,
+                                &mut conn,
+                                &url,
+                                &cluster.token,
+                                RAFT_APPEND,
+                                &req,
+                                RPC_TIMEOUT,
+                            ),
+                        )
+                        .await else {
+                        break;
+                    };
+                match res {
+                    Ok(resp) => {
+                        let mut raft = cluster.raft.lock().unwrap();
+                        if resp.term > raft.stored.term {
+                            raft.step_down(resp.term);
+                            if let Err(e) = cluster.persist(&raft.stored) {
+                                cluster.log.log_err(loga::WARN, e);
+                            }
+                        } else if raft.role == Role::Leader && raft.stored.term == req.term {
+                            if resp.success {
+                                let matched = resp.last_index.max(raft.match_index[&peer]);
+                                raft.match_index.insert(peer.clone(), matched);
+                                raft.next_index.insert(peer.clone(), matched + 1);
+                                if cluster.advance_commit(&mut raft) {
+                                    cluster.commit_changed.send_replace(());
+                                }
+                            } else {
+                                let next = (raft.next_index[&peer] - 1).min(resp.last_index + 1).max(1);
+                                raft.next_index.insert(peer.clone(), next);
+                            }
+                            more = raft.next_index[&peer] <= raft.last_index();
+                        }
+                    },
+                    Err(e) => {
+                        cluster.log.log_with(loga::DEBUG, "Error sending entries to peer", ea!(peer = peer, err = e));
+                    },
+                }
+            },
+            Some(PeerRequest::Snapshot(req)) => {
+                let version = req.database.version;
+                let term = req.term;
+                let Some(res) =
+                    tm
+                        .if_alive(
+                            rpc::<SnapshotRequest, SnapshotResponse>(
+                                &cluster.log,
+                                &mut conn,
+                                &url,
+                                &cluster.token,
+                                RAFT_SNAPSHOT,
+                                &req,
+                                SNAPSHOT_TIMEOUT,
+                            ),
+                        )
+                        .await else {
+                        break;
+                    };
+                match res {
+                    Ok(resp) => {
+                        let mut raft = cluster.raft.lock().unwrap();
+                        if resp.term > raft.stored.term {
+                            raft.step_down(resp.term);
+                            if let Err(e) = cluster.persist(&raft.stored) {
+                                cluster.log.log_err(loga::WARN, e);
+                            }
+                        } else if raft.role == Role::Leader && raft.stored.term == term {
+                            let matched = version.max(raft.match_index[&peer]);
+                            raft.match_index.insert(peer.clone(), matched);
+                            raft.next_index.insert(peer.clone(), matched + 1);
+                            if cluster.advance_commit(&mut raft) {
+                                cluster.commit_changed.send_replace(());
+                            }
+                            more = raft.next_index[&peer] <= raft.last_index();
+                        }
+                    },
+                    Err(e) => {
+                        cluster.log.log_with(loga::DEBUG, "Error sending snapshot to peer", ea!(peer = peer, err = e));
+                    },
+                }
+            },
+        }
+        if more {
+            continue;
+        }
+        let Some(_) = tm.if_alive(async {
+            select!{
+                _ = log_changed.changed() => { },
+                _ = sleep(HEARTBEAT_PERIOD) => { },
+            }
+        }).await else {
+            break;
+        };
+    }
+}
+
+fn apply_committed_locked(self0: &State, cluster: &Cluster, db: &mut dyn Storage) -> Result<(), loga::Error> {
+    loop {
+        let index = db.version() + 1;
+        let entry = {
+            let raft = cluster.raft.lock().unwrap();
+            if db.version() >= raft.commit_index {
+                break;
+            }
+            raft
+                .entry(index)
+                .cloned()
+                .ok_or_else(|| loga::err_with("Committed cluster log entry is missing", ea!(index = index)))?
+        };
+        let version = commit(self0, db, &Writer {
+            token_id: &entry.writer.token_id,
+            method: &entry.writer.method,
+            path: &entry.writer.path,
+        }, entry.mutation)?;
+        if version != index {
+            return Err(
+                loga::err_with("Database version doesn't match cluster log", ea!(version = version, index = index)),
+            );
+        }
+        let mut raft = cluster.raft.lock().unwrap();
+        if let Some((term, tx)) = raft.pending.remove(&index) {
+            _ = tx.send(if term == entry.term {
+                Ok(version)
+            } else {
+                Err(format!("The change was discarded by a new cluster leader"))
+            });
+        }
+    }
+
+    // Drop old applied entries from the log, in batches so the file isn't rewritten
+    // for every entry
+    let mut raft = cluster.raft.lock().unwrap();
+    if db.version() - raft.stored.base_index > 2 * APPLIED_ENTRIES_KEPT {
+        let base_index = db.version() - APPLIED_ENTRIES_KEPT;
+        let mut stored = raft.stored.clone();
+        stored.base_term = raft.term_at(base_index).unwrap();
+        stored.base_index = base_index;
+
+        // Move the base first, so a crash before the file is rewritten just leaves
+        // records that are skipped when loading
+        cluster.persist(&stored)?;
+        let count = base_index - raft.stored.base_index;
+        raft.stored = stored;
+        raft.log.drop_front(base_index, count)?;
+    }
+    return Ok(());
+}
+
+/// Apply newly committed entries to the database.
+fn apply_committed(self0: &State, cluster: &Cluster) -> Result<(), loga::Error> {
+    let mut db = self0.database.write().unwrap();
+    let start = db.version();
+    let res = apply_committed_locked(self0, cluster, &mut **db);
+    let version = db.version();
+    drop(db);
+    if version != start {
+        self0.changes.send_replace(version);
+    }
+    return res;
+}
+
+async fn apply(tm: TaskManager, state: Arc<State>) {
+    let cluster = state.cluster.as_ref().unwrap();
+    let mut commit_changed = cluster.commit_changed.subscribe();
+    loop {
+        if let Err(e) = apply_committed(&state, cluster) {
+            // Can't skip entries, so retrying is the only option
+            cluster.log.log_err(loga::ERROR, e.context("Error applying committed cluster changes"));
+            let Some(_) = tm.if_alive(sleep(HEARTBEAT_PERIOD)).await else {
+                break;
+            };
+            continue;
+        }
+        let Some(_) = tm.if_alive(commit_changed.changed()).await else {
+            break;
+        };
+    }
+}
+
+/// Start elections, replication, and applying committed changes.
+pub fn start(tm: &TaskManager, state: &Arc<State>) {
+    let cluster = state.cluster.as_ref().unwrap();
+    tm.task("Cluster election", elect(tm.clone(), state.clone()));
+    tm.task("Cluster apply", apply(tm.clone(), state.clone()));
+    for (id, url) in &cluster.peers {
+        tm.task(format!("Cluster replication - {}", id), replicate_to(tm.clone(), state.clone(), id.clone(), url.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            ClusterLeader,
+            APPLIED_ENTRIES_KEPT,
+        },
+        crate::{
+            dball::DbVersion,
+            test_util::{
+                free_addr,
+                test_config,
+                wait_for,
+                wait_value,
+                TestNode,
+                TEST_WAIT,
+            },
+        },
+        http::Method,
+        serde_json::{
+            json,
+            Value,
+        },
+        std::collections::HashMap,
+    };
+
+    const CLUSTER_TOKEN: &str = "cluster-token";
+
+    /// Nodes running in the test process, which can be stopped and restarted with
+    /// the same data.
+    struct TestCluster {
+        configs: Vec<Value>,
+        nodes: Vec<Option<TestNode>>,
+        _dir: tempfile::TempDir,
+    }
+
+    impl TestCluster {
+        async fn start(count: usize) -> TestCluster {
+            let dir = tempfile::tempdir().unwrap();
+            let addrs = (0 .. count).map(|_| free_addr()).collect::<Vec<_>>();
+            let urls =
+                addrs
+                    .iter()
+                    .enumerate()
+                    .map(|(i, addr)| (format!("node{}", i), format!("http://{}/", addr)))
+                    .collect::<HashMap<_, _>>();
+            let mut configs = vec![];
+            for (i, addr) in addrs.iter().enumerate() {
+                let mut config = test_config(&dir.path().join(format!("node{}", i)), addr);
+                config["cluster"] = json!({
+                    "node_id": format!("node{}", i),
+                    "nodes": urls,
+                    "token": CLUSTER_TOKEN,
+                });
+                configs.push(config);
+            }
+            let mut nodes = vec![];
+            for config in &configs {
+                nodes.push(Some(TestNode::start(config.clone()).await));
+            }
+            return TestCluster {
+                configs: configs,
+                nodes: nodes,
+                _dir: dir,
+            };
+        }
+
+        fn node(&self, i: usize) -> &TestNode {
+            return self.nodes[i].as_ref().unwrap();
+        }
+
+        async fn kill(&mut self, i: usize) {
+            self.nodes[i].take().unwrap().kill().await;
+        }
+
+        async fn restart(&mut self, i: usize) {
+            self.nodes[i] = Some(TestNode::start(self.configs[i].clone()).await);
+        }
+
+        /// Wait until a running node is the leader, returning its index.
+        async fn wait_leader(&self) -> usize {
+            let nodes = &self.nodes;
+            return wait_for(TEST_WAIT, move || async move {
+                for (i, node) in nodes.iter().enumerate() {
+                    let Some(node) = node else {
+                        continue;
+                    };
+                    if matches!(node.state.cluster.as_ref().unwrap().leader(), ClusterLeader::This) {
+                        return Some(i);
+                    }
+                }
+                return None;
+            }).await;
+        }
+
+        async fn shutdown(self) {
+            for node in self.nodes.into_iter().flatten() {
+                node.kill().await;
+            }
+        }
+    }
+
+    /// Write, retrying while the cluster has no leader.
+    async fn set(node: &TestNode, path: &str, value: Value) {
+        let value = &value;
+        wait_for(TEST_WAIT, move || async move {
+            match node.request(Method::POST, path, Some(value.clone())).await {
+                Ok((200, _)) => return Some(()),
+                _ => return None,
+            }
+        }).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cluster_election() {
+        let cluster = TestCluster::start(3).await;
+        let leader = cluster.wait_leader().await;
+
+        // The others learn who the leader is
+        for i in 0 .. 3 {
+            if i == leader {
+                continue;
+            }
+            let node = cluster.node(i);
+            wait_for(TEST_WAIT, move || async move {
+                match node.state.cluster.as_ref().unwrap().leader() {
+                    ClusterLeader::Other(_) => return Some(()),
+                    _ => return None,
+                }
+            }).await;
+        }
+
+        // Being elected doesn't change the data
+        set(cluster.node(leader), "a", json!(1)).await;
+        for i in 0 .. 3 {
+            wait_value(cluster.node(i), "", json!({
+                "a": 1
+            })).await;
+        }
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cluster_commit_with_node_down() {
+        let mut cluster = TestCluster::start(3).await;
+        let leader = cluster.wait_leader().await;
+        let down = (leader + 1) % 3;
+        let other = (leader + 2) % 3;
+        cluster.kill(down).await;
+        set(cluster.node(leader), "a", json!(1)).await;
+        wait_value(cluster.node(other), "a", json!(1)).await;
+
+        // Writes to other nodes are sent to the leader
+        set(cluster.node(other), "b", json!(2)).await;
+        wait_value(cluster.node(leader), "b", json!(2)).await;
+
+        // Without a majority nothing is committed
+        cluster.kill(other).await;
+        let (status, _) = cluster.node(leader).request(Method::POST, "c", Some(json!(3))).await.unwrap();
+        assert_eq!(status, 503);
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cluster_failover() {
+        let mut cluster = TestCluster::start(3).await;
+        let old = cluster.wait_leader().await;
+        set(cluster.node(old), "a", json!(1)).await;
+        cluster.kill(old).await;
+        let new = cluster.wait_leader().await;
+        assert_ne!(new, old);
+
+        // Committed changes survive
+        wait_value(cluster.node(new), "a", json!(1)).await;
+        set(cluster.node(new), "a", json!(2)).await;
+
+        // The old leader rejoins as a follower
+        cluster.restart(old).await;
+        wait_value(cluster.node(old), "a", json!(2)).await;
+        assert!(matches!(cluster.node(old).state.cluster.as_ref().unwrap().leader(), ClusterLeader::Other(_)));
+        cluster.shutdown().await;
+    }
+
+    fn base_index(node: &TestNode) -> DbVersion {
+        return node.state.cluster.as_ref().unwrap().raft.lock().unwrap().stored.base_index;
+    }
+
+    /// Stop a follower, make `count` writes, then restart it and check it catches
+    /// up. Returns whether it caught up with a snapshot.
+    async fn catch_up(count: usize) -> bool {
+        let mut cluster = TestCluster::start(3).await;
+        let leader = cluster.wait_leader().await;
+        let down = (leader + 1) % 3;
+        set(cluster.node(leader), "a", json!(1)).await;
+        wait_value(cluster.node(down), "a", json!(1)).await;
+        let down_version = cluster.node(down).state.database.read().unwrap().version();
+        cluster.kill(down).await;
+        let mut want = json!({
+            "a": 1
+        });
+        for i in 0 .. count {
+            set(cluster.node(leader), &format!("k{}", i), json!(i)).await;
+            want[format!("k{}", i)] = json!(i);
+        }
+        let snapshot = base_index(cluster.node(leader)) > down_version;
+        cluster.restart(down).await;
+        wait_value(cluster.node(down), "", want).await;
+
+        // A snapshot moves the base of the restarted node's log past what it had
+        assert_eq!(base_index(cluster.node(down)) > down_version, snapshot);
+
+        // And then follows new changes normally
+        set(cluster.node(leader), "a", json!(2)).await;
+        wait_value(cluster.node(down), "a", json!(2)).await;
+        cluster.shutdown().await;
+        return snapshot;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cluster_append_catch_up() {
+        // Recently applied entries are still in the log
+        assert!(!catch_up(5).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cluster_snapshot_catch_up() {
+        // Entries the stopped node needs have been dropped from the log
+        assert!(catch_up(3 * APPLIED_ENTRIES_KEPT).await);
+    }
+}
@@ -0,0 +1,536 @@
+use {
+    crate::{
+        parse_array_index,
+        DataPath,
+    },
+    openfdap::interface::config::{
+        AccessAction,
+        AccessPathSeg,
+        UserDetails,
+    },
+    chrono::{
+        DateTime,
+        Utc,
+    },
+    std::collections::HashMap,
+};
+
+// How specific a rule segment is, higher is more specific
+const RANK_RECURSIVE: u8 = 0;
+const RANK_WILDCARD: u8 = 1;
+const RANK_GLOB: u8 = 2;
+const RANK_EXACT: u8 = 3;
+
+/// Match a segment against a pattern where `*` matches any run of characters.
+pub fn glob_matches(pattern: &str, seg: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap();
+    let Some(mut rest) = seg.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts = parts.collect::<Vec<_>>();
+    let Some(last) = parts.pop() else {
+        // No `*`
+        return rest.is_empty();
+    };
+    for part in parts {
+        let Some(i) = rest.find(part) else {
+            return false;
+        };
+        rest = &rest[i + part.len()..];
+    }
+    return rest.ends_with(last);
+}
+
+#[derive(Clone, Default, Debug)]
+struct GrantNode {
+    action: Option<AccessAction>,
+    strings: HashMap<String, GrantNode>,
+    indexes: HashMap<usize, GrantNode>,
+    globs: Vec<(String, GrantNode)>,
+    wildcard: Option<Box<GrantNode>>,
+    recursive: Option<Box<GrantNode>>,
+    /// Some rule under this node (not including this node) denies or doesn't allow
+    /// reading.
+    restricted_below: bool,
+    /// Some rule under this node (not including this node) denies or doesn't allow
+    /// writing.
+    unwritable_below: bool,
+}
+
+impl GrantNode {
+    fn child(&mut self, seg: AccessPathSeg) -> &mut GrantNode {
+        match seg {
+            AccessPathSeg::String(s) => return self.strings.entry(s).or_default(),
+            AccessPathSeg::Index(i) => return self.indexes.entry(i).or_default(),
+            AccessPathSeg::Glob(g) => {
+                let i = match self.globs.iter().position(|(have, _)| *have == g) {
+                    Some(i) => i,
+                    None => {
+                        self.globs.push((g, Default::default()));
+                        self.globs.len() - 1
+                    },
+                };
+                return &mut self.globs[i].1;
+            },
+            AccessPathSeg::Wildcard => return self.wildcard.get_or_insert_with(Default::default),
+            AccessPathSeg::RecursiveWildcard => return self.recursive.get_or_insert_with(Default::default),
+            AccessPathSeg::Variable(_) => unreachable!(),
+        }
+    }
+
+    /// Call `f` with each child matching `seg`, and the rank of the child's segment.
+    fn each_child<'a>(&'a self, seg: &str, mut f: impl FnMut(&'a GrantNode, u8)) {
+        if let Some(c) = self.strings.get(seg) {
+            f(c, RANK_EXACT);
+        }
+        if let Some(c) = parse_array_index(seg).and_then(|i| self.indexes.get(&i)) {
+            f(c, RANK_EXACT);
+        }
+        for (pattern, c) in &self.globs {
+            if glob_matches(pattern, seg) {
+                f(c, RANK_GLOB);
+            }
+        }
+        if let Some(c) = &self.wildcard {
+            f(c, RANK_WILDCARD);
+        }
+    }
+
+    /// Set `restricted_below` and `unwritable_below`, returning whether this node or
+    /// any under it restricts reading and writing respectively.
+    fn update_restricted(&mut self) -> (bool, bool) {
+        let mut restricted = false;
+        let mut unwritable = false;
+        for c in Iterator::chain(
+            Iterator::chain(self.strings.values_mut(), self.indexes.values_mut()),
+            self.globs.iter_mut().map(|(_, c)| c),
+        ).chain(self.wildcard.as_deref_mut()).chain(self.recursive.as_deref_mut()) {
+            let (child_restricted, child_unwritable) = c.update_restricted();
+            restricted = restricted || child_restricted;
+            unwritable = unwritable || child_unwritable;
+        }
+        self.restricted_below = restricted;
+        self.unwritable_below = unwritable;
+        return (
+            restricted || self.action.is_some_and(|a| a.deny || !a.read),
+            unwritable || self.action.is_some_and(|a| a.deny || !a.write),
+        );
+    }
+}
+
+/// A matched rule: the number of data path segments it covers (not counting ones
+/// matched by a trailing recursive wildcard), the ranks of the segments that
+/// matched them, and its actions.
+struct Match {
+    len: usize,
+    ranks: Vec<u8>,
+    action: AccessAction,
+}
+
+/// A token's access rules, indexed for matching against data paths.
+///
+/// The rule that applies to a path is the one covering the most segments of the
+/// path, where a trailing recursive wildcard doesn't count as covering the segments
+/// it matches (so `user/**` is no more specific than `user`). Between rules
+/// covering the same segments, the one with more specific segments earlier wins:
+/// exact strings and indexes, then globs, then wildcards, then recursive wildcards.
+#[derive(Clone, Default, Debug)]
+pub struct Grants {
+    root: GrantNode,
+}
+
+impl Grants {
+    pub fn new(user: UserDetails) -> Grants {
+        let mut root = GrantNode::default();
+        'next_rule: for pair in user.access {
+            let mut at = &mut root;
+            for seg in pair.path {
+                let seg = match seg {
+                    AccessPathSeg::Variable(name) => match user.metadata.get(&name) {
+                        Some(v) => AccessPathSeg::String(v.clone()),
+                        None => {
+                            continue 'next_rule;
+                        },
+                    },
+                    seg => seg,
+                };
+                at = at.child(seg);
+            }
+            at.action = Some(pair.action);
+        }
+        root.update_restricted();
+        return Grants { root: root };
+    }
+
+    /// Visit every node matching a prefix of `path`. `f` gets the node, the number of
+    /// segments consumed, and the ranks of the segments that consumed them.
+    fn walk<'a>(&'a self, path: &[String], f: &mut dyn FnMut(&'a GrantNode, usize, &[u8])) {
+        fn visit<'a>(
+            node: &'a GrantNode,
+            path: &[String],
+            at: usize,
+            ranks: &mut Vec<u8>,
+            f: &mut dyn FnMut(&'a GrantNode, usize, &[u8]),
+        ) {
+            f(node, at, ranks);
+            if let Some(recursive) = &node.recursive {
+                // Consumes zero or more segments
+                let start = ranks.len();
+                for end in at ..= path.len() {
+                    visit(recursive, path, end, ranks, f);
+                    if end < path.len() {
+                        ranks.push(RANK_RECURSIVE);
+                    }
+                }
+                ranks.truncate(start);
+            }
+            let Some(seg) = path.get(at) else {
+                return;
+            };
+            node.each_child(seg, |child, rank| {
+                ranks.push(rank);
+                visit(child, path, at + 1, ranks, f);
+                ranks.pop();
+            });
+        }
+
+        visit(&self.root, path, 0, &mut vec![], f);
+    }
+
+    /// Find the actions granted at `path` from the most specific matching rule, or
+    /// nothing if no rule matches or a matching rule denies access.
+    pub fn find(&self, path: &DataPath) -> Option<AccessAction> {
+        let mut best: Option<Match> = None;
+        let mut denied = false;
+        self.walk(path, &mut |node, len, ranks| {
+            let Some(action) = node.action else {
+                return;
+            };
+            if action.deny {
+                denied = true;
+                return;
+            }
+            let len = len - ranks.iter().rev().take_while(|r| **r == RANK_RECURSIVE).count();
+            let ranks = &ranks[..len];
+            if best.as_ref().is_some_and(|b| (b.len, b.ranks.as_slice()) >= (len, ranks)) {
+                return;
+            }
+            best = Some(Match {
+                len: len,
+                ranks: ranks.to_vec(),
+                action: action,
+            });
+        });
+        if denied {
+            return None;
+        }
+        return best.map(|b| b.action);
+    }
+
+    /// Whether some rule may prevent reading data under `path` (but not `path`
+    /// itself). This may be true when all data under `path` can actually be read, but
+    /// is never false when some can't.
+    pub fn has_unreadable_descendants(&self, path: &DataPath) -> bool {
+        return self.has_restricted_descendants(path, |n| n.restricted_below, |a| a.deny || !a.read);
+    }
+
+    /// Whether some rule may prevent writing data under `path` (but not `path`
+    /// itself), so replacing or deleting `path` would change data the token can't
+    /// write. Like `has_unreadable_descendants` this errs towards true.
+    pub fn has_unwritable_descendants(&self, path: &DataPath) -> bool {
+        return self.has_restricted_descendants(path, |n| n.unwritable_below, |a| a.deny || !a.write);
+    }
+
+    fn has_restricted_descendants(
+        &self,
+        path: &DataPath,
+        below: fn(&GrantNode) -> bool,
+        restricts: fn(AccessAction) -> bool,
+    ) -> bool {
+        let mut restricted = false;
+        self.walk(path, &mut |node, len, _| {
+            if len != path.len() {
+                return;
+            }
+            if below(node) {
+                restricted = true;
+            }
+        });
+        if !restricted {
+            // A recursive wildcard reached while consuming the path may still match
+            // descendants at its own level
+            self.walk(path, &mut |node, _, _| {
+                if let Some(recursive) = &node.recursive {
+                    if recursive.action.is_some_and(restricts) {
+                        restricted = true;
+                    }
+                }
+            });
+        }
+        return restricted;
+    }
+}
+
+/// A token's access, from the config or `fdap_user`.
+#[derive(Clone, Debug)]
+pub struct User {
+    pub grants: Grants,
+    pub not_before: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl User {
+    pub fn new(details: UserDetails) -> User {
+        return User {
+            not_before: details.not_before,
+            expires_at: details.expires_at,
+            grants: Grants::new(details),
+        };
+    }
+
+    /// Why the token can't be used at `now`, if it can't.
+    pub fn invalid_at(&self, now: DateTime<Utc>) -> Option<&'static str> {
+        if self.not_before.is_some_and(|t| now < t) {
+            return Some("Token is not valid yet");
+        }
+        if self.expires_at.is_some_and(|t| now >= t) {
+            return Some("Token has expired");
+        }
+        return None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            glob_matches,
+            Grants,
+        },
+        crate::DataPath,
+        serde_json::json,
+    };
+
+    fn grants(user: serde_json::Value) -> Grants {
+        return Grants::new(serde_json::from_value(user).unwrap());
+    }
+
+    fn rules(rules: serde_json::Value) -> Grants {
+        return grants(json!({
+            "access": rules
+        }));
+    }
+
+    fn p(path: &str) -> DataPath {
+        if path.is_empty() {
+            return vec![];
+        }
+        return path.split('/').map(|s| s.to_string()).collect();
+    }
+
+    /// The granted (read, write), or `None` if nothing is granted or it's denied.
+    fn rw(g: &Grants, path: &str) -> Option<(bool, bool)> {
+        return g.find(&p(path)).map(|a| (a.read, a.write));
+    }
+
+    const RO: (bool, bool) = (true, false);
+    const RW: (bool, bool) = (true, true);
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("app_*", "app_x"));
+        assert!(glob_matches("app_*", "app_"));
+        assert!(!glob_matches("app_*", "ap"));
+        assert!(!glob_matches("app_*", "xapp_x"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("*_cfg", "x_cfg"));
+        assert!(!glob_matches("*_cfg", "x_cfgx"));
+        assert!(glob_matches("a*b*c", "abc"));
+        assert!(glob_matches("a*b*c", "aXbYc"));
+        assert!(!glob_matches("a*b*c", "acb"));
+        assert!(glob_matches("abc", "abc"));
+        assert!(!glob_matches("abc", "abcd"));
+    }
+
+    #[test]
+    fn test_no_match() {
+        let g = rules(json!([{ "path": [{ "string": "a" }], "action": { "read": true, "write": true } }]));
+        assert_eq!(rw(&g, "b"), None);
+        assert_eq!(rw(&g, ""), None);
+        assert_eq!(rw(&g, "a"), Some(RW));
+        assert_eq!(rw(&g, "a/b/c"), Some(RW));
+    }
+
+    #[test]
+    fn test_exact_beats_wildcard() {
+        let g = rules(json!([
+            { "path": [{ "string": "user" }, "wildcard"], "action": { "read": true, "write": false } },
+            { "path": [{ "string": "user" }, { "string": "stephanie" }], "action": { "read": true, "write": true } },
+        ]));
+        assert_eq!(rw(&g, "user/stephanie"), Some(RW));
+        assert_eq!(rw(&g, "user/stephanie/email"), Some(RW));
+        assert_eq!(rw(&g, "user/bob"), Some(RO));
+        assert_eq!(rw(&g, "user"), None);
+    }
+
+    #[test]
+    fn test_glob_beats_wildcard() {
+        let g = rules(json!([
+            { "path": ["wildcard"], "action": { "read": true, "write": false } },
+            { "path": [{ "glob": "app_*" }], "action": { "read": true, "write": true } },
+        ]));
+        assert_eq!(rw(&g, "app_x"), Some(RW));
+        assert_eq!(rw(&g, "other"), Some(RO));
+    }
+
+    #[test]
+    fn test_index() {
+        let g = rules(json!([
+            { "path": [{ "string": "list" }, "wildcard"], "action": { "read": true, "write": false } },
+            { "path": [{ "string": "list" }, { "index": 0 }], "action": { "read": true, "write": true } },
+        ]));
+        assert_eq!(rw(&g, "list/0"), Some(RW));
+        assert_eq!(rw(&g, "list/1"), Some(RO));
+
+        // Not the same index
+        assert_eq!(rw(&g, "list/00"), Some(RO));
+    }
+
+    #[test]
+    fn test_longer_beats_shorter() {
+        let g = rules(json!([
+            { "path": [{ "string": "user" }], "action": { "read": true, "write": true } },
+            {
+                "path": [{ "string": "user" }, "wildcard", { "string": "password_hash" }],
+                "action": { "read": false, "write": false },
+            },
+        ]));
+        assert_eq!(rw(&g, "user/bob"), Some(RW));
+        assert_eq!(rw(&g, "user/bob/password_hash"), Some((false, false)));
+        assert_eq!(rw(&g, "user/bob/password_hash/x"), Some((false, false)));
+    }
+
+    #[test]
+    fn test_trailing_recursive_doesnt_beat_prefix() {
+        let g = rules(json!([
+            { "path": [{ "string": "user" }, "recursive_wildcard"], "action": { "read": true, "write": false } },
+            { "path": [{ "string": "user" }, { "string": "stephanie" }], "action": { "read": true, "write": true } },
+        ]));
+        assert_eq!(rw(&g, "user/stephanie"), Some(RW));
+        assert_eq!(rw(&g, "user/stephanie/email"), Some(RW));
+        assert_eq!(rw(&g, "user/bob/email"), Some(RO));
+        assert_eq!(rw(&g, "user"), Some(RO));
+    }
+
+    #[test]
+    fn test_trailing_recursive_vs_wildcard() {
+        let g = rules(json!([
+            { "path": [{ "string": "user" }, "wildcard"], "action": { "read": true, "write": false } },
+            { "path": [{ "string": "user" }, "recursive_wildcard"], "action": { "read": true, "write": true } },
+        ]));
+        assert_eq!(rw(&g, "user/bob"), Some(RO));
+        assert_eq!(rw(&g, "user/bob/email"), Some(RO));
+        assert_eq!(rw(&g, "user"), Some(RW));
+    }
+
+    #[test]
+    fn test_recursive_matches_nothing() {
+        let g = rules(json!([{ "path": ["recursive_wildcard"], "action": { "read": true, "write": false } }]));
+        assert_eq!(rw(&g, ""), Some(RO));
+        assert_eq!(rw(&g, "a/b/c"), Some(RO));
+    }
+
+    #[test]
+    fn test_inner_recursive() {
+        let g = rules(json!([
+            { "path": ["recursive_wildcard"], "action": { "read": true, "write": true } },
+            { "path": ["recursive_wildcard", { "string": "secret" }], "action": { "read": false, "write": false } },
+        ]));
+        assert_eq!(rw(&g, "a/b"), Some(RW));
+        assert_eq!(rw(&g, "secret"), Some((false, false)));
+        assert_eq!(rw(&g, "a/b/secret"), Some((false, false)));
+        assert_eq!(rw(&g, "a/b/secret/c"), Some((false, false)));
+
+        // Covers more segments than an exact prefix rule
+        let g = rules(json!([
+            { "path": [{ "string": "user" }, { "string": "bob" }], "action": { "read": true, "write": true } },
+            {
+                "path": ["recursive_wildcard", { "string": "password_hash" }],
+                "action": { "read": false, "write": false },
+            },
+        ]));
+        assert_eq!(rw(&g, "user/bob/password_hash"), Some((false, false)));
+        assert_eq!(rw(&g, "user/bob/email"), Some(RW));
+    }
+
+    #[test]
+    fn test_earlier_specificity_wins() {
+        let g = rules(json!([
+            { "path": [{ "string": "a" }, "wildcard"], "action": { "read": true, "write": true } },
+            { "path": ["wildcard", { "string": "b" }], "action": { "read": true, "write": false } },
+        ]));
+        assert_eq!(rw(&g, "a/b"), Some(RW));
+        assert_eq!(rw(&g, "x/b"), Some(RO));
+    }
+
+    #[test]
+    fn test_deny() {
+        let g = rules(json!([
+            { "path": ["recursive_wildcard"], "action": { "read": true, "write": true } },
+            { "path": [{ "string": "user" }], "action": { "read": false, "write": false, "deny": true } },
+            { "path": [{ "string": "user" }, { "string": "stephanie" }], "action": { "read": true, "write": true } },
+        ]));
+        assert_eq!(rw(&g, "other"), Some(RW));
+        assert_eq!(rw(&g, "user"), None);
+        assert_eq!(rw(&g, "user/stephanie"), None);
+        assert_eq!(rw(&g, ""), Some(RW));
+    }
+
+    #[test]
+    fn test_variable() {
+        let g = grants(json!({
+            "access": [
+                { "path": [{ "string": "user" }, { "variable": "subject" }], "action": { "read": true, "write": true } },
+                { "path": [{ "string": "group" }, { "variable": "group" }], "action": { "read": true, "write": true } },
+            ],
+            "metadata": { "subject": "stephanie" },
+        }));
+        assert_eq!(rw(&g, "user/stephanie"), Some(RW));
+        assert_eq!(rw(&g, "user/bob"), None);
+
+        // No `group` metadata, rule ignored
+        assert_eq!(rw(&g, "group/x"), None);
+    }
+
+    #[test]
+    fn test_descendants() {
+        let g = rules(json!([
+            { "path": [{ "string": "user" }, "wildcard"], "action": { "read": true, "write": true } },
+            {
+                "path": [{ "string": "user" }, "wildcard", { "string": "password_hash" }],
+                "action": { "read": true, "write": false },
+            },
+            {
+                "path": [{ "string": "user" }, "wildcard", { "string": "private" }],
+                "action": { "read": false, "write": false, "deny": true },
+            },
+        ]));
+        assert!(g.has_unwritable_descendants(&p("user/bob")));
+        assert!(g.has_unwritable_descendants(&p("user")));
+        assert!(!g.has_unwritable_descendants(&p("user/bob/email")));
+        assert!(g.has_unreadable_descendants(&p("user/bob")));
+        assert!(!g.has_unreadable_descendants(&p("user/bob/password_hash")));
+
+        // Recursive wildcards apply below the path at any depth
+        let g = rules(json!([
+            { "path": ["recursive_wildcard"], "action": { "read": true, "write": true } },
+            { "path": ["recursive_wildcard", { "string": "secret" }], "action": { "read": false, "write": false } },
+        ]));
+        assert!(g.has_unwritable_descendants(&p("a/b")));
+        assert!(g.has_unreadable_descendants(&p("a/b")));
+        let g = rules(json!([{ "path": ["recursive_wildcard"], "action": { "read": true, "write": true } }]));
+        assert!(!g.has_unwritable_descendants(&p("a/b")));
+        assert!(!g.has_unreadable_descendants(&p("a/b")));
+    }
+}
@@ -0,0 +1,414 @@
+use {
+    crate::{
+        dball::DbVersion,
+        dbv1,
+        delete_at,
+        parse_array_index,
+        pointer_get,
+        storage::{
+            NodeKind,
+            StorageRead,
+        },
+        walk_create,
+        DataPath,
+    },
+    chrono::{
+        DateTime,
+        Utc,
+    },
+    openfdap::interface::config::HistoryConfig,
+    std::collections::VecDeque,
+};
+
+pub struct HistoryEntry {
+    pub version: DbVersion,
+    pub timestamp: DateTime<Utc>,
+    /// `None` if the version changed nothing.
+    pub undo: Option<dbv1::Undo>,
+}
+
+/// Take the history entry for a logged record, if history was enabled when it was
+/// logged. `Noop`s have no undo but still get an entry, to keep versions
+/// contiguous.
+pub fn take_history_entry(record: &mut dbv1::WalRecord) -> Option<HistoryEntry> {
+    if record.undo.is_none() && !matches!(record.mutation, dbv1::Mutation::Noop) {
+        return None;
+    }
+    return Some(HistoryEntry {
+        version: record.version,
+        timestamp: record.timestamp,
+        undo: record.undo.take(),
+    });
+}
+
+/// Undo information for recent versions, oldest first. Versions are contiguous and
+/// end at the current version.
+pub struct History {
+    config: Option<HistoryConfig>,
+    entries: VecDeque<HistoryEntry>,
+}
+
+impl History {
+    pub fn new(config: Option<HistoryConfig>) -> Self {
+        return History {
+            config: config,
+            entries: Default::default(),
+        };
+    }
+
+    pub fn enabled(&self) -> bool {
+        return self.config.is_some();
+    }
+
+    /// Add the entry for a new version, dropping entries outside the retention
+    /// limits.
+    pub fn push(&mut self, entry: HistoryEntry) {
+        let Some(config) = &self.config else {
+            return;
+        };
+        if self.entries.back().is_some_and(|e| e.version >= entry.version) {
+            // Replaces a logged mutation that failed to apply
+            while self.entries.back().is_some_and(|e| e.version >= entry.version) {
+                self.entries.pop_back();
+            }
+        }
+        if self.entries.back().is_some_and(|e| e.version + 1 != entry.version) {
+            // Not contiguous (history was disabled for a while), older entries are unusable
+            self.entries.clear();
+        }
+        self.entries.push_back(entry);
+        if let Some(max_versions) = config.max_versions {
+            while self.entries.len() > max_versions {
+                self.entries.pop_front();
+            }
+        }
+        if let Some(max_age_secs) = config.max_age_secs {
+            let cutoff = Utc::now() - chrono::Duration::seconds(max_age_secs as i64);
+            while self.entries.front().is_some_and(|e| e.timestamp < cutoff) {
+                self.entries.pop_front();
+            }
+        }
+    }
+
+    /// Drop all entries, ex: when the database is replaced entirely.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Drop all entries if they don't end at `version`, ex: if the logs they came
+    /// from were replaced by a snapshot.
+    pub fn end_at(&mut self, version: DbVersion) {
+        if self.entries.back().is_some_and(|e| e.version != version) {
+            self.entries.clear();
+        }
+    }
+
+    /// The oldest version whose mutation is still retained. Logged mutations from
+    /// this version on must be kept.
+    pub fn oldest_retained(&self, current: DbVersion) -> DbVersion {
+        return self.entries.front().map(|e| e.version).unwrap_or(current + 1);
+    }
+
+    /// The version that was current at `time`.
+    pub fn version_at(&self, time: DateTime<Utc>) -> Result<DbVersion, String> {
+        for entry in self.entries.iter().rev() {
+            if entry.timestamp <= time {
+                return Ok(entry.version);
+            }
+        }
+        return Err(format!("No retained version is old enough for time {}", time.to_rfc3339()));
+    }
+
+    /// Reconstruct the data at `path` as of `version`, given the current data there.
+    pub fn get_at(
+        &self,
+        mut current: Option<serde_json::Value>,
+        current_version: DbVersion,
+        path: &DataPath,
+        version: DbVersion,
+    ) -> Result<Option<serde_json::Value>, String> {
+        if version > current_version {
+            return Err(format!("Version {} doesn't exist yet, current version is {}", version, current_version));
+        }
+        if version < self.oldest_retained(current_version) - 1 {
+            return Err(format!("Version {} is no longer retained", version));
+        }
+        for entry in self.entries.iter().rev() {
+            if entry.version <= version {
+                break;
+            }
+            let Some(undo) = &entry.undo else {
+                continue;
+            };
+            if path.starts_with(&undo.path) {
+                // Restores an ancestor (or the path itself)
+                current = undo.old.as_ref().and_then(|old| pointer_get(old, &path[undo.path.len()..])).cloned();
+            } else if undo.path.starts_with(path) {
+                // Restores a descendant
+                let mut rel_path = undo.path[path.len()..].to_vec();
+                match &undo.old {
+                    Some(old) => {
+                        if let Ok(at) = walk_create(current.get_or_insert(serde_json::Value::Null), &mut rel_path) {
+                            *at = old.clone();
+                        }
+                    },
+                    None => {
+                        if let Some(current) = &mut current {
+                            _ = delete_at(current, &rel_path);
+                        }
+                    },
+                }
+            }
+        }
+        return Ok(current);
+    }
+}
+
+/// Determine how to undo `mutation`: the shallowest path it changes and the data
+/// there before the change. `None` if it changes nothing.
+pub fn mutation_undo<
+    R: StorageRead + ?Sized,
+>(db: &R, mutation: &dbv1::Mutation) -> Result<Option<dbv1::Undo>, loga::Error> {
+    match mutation {
+        dbv1::Mutation::Set { path, .. } | dbv1::Mutation::MergePatch { path, .. } => {
+            let mut undo_path = vec![];
+            for seg in path {
+                match db.kind(&undo_path)? {
+                    Some(NodeKind::Object) => { },
+                    Some(NodeKind::Array(len)) => {
+                        if seg == "-" {
+                            undo_path.push(len.to_string());
+                            return Ok(Some(dbv1::Undo {
+                                path: undo_path,
+                                old: None,
+                            }));
+                        }
+                        if !parse_array_index(seg).is_some_and(|i| i < len) {
+                            // Not writable, so the mutation will fail
+                            return Ok(Some(dbv1::Undo {
+                                old: db.get(&undo_path)?,
+                                path: undo_path,
+                            }));
+                        }
+                    },
+                    _ => {
+                        // Null gets replaced by an object, anything else will fail
+                        return Ok(Some(dbv1::Undo {
+                            old: db.get(&undo_path)?,
+                            path: undo_path,
+                        }));
+                    },
+                }
+                undo_path.push(seg.clone());
+                if db.kind(&undo_path)?.is_none() {
+                    // Created by the mutation
+                    return Ok(Some(dbv1::Undo {
+                        path: undo_path,
+                        old: None,
+                    }));
+                }
+            }
+            return Ok(Some(dbv1::Undo {
+                old: db.get(&undo_path)?,
+                path: undo_path,
+            }));
+        },
+        dbv1::Mutation::Delete { path } => {
+            if let Some((_, parent_path)) = path.split_last() {
+                if let Some(NodeKind::Array(_)) = db.kind(parent_path)? {
+                    // Later elements shift, so restore the whole array
+                    return Ok(Some(dbv1::Undo {
+                        path: parent_path.to_vec(),
+                        old: db.get(parent_path)?,
+                    }));
+                }
+            }
+            return Ok(Some(dbv1::Undo {
+                path: path.clone(),
+                old: db.get(path)?,
+            }));
+        },
+        dbv1::Mutation::Noop => {
+            return Ok(None);
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            mutation_undo,
+            History,
+            HistoryEntry,
+        },
+        crate::{
+            apply_mutation,
+            dball::DbVersion,
+            dbv1,
+            latest,
+            storage::{
+                MemoryTransaction,
+                Transaction,
+            },
+        },
+        chrono::{
+            DateTime,
+            Duration,
+            Utc,
+        },
+        openfdap::interface::config::HistoryConfig,
+        serde_json::json,
+    };
+
+    struct TestDb {
+        db: latest::Database,
+        history: History,
+    }
+
+    impl TestDb {
+        fn new(max_versions: Option<usize>, max_age_secs: Option<u64>) -> TestDb {
+            return TestDb {
+                db: latest::Database {
+                    version: 0,
+                    data: serde_json::Value::Null,
+                },
+                history: History::new(Some(HistoryConfig {
+                    max_versions: max_versions,
+                    max_age_secs: max_age_secs,
+                })),
+            };
+        }
+
+        fn write(&mut self, mutation: dbv1::Mutation) {
+            let undo = mutation_undo(&self.db.data, &mutation).unwrap();
+            let version = self.db.version + 1;
+            let mut tx = Box::new(MemoryTransaction::new(&mut self.db));
+            apply_mutation(&mut *tx, mutation).unwrap().unwrap();
+            tx.commit(version).unwrap();
+            self.history.push(HistoryEntry {
+                version: version,
+                timestamp: Utc::now(),
+                undo: undo,
+            });
+        }
+
+        fn set(&mut self, path: &[&str], data: serde_json::Value) {
+            self.write(dbv1::Mutation::Set {
+                path: p(path),
+                data: data,
+            });
+        }
+
+        fn get_at(&self, path: &[&str], version: DbVersion) -> Result<Option<serde_json::Value>, String> {
+            let path = p(path);
+            return self
+                .history
+                .get_at(crate::pointer_get(&self.db.data, &path).cloned(), self.db.version, &path, version);
+        }
+    }
+
+    fn p(path: &[&str]) -> Vec<String> {
+        return path.iter().map(|s| s.to_string()).collect();
+    }
+
+    fn entry(version: DbVersion, timestamp: DateTime<Utc>) -> HistoryEntry {
+        return HistoryEntry {
+            version: version,
+            timestamp: timestamp,
+            undo: None,
+        };
+    }
+
+    #[test]
+    fn test_get_at() {
+        let mut db = TestDb::new(None, None);
+        db.set(&["a"], json!({
+            "x": 1
+        }));
+        db.set(&["a", "x"], json!(2));
+        db.write(dbv1::Mutation::Delete { path: p(&["a"]) });
+        db.set(&["b"], json!([1]));
+        db.set(&["b", "-"], json!(2));
+
+        // Ancestors, the path itself, and descendants are restored
+        assert_eq!(db.get_at(&[], 0), Ok(Some(json!(null))));
+        assert_eq!(db.get_at(&["a"], 0), Ok(None));
+        assert_eq!(db.get_at(&["a"], 1), Ok(Some(json!({
+            "x": 1
+        }))));
+        assert_eq!(db.get_at(&["a", "x"], 2), Ok(Some(json!(2))));
+        assert_eq!(db.get_at(&[], 2), Ok(Some(json!({
+            "a": {
+                "x": 2
+            }
+        }))));
+        assert_eq!(db.get_at(&["a"], 3), Ok(None));
+        assert_eq!(db.get_at(&["b"], 4), Ok(Some(json!([1]))));
+        assert_eq!(db.get_at(&["b"], 5), Ok(Some(json!([1, 2]))));
+        assert!(db.get_at(&["b"], 6).is_err());
+    }
+
+    #[test]
+    fn test_version_at() {
+        let mut history = History::new(Some(HistoryConfig {
+            max_versions: None,
+            max_age_secs: None,
+        }));
+        let start = Utc::now() - Duration::seconds(100);
+        for i in 0 .. 3 {
+            history.push(entry(i + 1, start + Duration::seconds(i as i64 * 10)));
+        }
+        assert!(history.version_at(start - Duration::seconds(1)).is_err());
+        assert_eq!(history.version_at(start), Ok(1));
+        assert_eq!(history.version_at(start + Duration::seconds(15)), Ok(2));
+        assert_eq!(history.version_at(start + Duration::seconds(20)), Ok(3));
+        assert_eq!(history.version_at(Utc::now()), Ok(3));
+    }
+
+    #[test]
+    fn test_prune_max_versions() {
+        let mut db = TestDb::new(Some(2), None);
+        for i in 1 ..= 4 {
+            db.set(&["a"], json!(i));
+        }
+        assert_eq!(db.history.oldest_retained(4), 3);
+
+        // The version before the oldest retained mutation can still be reconstructed
+        assert_eq!(db.get_at(&["a"], 2), Ok(Some(json!(2))));
+        assert!(db.get_at(&["a"], 1).is_err());
+    }
+
+    #[test]
+    fn test_prune_max_age() {
+        let mut history = History::new(Some(HistoryConfig {
+            max_versions: None,
+            max_age_secs: Some(60),
+        }));
+        history.push(entry(1, Utc::now() - Duration::seconds(120)));
+        history.push(entry(2, Utc::now() - Duration::seconds(30)));
+        history.push(entry(3, Utc::now()));
+        assert_eq!(history.oldest_retained(3), 2);
+        assert!(history.version_at(Utc::now() - Duration::seconds(90)).is_err());
+    }
+
+    #[test]
+    fn test_push_replaces_failed_version() {
+        let mut history = History::new(Some(HistoryConfig {
+            max_versions: None,
+            max_age_secs: None,
+        }));
+        let now = Utc::now();
+        history.push(entry(1, now));
+        history.push(entry(2, now));
+
+        // Logged again after failing to apply
+        history.push(entry(2, now));
+        history.push(entry(3, now));
+        assert_eq!(history.oldest_retained(3), 1);
+
+        // A gap makes older entries unusable
+        history.push(entry(5, now));
+        assert_eq!(history.oldest_retained(5), 5);
+    }
+}
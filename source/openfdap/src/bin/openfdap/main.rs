@@ -0,0 +1,2819 @@
+use {
+    crate::{
+        audit::{
+            Audit,
+            AuditDiff,
+            AuditEntry,
+        },
+        cluster::{
+            Cluster,
+            ClusterLeader,
+            EntryWriter,
+            RAFT_APPEND,
+            RAFT_ROOT,
+            RAFT_SNAPSHOT,
+            RAFT_VOTE,
+        },
+        dball::DbVersion,
+        grants::{
+            Grants,
+            User,
+        },
+        history::{
+            mutation_undo,
+            take_history_entry,
+            History,
+        },
+        replicate::{
+            forward_write,
+            Follow,
+            REPLICATE_PROMOTE,
+            REPLICATE_ROOT,
+        },
+        schema::{
+            find_overlapping,
+            format_pointer,
+            pattern_prefix,
+            Change,
+            ChangeKind,
+            Schema,
+            SchemaViolation,
+        },
+        storage::{
+            read_json_database,
+            JsonStorage,
+            NodeKind,
+            SqliteStorage,
+            Storage,
+            StorageRead,
+            Transaction,
+        },
+        wal::Wal,
+    },
+    aargvark::{
+        traits_impls::AargvarkJson,
+        Aargvark,
+    },
+    flowcontrol::{
+        shed,
+        ta_return,
+    },
+    http::{
+        header::{
+            HeaderValue,
+            ALLOW,
+            CONTENT_TYPE,
+            ETAG,
+            IF_MATCH,
+            IF_NONE_MATCH,
+        },
+        Response,
+    },
+    http_body_util::BodyExt,
+    htwrap::htserve::{
+        self,
+        auth::get_auth_token,
+        handler::async_trait::async_trait,
+        responses::{
+            body_full,
+            body_json,
+            response_200_json,
+            Body,
+        },
+    },
+    hyper::Method,
+    loga::{
+        ea,
+        DebugDisplay,
+        ErrContext,
+        Log,
+        ResultContext,
+    },
+    openfdap::interface::config::{
+        AccessAction,
+        AccessPath,
+        AccessPathSeg,
+        UserEntry,
+        Config,
+        SchemaEntry,
+        StorageBackend,
+        TlsConfig,
+    },
+    rustls::{
+        server::{
+            ClientHello,
+            ResolvesServerCert,
+        },
+        sign::CertifiedKey,
+        ServerConfig,
+    },
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    sha2::{
+        Digest,
+        Sha256,
+    },
+    std::{
+        borrow::Cow,
+        collections::{
+            BTreeMap,
+            HashMap,
+        },
+        io::Write,
+        ops::Bound,
+        path::Path,
+        sync::{
+            Arc,
+            Mutex,
+            RwLock,
+        },
+        time::{
+            Duration,
+            SystemTime,
+        },
+    },
+    taskmanager::TaskManager,
+    tempfile::NamedTempFile,
+    tokio::{
+        fs::create_dir_all,
+        net::TcpListener,
+        select,
+        signal::unix::{
+            signal,
+            SignalKind,
+        },
+        sync::watch,
+        time::{
+            interval,
+            sleep,
+            timeout_at,
+            Instant,
+        },
+    },
+    chrono::Utc,
+    tokio_rustls::TlsAcceptor,
+    tokio_stream::wrappers::TcpListenerStream,
+};
+
+#[derive(Aargvark)]
+struct Args {
+    /// Configuration JSON file
+    config: Option<AargvarkJson<Config>>,
+    /// Check the config then exit
+    validate: Option<()>,
+    /// Generate a new random token, print it and its hashed form (for use as a key in
+    /// `users` or `fdap_user`), then exit
+    generate_token: Option<()>,
+    /// Enable debug logging
+    debug: Option<()>,
+}
+
+mod audit;
+mod cluster;
+mod grants;
+mod history;
+mod replicate;
+mod schema;
+mod storage;
+#[cfg(test)]
+mod test_util;
+mod wal;
+
+pub mod dball {
+    pub type DbVersion = usize;
+}
+
+pub mod dbv1 {
+    use {
+        chrono::{
+            DateTime,
+            Utc,
+        },
+        serde::{
+            Deserialize,
+            Serialize,
+        },
+        crate::dball::DbVersion,
+    };
+
+    #[derive(Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case", deny_unknown_fields)]
+    pub struct Database {
+        pub version: DbVersion,
+        pub data: serde_json::Value,
+    }
+
+    /// A change to the data at a path. `-` path segments append to arrays.
+    #[derive(Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case", deny_unknown_fields)]
+    pub enum Mutation {
+        Set {
+            path: Vec<String>,
+            data: serde_json::Value,
+        },
+        MergePatch {
+            path: Vec<String>,
+            patch: serde_json::Value,
+        },
+        Delete {
+            path: Vec<String>,
+        },
+        /// Changes nothing, marks the start of a cluster leader's term.
+        Noop,
+    }
+
+    /// How to revert a mutation: replace the data at `path` with `old`, or delete it
+    /// if `old` is `None`. `path` has no `-` segments.
+    #[derive(Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case", deny_unknown_fields)]
+    pub struct Undo {
+        pub path: Vec<String>,
+        pub old: Option<serde_json::Value>,
+    }
+
+    /// A line in the write-ahead log. `version` is the database version after the
+    /// mutation is applied.
+    #[derive(Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case", deny_unknown_fields)]
+    pub struct WalRecord {
+        pub version: DbVersion,
+        pub timestamp: DateTime<Utc>,
+        pub mutation: Mutation,
+        /// Only recorded if history is enabled, never for `Noop`.
+        pub undo: Option<Undo>,
+    }
+}
+
+fn format_etag(ver: DbVersion) -> String {
+    return format!("\"{}\"", ver);
+}
+
+fn response_304() -> Response<Body> {
+    return Response::builder().status(304).body(body_full(vec![])).unwrap();
+}
+
+/// Machine-readable reason for an error response, determines the status code.
+#[derive(Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+enum ErrorCode {
+    BadRequest,
+    /// Missing or unknown token.
+    Unauthorized,
+    /// The token isn't granted the action at the path.
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    /// The change would make the database not match the schema.
+    SchemaViolation,
+    /// This server is a follower and doesn't accept writes.
+    ReadOnly,
+    /// The cluster couldn't commit the change.
+    Unavailable,
+    /// A JSON patch `test` operation failed.
+    Conflict,
+    /// The requested version is no longer retained.
+    Gone,
+    PreconditionFailed,
+    UnsupportedMediaType,
+    Internal,
+}
+
+impl ErrorCode {
+    fn status(self) -> u16 {
+        match self {
+            ErrorCode::BadRequest => return 400,
+            ErrorCode::Unauthorized => return 401,
+            ErrorCode::Forbidden => return 403,
+            ErrorCode::NotFound => return 404,
+            ErrorCode::MethodNotAllowed => return 405,
+            ErrorCode::Conflict => return 409,
+            ErrorCode::Gone => return 410,
+            ErrorCode::PreconditionFailed => return 412,
+            ErrorCode::UnsupportedMediaType => return 415,
+            ErrorCode::SchemaViolation => return 422,
+            ErrorCode::Internal => return 500,
+            ErrorCode::ReadOnly => return 503,
+            ErrorCode::Unavailable => return 503,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+struct ErrorBody<'a> {
+    code: ErrorCode,
+    message: String,
+    /// The data path the error relates to, if the request path could be parsed.
+    path: Option<&'a DataPath>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    violations: Vec<SchemaViolation>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+struct BatchRequest {
+    paths: Vec<DataPath>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum BatchResult<'a> {
+    Ok {
+        data: serde_json::Value,
+        etag: String,
+    },
+    Error(ErrorBody<'a>),
+}
+
+fn response_error(code: ErrorCode, message: impl ToString, path: Option<&DataPath>) -> Response<Body> {
+    return Response::builder()
+        .status(code.status())
+        .header(CONTENT_TYPE, "application/json")
+        .body(body_json(ErrorBody {
+            code: code,
+            message: message.to_string(),
+            path: path,
+            violations: vec![],
+        }))
+        .unwrap();
+}
+
+fn response_404(path: &DataPath) -> Response<Body> {
+    return response_error(ErrorCode::NotFound, "No data at path", Some(path));
+}
+
+fn response_forbidden(path: &DataPath, action: &str) -> Response<Body> {
+    return response_error(ErrorCode::Forbidden, format!("Token isn't granted {} access at path", action), Some(path));
+}
+
+fn response_forbidden_below(path: &DataPath) -> Response<Body> {
+    return response_error(
+        ErrorCode::Forbidden,
+        "Token isn't granted write access to all data under path, write a more specific path instead",
+        Some(path),
+    );
+}
+
+fn response_405(path: &DataPath, allow: &str) -> Response<Body> {
+    let mut resp = response_error(ErrorCode::MethodNotAllowed, "Method not supported at path", Some(path));
+    resp.headers_mut().insert(ALLOW, HeaderValue::from_str(allow).unwrap());
+    return resp;
+}
+
+fn response_200_json_etag(v: impl Serialize, etag: String) -> Response<Body> {
+    return Response::builder()
+        .status(200)
+        .header(CONTENT_TYPE, "application/json")
+        .header(ETAG, etag)
+        .body(body_json(v))
+        .unwrap();
+}
+
+pub use dbv1 as latest;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum Database<'a> {
+    V1(Cow<'a, dbv1::Database>),
+}
+
+pub type DataPath = Vec<String>;
+
+struct State {
+    log: Log,
+    database: RwLock<Box<dyn Storage>>,
+    /// Only locked while holding the `database` lock, before `wal`.
+    history: Mutex<History>,
+    /// Only locked while holding the `database` lock.
+    wal: Mutex<Wal>,
+    audit: Mutex<Audit>,
+    schema: Option<Schema>,
+    /// Compiled `fdap_schema` registrations, with the etag version of `fdap_schema`
+    /// they were compiled from. Only locked while holding the `database` lock.
+    registered_schemas: Mutex<Option<(DbVersion, Arc<Vec<RegisteredSchema>>)>>,
+    users: HashMap<String, User>,
+    /// The leader to copy changes from, if this is a follower. Only written while
+    /// holding the `database` lock.
+    following: RwLock<Option<Follow>>,
+    cluster: Option<Cluster>,
+    etags: RwLock<BTreeMap<DataPath, DbVersion>>,
+    /// Sent the new version after every write, to wake up waiting reads.
+    changes: watch::Sender<DbVersion>,
+}
+
+#[async_trait]
+impl htserve::handler::Handler<Body> for State {
+    async fn handle(&self, args: htserve::handler::HandlerArgs<'_>) -> http::Response<Body> {
+        let log = self.log.fork(ea!(path = args.url, peer = args.peer_addr));
+        match async {
+            ta_return!(http:: Response < Body >, loga::Error);
+            let mut path: DataPath = vec![];
+            let subpath = args.subpath.trim_matches('/');
+            if subpath == "" {
+                // nop
+            } else {
+                for seg in subpath.split("/") {
+                    match urlencoding::decode(&seg) {
+                        Ok(seg) => path.push(seg.to_string()),
+                        Err(e) => {
+                            return Ok(
+                                response_error(
+                                    ErrorCode::BadRequest,
+                                    format!("Path segment {:?} can't be urldecoded: {}", seg, e),
+                                    None,
+                                ),
+                            );
+                        },
+                    }
+                }
+            }
+            let token = match get_auth_token(&args.head.headers) {
+                Ok(t) => t,
+                Err(_) => {
+                    log.log(loga::DEBUG, "No auth token in request");
+                    return Ok(response_error(ErrorCode::Unauthorized, "Missing bearer token", Some(&path)));
+                },
+            };
+            if path.first().is_some_and(|s| s == RAFT_ROOT) {
+                // Requests between cluster nodes, which use the cluster token rather than users
+                let Some(cluster) = &self.cluster else {
+                    return Ok(response_404(&path));
+                };
+                if !cluster.check_token(&token) {
+                    return Ok(response_error(ErrorCode::Unauthorized, "Unknown token", Some(&path)));
+                }
+                if path.len() != 2 {
+                    return Ok(response_404(&path));
+                }
+                if args.head.method != Method::POST {
+                    return Ok(response_405(&path, "POST"));
+                }
+                let body = args.body.collect().await.context("Error reading request body")?.to_bytes();
+                fn parse<T: serde::de::DeserializeOwned>(body: &[u8]) -> Result<T, String> {
+                    return serde_json::from_slice::<T>(body).map_err(|e| format!("Invalid cluster request: {}", e));
+                }
+                let resp = match path[1].as_str() {
+                    RAFT_VOTE => parse(&body).map(|req| cluster.handle_vote(req).map(response_200_json)),
+                    RAFT_APPEND => parse(&body).map(|req| cluster.handle_append(req).map(response_200_json)),
+                    RAFT_SNAPSHOT => parse(&body).map(|req| cluster.handle_snapshot(self, req).map(response_200_json)),
+                    _ => {
+                        return Ok(response_404(&path));
+                    },
+                };
+                match resp {
+                    Ok(r) => return Ok(r?),
+                    Err(e) => return Ok(response_error(ErrorCode::BadRequest, e, Some(&path))),
+                }
+            }
+            let token_id = token_id(&token);
+            let token_hash = format!("{}{}", TOKEN_HASH_PREFIX, token_id);
+            // A token that looks like a hashed key would otherwise match that key directly
+            let plain_token = !token.starts_with(TOKEN_HASH_PREFIX);
+            let user = shed!{
+                'user _;
+                if let Some(user) =
+                    self.users.get(&token_hash).or_else(|| self.users.get(&token).filter(|_| plain_token)) {
+                    break 'user Cow::Borrowed(user);
+                };
+                shed!{
+                    let db = self.database.read().unwrap();
+                    let Some(fdap_users) = get(&**db, &self.etags, &vec![format!("fdap_user")])? else {
+                        break;
+                    };
+                    let mut fdap_users =
+                        match serde_json::from_value::<HashMap<String, UserEntry>>(fdap_users.0) {
+                            Ok(f) => f,
+                            Err(e) => {
+                                log.log_err(
+                                    loga::WARN,
+                                    e.context("`fdap_user` key in database has invalid format"),
+                                );
+                                break;
+                            },
+                        };
+                    if let Some(user) = fdap_users.remove(&token_hash).or_else(|| match plain_token {
+                        true => fdap_users.remove(&token),
+                        false => None,
+                    }) {
+                        break 'user Cow::Owned(User::new(user.into_details()));
+                    }
+                }
+                log.log(loga::DEBUG, "No user in config for token");
+                return Ok(response_error(ErrorCode::Unauthorized, "Unknown token", Some(&path)));
+            };
+            if let Some(reason) = user.invalid_at(Utc::now()) {
+                log.log_with(loga::DEBUG, "Token used outside its validity period", ea!(reason = reason));
+                return Ok(response_error(ErrorCode::Unauthorized, reason, Some(&path)));
+            }
+            let grants = &user.grants;
+            log.log_with(
+                loga::DEBUG,
+                "Checking path against grants",
+                ea!(path = path.dbg_str(), grants = grants.dbg_str()),
+            );
+            let grants_actions = match grants.find(&path) {
+                Some(a) => {
+                    log.log_with(
+                        loga::DEBUG,
+                        "User granted actions at path",
+                        ea!(path = path.dbg_str(), actions = a.dbg_str()),
+                    );
+                    a
+                },
+                None => {
+                    // Not rejected immediately - json patches check grants on each affected path
+                    // rather than the request path.
+                    log.log_with(loga::DEBUG, "Found no actions granted at path", ea!(path = path.dbg_str()));
+                    AccessAction {
+                        read: false,
+                        write: false,
+                        deny: false,
+                    }
+                },
+            };
+            if path.first().is_some_and(|s| s == BATCH_ROOT) {
+                if path.len() != 1 {
+                    return Ok(response_404(&path));
+                }
+                if args.head.method != Method::POST {
+                    return Ok(response_405(&path, "POST"));
+                }
+                let body = args.body.collect().await.context("Error reading request body")?.to_bytes();
+                let req = match serde_json::from_slice::<BatchRequest>(body.as_ref()) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        return Ok(
+                            response_error(ErrorCode::BadRequest, format!("Invalid batch request: {}", e), Some(&path)),
+                        );
+                    },
+                };
+                let mut results = vec![];
+                let db = self.database.read().unwrap();
+                for get_path in &req.paths {
+                    results.push(shed!{
+                        'result _;
+                        let error = |code, message: &str| BatchResult::Error(ErrorBody {
+                            code: code,
+                            message: message.to_string(),
+                            path: Some(get_path),
+                            violations: vec![],
+                        });
+                        if is_reserved(get_path) {
+                            break 'result error(ErrorCode::BadRequest, "Reserved paths can't be read in a batch");
+                        }
+                        if !grants.find(get_path).is_some_and(|a| a.read) {
+                            break 'result error(ErrorCode::Forbidden, "Token isn't granted read access at path");
+                        }
+                        let Some((data, ver)) = get(&**db, &self.etags, get_path)? else {
+                            break 'result error(ErrorCode::NotFound, "No data at path");
+                        };
+                        break 'result BatchResult::Ok {
+                            data: redact(grants, get_path, data),
+                            etag: format_etag(ver),
+                        };
+                    });
+                }
+                return Ok(response_200_json(results));
+            }
+            if path.first().is_some_and(|s| s == AUDIT_ROOT) {
+                if args.head.method != Method::GET {
+                    return Ok(response_405(&path, "GET"));
+                }
+                if !grants_actions.read {
+                    return Ok(response_forbidden(&path, "read"));
+                }
+                let since = match query_param(args.head.uri.query(), "since").map(|v| v.parse::<DbVersion>()) {
+                    Some(Ok(v)) => v,
+                    Some(Err(_)) => {
+                        return Ok(
+                            response_error(
+                                ErrorCode::BadRequest,
+                                "Invalid `since` value, must be a version",
+                                Some(&path),
+                            ),
+                        );
+                    },
+                    None => 0,
+                };
+                let limit = match query_param(args.head.uri.query(), "limit").map(|v| v.parse::<usize>()) {
+                    Some(Ok(v)) => v,
+                    Some(Err(_)) => {
+                        return Ok(
+                            response_error(ErrorCode::BadRequest, "Invalid `limit` value, must be a number", Some(&path)),
+                        );
+                    },
+                    None => AUDIT_DEFAULT_LIMIT,
+                };
+                let audit_path = self.audit.lock().unwrap().path.clone();
+                let mut entries = audit::read(&audit_path, &path[1..].to_vec(), since, limit)?;
+                for entry in &mut entries {
+                    // Entries for ancestors include data outside the requested path
+                    let Some(diff) = entry.diff.take() else {
+                        continue;
+                    };
+                    if !grants.find(&entry.path).is_some_and(|a| a.read) {
+                        continue;
+                    }
+                    entry.diff = Some(AuditDiff {
+                        old: diff.old.map(|v| redact(grants, &entry.path, v)),
+                        new: diff.new.map(|v| redact(grants, &entry.path, v)),
+                    });
+                }
+                return Ok(response_200_json(entries));
+            }
+            if path.first().is_some_and(|s| s == REPLICATE_ROOT) {
+                if path.len() == 2 && path[1] == REPLICATE_PROMOTE {
+                    if args.head.method != Method::POST {
+                        return Ok(response_405(&path, "POST"));
+                    }
+                    if !grants_actions.write {
+                        return Ok(response_forbidden(&path, "write"));
+                    }
+                    if replicate::promote(self) {
+                        log.log(loga::INFO, "Promoted from follower, now accepting writes");
+                    }
+                    return Ok(response_200_json(()));
+                }
+                if path.len() != 1 {
+                    return Ok(response_404(&path));
+                }
+                if args.head.method != Method::GET {
+                    return Ok(response_405(&path, "GET"));
+                }
+                if !grants_actions.read {
+                    return Ok(response_forbidden(&path, "read"));
+                }
+                let since = match query_param(args.head.uri.query(), "since").map(|v| v.parse::<DbVersion>()) {
+                    Some(Ok(v)) => v,
+                    Some(Err(_)) | None => {
+                        return Ok(
+                            response_error(
+                                ErrorCode::BadRequest,
+                                "Missing or invalid `since` value, must be a version",
+                                Some(&path),
+                            ),
+                        );
+                    },
+                };
+                let wait = match parse_wait(args.head.uri.query()) {
+                    Ok(w) => w,
+                    Err(e) => {
+                        return Ok(response_error(ErrorCode::BadRequest, e, Some(&path)));
+                    },
+                };
+                let deadline = Instant::now() + wait;
+                let mut changes = self.changes.subscribe();
+                loop {
+                    {
+                        let db = self.database.read().unwrap();
+                        if db.version() != since || Instant::now() >= deadline {
+                            return Ok(response_200_json(replicate::read_since(self, &**db, since)?));
+                        }
+                    }
+                    _ = timeout_at(deadline, changes.changed()).await;
+                }
+            }
+            if args.head.method != Method::GET && args.head.method != Method::HEAD {
+                let following = self.following.read().unwrap().clone();
+                if let Some(follow) = following {
+                    if !follow.forward_writes {
+                        return Ok(
+                            response_error(
+                                ErrorCode::ReadOnly,
+                                format!("This server is a follower, send writes to the leader at {}", follow.leader),
+                                Some(&path),
+                            ),
+                        );
+                    }
+                    let body = args.body.collect().await.context("Error reading request body")?.to_bytes();
+                    return Ok(
+                        forward_write(
+                            &log,
+                            &follow.leader,
+                            &args.head,
+                            &args.subpath,
+                            body.to_vec(),
+                        ).await.context("Error forwarding write to leader")?,
+                    );
+                }
+                if let Some(cluster) = &self.cluster {
+                    match cluster.leader() {
+                        ClusterLeader::This => { },
+                        ClusterLeader::Other(leader) => {
+                            let body = args.body.collect().await.context("Error reading request body")?.to_bytes();
+                            return Ok(
+                                forward_write(
+                                    &log,
+                                    &leader,
+                                    &args.head,
+                                    &args.subpath,
+                                    body.to_vec(),
+                                ).await.context("Error forwarding write to cluster leader")?,
+                            );
+                        },
+                        ClusterLeader::Unknown => {
+                            return Ok(
+                                response_error(
+                                    ErrorCode::Unavailable,
+                                    "The cluster has no leader at the moment, retry later",
+                                    Some(&path),
+                                ),
+                            );
+                        },
+                    }
+                }
+            }
+            match args.head.method {
+                Method::HEAD | Method::GET => {
+                    if !grants_actions.read {
+                        return Ok(response_forbidden(&path, "read"));
+                    }
+                    shed!{
+                        let db = self.database.read().unwrap();
+                        let history = self.history.lock().unwrap();
+                        let as_of = match parse_as_of(args.head.uri.query(), &history) {
+                            Ok(Some(v)) => v,
+                            Ok(None) => {
+                                break;
+                            },
+                            Err(e) => {
+                                return Ok(response_error(ErrorCode::BadRequest, e, Some(&path)));
+                            },
+                        };
+                        match history.get_at(db.get(&path)?, db.version(), &path, as_of) {
+                            Ok(Some(data)) => {
+                                if args.head.method == Method::HEAD {
+                                    return Ok(response_200_json(()));
+                                } else {
+                                    return Ok(response_200_json(redact(grants, &path, data)));
+                                }
+                            },
+                            Ok(None) => {
+                                return Ok(response_404(&path));
+                            },
+                            Err(e) => {
+                                return Ok(response_error(ErrorCode::Gone, e, Some(&path)));
+                            },
+                        }
+                    }
+                    let wait = match parse_wait(args.head.uri.query()) {
+                        Ok(w) => w,
+                        Err(e) => {
+                            return Ok(response_error(ErrorCode::BadRequest, e, Some(&path)));
+                        },
+                    };
+                    let deadline = Instant::now() + wait;
+                    let mut changes = self.changes.subscribe();
+                    let if_ver = args.head.headers.get(IF_NONE_MATCH);
+                    loop {
+                        // When waiting, block while the response would match what the client already
+                        // has (the same etag, or nothing if no etag was sent)
+                        let (resp, unchanged) = shed!{
+                            'resp _;
+                            shed!{
+                                let Some(if_ver) = if_ver else {
+                                    break;
+                                };
+                                let etags = self.etags.read().unwrap();
+                                let Some(&stored_ver) = etags.get(&path) else {
+                                    break;
+                                };
+                                let etag = format_etag(stored_ver);
+                                if if_ver != etag.as_bytes() {
+                                    break;
+                                }
+                                break 'resp (response_304(), true);
+                            }
+                            let db = self.database.read().unwrap();
+                            if let Some((data, ver)) = get(&**db, &self.etags, &path)? {
+                                let etag = format_etag(ver);
+                                if if_ver.is_some_and(|if_ver| if_ver == etag.as_bytes()) {
+                                    break 'resp (response_304(), true);
+                                }
+                                if args.head.method == Method::HEAD {
+                                    break 'resp (response_200_json_etag((), etag), false);
+                                } else {
+                                    break 'resp (response_200_json_etag(redact(grants, &path, data), etag), false);
+                                }
+                            } else {
+                                break 'resp (response_404(&path), if_ver.is_none());
+                            }
+                        };
+                        if !unchanged || Instant::now() >= deadline {
+                            return Ok(resp);
+                        }
+                        _ = timeout_at(deadline, changes.changed()).await;
+                    }
+                },
+                Method::POST | Method::PATCH => {
+                    let body = args.body.collect().await.context("Error reading request body")?.to_bytes();
+                    let write_op = if args.head.method == Method::PATCH {
+                        match content_type(&args.head.headers) {
+                            None | Some(CONTENT_TYPE_MERGE_PATCH) => {
+                                match serde_json::from_slice::<serde_json::Value>(body.as_ref()) {
+                                    Ok(patch) => WriteOp::MergePatch(patch),
+                                    Err(e) => {
+                                        return Ok(
+                                            response_error(
+                                                ErrorCode::BadRequest,
+                                                format!("Invalid JSON in PATCH: {}", e),
+                                                Some(&path),
+                                            ),
+                                        );
+                                    },
+                                }
+                            },
+                            Some(CONTENT_TYPE_JSON_PATCH) => {
+                                match serde_json::from_slice::<Vec<JsonPatchOp>>(body.as_ref()) {
+                                    Ok(ops) => WriteOp::JsonPatch(ops),
+                                    Err(e) => {
+                                        return Ok(
+                                            response_error(
+                                                ErrorCode::BadRequest,
+                                                format!("Invalid JSON patch: {}", e),
+                                                Some(&path),
+                                            ),
+                                        );
+                                    },
+                                }
+                            },
+                            Some(_) => {
+                                return Ok(
+                                    response_error(
+                                        ErrorCode::UnsupportedMediaType,
+                                        format!(
+                                            "PATCH body must be `{}` or `{}`",
+                                            CONTENT_TYPE_MERGE_PATCH,
+                                            CONTENT_TYPE_JSON_PATCH
+                                        ),
+                                        Some(&path),
+                                    ),
+                                );
+                            },
+                        }
+                    } else if has_as_of(args.head.uri.query()) {
+                        WriteOp::Rollback
+                    } else {
+                        match serde_json::from_slice::<serde_json::Value>(body.as_ref()) {
+                            Ok(data) => WriteOp::Replace(data),
+                            Err(e) => {
+                                return Ok(
+                                    response_error(
+                                        ErrorCode::BadRequest,
+                                        format!("Invalid JSON in POST: {}", e),
+                                        Some(&path),
+                                    ),
+                                );
+                            },
+                        }
+                    };
+                    match write_op {
+                        WriteOp::JsonPatch(_) => {
+                            // Checked per operation
+                        },
+                        _ => {
+                            if !grants_actions.write {
+                                return Ok(response_forbidden(&path, "write"));
+                            }
+                            if grants.has_unwritable_descendants(&path) {
+                                return Ok(response_forbidden_below(&path));
+                            }
+                        },
+                    }
+                    let _writing = match &self.cluster {
+                        Some(cluster) => match cluster.start_write(self).await {
+                            Ok(w) => Some(w),
+                            Err(e) => {
+                                return Ok(response_error(ErrorCode::Unavailable, e, Some(&path)));
+                            },
+                        },
+                        None => None,
+                    };
+
+                    // # Sync code
+                    let mut db = self.database.write().unwrap();
+                    if let Some(resp) = check_if_match(self, &**db, &args.head.headers, &path)? {
+                        return Ok(resp);
+                    }
+                    if let Err(e) = resolve_create(&**db, &path)? {
+                        return Ok(response_error(ErrorCode::BadRequest, e, Some(&path)));
+                    }
+                    let mutation = match write_op {
+                        WriteOp::Rollback => {
+                            let old = {
+                                let history = self.history.lock().unwrap();
+                                let as_of = match parse_as_of(args.head.uri.query(), &history) {
+                                    Ok(v) => v.unwrap(),
+                                    Err(e) => {
+                                        return Ok(response_error(ErrorCode::BadRequest, e, Some(&path)));
+                                    },
+                                };
+                                match history.get_at(db.get(&path)?, db.version(), &path, as_of) {
+                                    Ok(old) => old,
+                                    Err(e) => {
+                                        return Ok(response_error(ErrorCode::Gone, e, Some(&path)));
+                                    },
+                                }
+                            };
+                            match old {
+                                Some(data) => dbv1::Mutation::Set {
+                                    path: path.clone(),
+                                    data: data,
+                                },
+                                None => {
+                                    if delete_check(&**db, &path)?.is_err() {
+                                        // Missing then and now
+                                        return Ok(response_200_json(()));
+                                    }
+                                    dbv1::Mutation::Delete { path: path.clone() }
+                                },
+                            }
+                        },
+                        WriteOp::Replace(data) => dbv1::Mutation::Set {
+                            path: path.clone(),
+                            data: data,
+                        },
+                        WriteOp::MergePatch(patch) => dbv1::Mutation::MergePatch {
+                            path: path.clone(),
+                            patch: patch,
+                        },
+                        WriteOp::JsonPatch(ops) => {
+                            let check = |rel_path: &[String], write: bool| -> Result<(), JsonPatchError> {
+                                let mut abs_path = path.clone();
+                                abs_path.extend(rel_path.iter().cloned());
+                                if write && is_reserved(&abs_path) {
+                                    return Err(JsonPatchError::Forbidden(abs_path));
+                                }
+                                let allowed = match grants.find(&abs_path) {
+                                    Some(a) => if write {
+                                        // Writes replace the whole subtree
+                                        a.write && !grants.has_unwritable_descendants(&abs_path)
+                                    } else {
+                                        // Reads copy or compare the whole subtree
+                                        a.read && !grants.has_unreadable_descendants(&abs_path)
+                                    },
+                                    None => false,
+                                };
+                                if !allowed {
+                                    return Err(JsonPatchError::Forbidden(abs_path));
+                                }
+                                return Ok(());
+                            };
+
+                            // Patch a copy so failed patches leave no changes
+                            let mut data = db.get(&path)?.unwrap_or(serde_json::Value::Null);
+                            match apply_json_patch(&mut data, ops, &check) {
+                                Ok(_) => { },
+                                Err(JsonPatchError::Forbidden(p)) => {
+                                    log.log_with(
+                                        loga::DEBUG,
+                                        "JSON patch touches path without grant",
+                                        ea!(path = p.dbg_str()),
+                                    );
+                                    return Ok(
+                                        response_error(
+                                            ErrorCode::Forbidden,
+                                            "JSON patch accesses a path the token isn't granted access to",
+                                            Some(&p),
+                                        ),
+                                    );
+                                },
+                                Err(JsonPatchError::TestFailed(m)) => {
+                                    return Ok(response_error(ErrorCode::Conflict, m, Some(&path)));
+                                },
+                                Err(JsonPatchError::Invalid(m)) => {
+                                    return Ok(response_error(ErrorCode::BadRequest, m, Some(&path)));
+                                },
+                            }
+                            dbv1::Mutation::Set {
+                                path: path.clone(),
+                                data: data,
+                            }
+                        },
+                    };
+                    if let Some(resp) = check_schema(self, &mut **db, &path, &mutation)? {
+                        return Ok(resp);
+                    }
+                    let version = match &self.cluster {
+                        None => {
+                            let version = commit(self, &mut **db, &Writer {
+                                token_id: &token_id,
+                                method: args.head.method.as_str(),
+                                path: &path,
+                            }, mutation)?;
+                            drop(db);
+                            version
+                        },
+                        Some(cluster) => {
+                            let based_on = db.version();
+                            drop(db);
+                            match cluster.propose(based_on, EntryWriter {
+                                token_id: token_id.clone(),
+                                method: args.head.method.to_string(),
+                                path: path.clone(),
+                            }, mutation).await {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    return Ok(response_error(ErrorCode::Unavailable, e, Some(&path)));
+                                },
+                            }
+                        },
+                    };
+                    self.changes.send_replace(version);
+                    return Ok(response_200_json(()));
+                },
+                Method::DELETE => {
+                    if !grants_actions.write {
+                        return Ok(response_forbidden(&path, "write"));
+                    }
+                    if grants.has_unwritable_descendants(&path) {
+                        return Ok(response_forbidden_below(&path));
+                    }
+                    let _writing = match &self.cluster {
+                        Some(cluster) => match cluster.start_write(self).await {
+                            Ok(w) => Some(w),
+                            Err(e) => {
+                                return Ok(response_error(ErrorCode::Unavailable, e, Some(&path)));
+                            },
+                        },
+                        None => None,
+                    };
+
+                    // # Sync code
+                    let mut db = self.database.write().unwrap();
+                    if let Some(resp) = check_if_match(self, &**db, &args.head.headers, &path)? {
+                        return Ok(resp);
+                    }
+                    if let Err(e) = delete_check(&**db, &path)? {
+                        return Ok(response_error(ErrorCode::BadRequest, e, Some(&path)));
+                    }
+                    let mutation = dbv1::Mutation::Delete { path: path.clone() };
+                    if let Some(resp) = check_schema(self, &mut **db, &path, &mutation)? {
+                        return Ok(resp);
+                    }
+                    let version = match &self.cluster {
+                        None => {
+                            let version = commit(self, &mut **db, &Writer {
+                                token_id: &token_id,
+                                method: args.head.method.as_str(),
+                                path: &path,
+                            }, mutation)?;
+                            drop(db);
+                            version
+                        },
+                        Some(cluster) => {
+                            let based_on = db.version();
+                            drop(db);
+                            match cluster.propose(based_on, EntryWriter {
+                                token_id: token_id.clone(),
+                                method: args.head.method.to_string(),
+                                path: path.clone(),
+                            }, mutation).await {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    return Ok(response_error(ErrorCode::Unavailable, e, Some(&path)));
+                                },
+                            }
+                        },
+                    };
+                    self.changes.send_replace(version);
+                    return Ok(response_200_json(()));
+                },
+                _ => {
+                    return Ok(response_405(&path, "GET, HEAD, POST, PATCH, DELETE"));
+                },
+            }
+        }.await {
+            Ok(r) => return r,
+            Err(e) => {
+                log.log_err(loga::WARN, e.context("Error handling response"));
+                return response_error(ErrorCode::Internal, "Internal error", None);
+            },
+        }
+    }
+}
+
+const ENV_CONFIG: &str = "OPENFDAP_CONFIG";
+
+/// Prefix of `users` and `fdap_user` keys that are a hashed token, see `token_id`.
+const TOKEN_HASH_PREFIX: &str = "sha256:";
+const TOKEN_EXPIRY_CHECK_PERIOD: Duration = Duration::from_secs(60 * 60);
+const TOKEN_EXPIRY_WARN_SECS: i64 = 7 * 24 * 60 * 60;
+const TLS_CHECK_PERIOD: Duration = Duration::from_secs(10);
+const CONTENT_TYPE_MERGE_PATCH: &str = "application/merge-patch+json";
+const CONTENT_TYPE_JSON_PATCH: &str = "application/json-patch+json";
+const MAX_WAIT: Duration = Duration::from_secs(300);
+const WAL_COMPACT_RECORDS: usize = 1000;
+
+/// Root key of the read-only virtual tree for reading the audit log.
+const AUDIT_ROOT: &str = "fdap_audit";
+
+/// Root key of the endpoint for reading multiple paths in one request.
+const BATCH_ROOT: &str = "fdap_batch";
+
+/// Root key of the schemas registered for subtrees of the database. This is
+/// normal data.
+const SCHEMA_ROOT: &str = "fdap_schema";
+
+/// Whether the path is in a virtual tree that's handled specially rather than
+/// stored data.
+fn is_reserved(path: &DataPath) -> bool {
+    return path.first().is_some_and(
+        |s| s == AUDIT_ROOT || s == BATCH_ROOT || s == REPLICATE_ROOT || s == RAFT_ROOT,
+    );
+}
+const AUDIT_DEFAULT_LIMIT: usize = 1000;
+
+/// Get a url-decoded query parameter.
+fn query_param(query: Option<&str>, key: &str) -> Option<String> {
+    for kv in query?.split("&") {
+        let (k, v) = kv.split_once("=").unwrap_or((kv, ""));
+        if k != key {
+            continue;
+        }
+        return Some(urlencoding::decode(v).map(|v| v.into_owned()).unwrap_or_else(|_| v.to_string()));
+    }
+    return None;
+}
+
+/// Parse the `wait` query parameter (seconds) used to long-poll for changes.
+fn parse_wait(query: Option<&str>) -> Result<Duration, String> {
+    let Some(v) = query_param(query, "wait") else {
+        return Ok(Duration::ZERO);
+    };
+    let secs = v.parse::<u64>().map_err(|_| format!("Invalid `wait` value {:?}, must be seconds", v))?;
+    return Ok(Duration::from_secs(secs).min(MAX_WAIT));
+}
+
+fn has_as_of(query: Option<&str>) -> bool {
+    return query_param(query, "version").is_some() || query_param(query, "at").is_some();
+}
+
+/// Resolve the `version` or `at` (RFC 3339 timestamp) query parameters to a
+/// historical version.
+fn parse_as_of(query: Option<&str>, history: &History) -> Result<Option<DbVersion>, String> {
+    if let Some(v) = query_param(query, "version") {
+        return Ok(Some(v.parse::<DbVersion>().map_err(|_| format!("Invalid `version` value {:?}", v))?));
+    }
+    if let Some(v) = query_param(query, "at") {
+        let at =
+            chrono::DateTime::parse_from_rfc3339(
+                &v,
+            ).map_err(|e| format!("Invalid `at` value {:?}, must be an RFC 3339 timestamp: {}", v, e))?;
+        return Ok(Some(history.version_at(at.with_timezone(&Utc))?));
+    }
+    return Ok(None);
+}
+
+/// Check the `If-Match` header against the current version of `path`, returning a
+/// 412 response if it doesn't match.
+fn check_if_match(
+    self0: &State,
+    db: &dyn Storage,
+    headers: &http::HeaderMap,
+    path: &DataPath,
+) -> Result<Option<Response<Body>>, loga::Error> {
+    let Some(if_match) = headers.get(IF_MATCH) else {
+        return Ok(None);
+    };
+    let matches = match etag_version(db, &self0.etags, path)? {
+        Some(ver) => {
+            let etag = format_etag(ver);
+            if_match == "*" ||
+                if_match.to_str().is_ok_and(|if_match| if_match.split(",").any(|e| e.trim() == etag))
+        },
+        None => false,
+    };
+    if matches {
+        return Ok(None);
+    }
+    return Ok(
+        Some(
+            response_error(
+                ErrorCode::PreconditionFailed,
+                "Data at path was modified since the version in `If-Match`",
+                Some(path),
+            ),
+        ),
+    );
+}
+
+/// An identifier for a token that's safe to store and display: the zbase32 encoded
+/// sha256 hash of the token.
+pub fn token_id(token: &str) -> String {
+    return zbase32::encode_full_bytes(&Sha256::digest(token.as_bytes()));
+}
+
+/// Remove data the token can't read from `data`, which was read from `path`.
+fn redact(grants: &Grants, path: &DataPath, mut data: serde_json::Value) -> serde_json::Value {
+    fn walk(grants: &Grants, path: &mut DataPath, data: &mut serde_json::Value) {
+        let mut remove = vec![];
+        match data {
+            serde_json::Value::Object(m) => {
+                for (k, v) in m.iter_mut() {
+                    path.push(k.clone());
+                    if !grants.find(path).is_some_and(|a| a.read) {
+                        remove.push(k.clone());
+                    } else if grants.has_unreadable_descendants(path) {
+                        walk(grants, path, v);
+                    }
+                    path.pop();
+                }
+                for k in remove {
+                    m.remove(&k);
+                }
+            },
+            serde_json::Value::Array(a) => {
+                // Elements are left as `null` rather than removed so indexes stay meaningful
+                for (i, v) in a.iter_mut().enumerate() {
+                    path.push(i.to_string());
+                    if !grants.find(path).is_some_and(|a| a.read) {
+                        *v = serde_json::Value::Null;
+                    } else if grants.has_unreadable_descendants(path) {
+                        walk(grants, path, v);
+                    }
+                    path.pop();
+                }
+            },
+            _ => { },
+        }
+    }
+
+    if !grants.has_unreadable_descendants(path) {
+        return data;
+    }
+    walk(grants, &mut path.clone(), &mut data);
+    return data;
+}
+
+fn wipe_etags(self0: &State, at: &DataPath, replace: Option<DbVersion>) {
+    let mut etags = self0.etags.write().unwrap();
+    for prefix in 0 .. at.len() {
+        etags.remove(&at[0 .. prefix]);
+    }
+    let mut suffixes = vec![];
+    for (k, _v) in etags.range((Bound::Included(at.clone()), Bound::Unbounded)) {
+        if !k.starts_with(&at) {
+            break;
+        }
+        suffixes.push(k.clone());
+    }
+    for k in suffixes {
+        etags.remove(&k);
+    }
+    if let Some(v) = replace {
+        etags.insert(at.clone(), v);
+    }
+}
+
+/// The version for the etag of the data at `path`, if there's data there.
+fn etag_version(
+    db: &dyn Storage,
+    etags: &RwLock<BTreeMap<DataPath, DbVersion>>,
+    path: &DataPath,
+) -> Result<Option<DbVersion>, loga::Error> {
+    if db.kind(path)?.is_none() {
+        return Ok(None);
+    }
+    {
+        let etags = etags.read().unwrap();
+        for prefix in (0 ..= path.len()).rev() {
+            if let Some(&ver) = etags.get(&path[0 .. prefix]) {
+                return Ok(Some(ver));
+            }
+        }
+    }
+    etags.write().unwrap().insert(path.clone(), db.version());
+    return Ok(Some(db.version()));
+}
+
+fn get(
+    db: &dyn Storage,
+    etags: &RwLock<BTreeMap<DataPath, DbVersion>>,
+    path: &DataPath,
+) -> Result<Option<(serde_json::Value, DbVersion)>, loga::Error> {
+    let Some(data) = db.get(path)? else {
+        return Ok(None);
+    };
+    let ver = etag_version(db, etags, path)?.unwrap();
+    return Ok(Some((data, ver)));
+}
+
+/// The media type of the request body, without parameters.
+fn content_type(headers: &http::HeaderMap) -> Option<&str> {
+    let t = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+    return Some(t.split(";").next().unwrap().trim());
+}
+
+/// Apply a JSON merge patch (RFC 7396) in place.
+fn merge_patch(target: &mut serde_json::Value, patch: serde_json::Value) {
+    let serde_json::Value::Object(patch) = patch else {
+        *target = patch;
+        return;
+    };
+    if !target.is_object() {
+        *target = serde_json::Value::Object(serde_json::Map::new());
+    }
+    let serde_json::Value::Object(target) = target else {
+        panic!();
+    };
+    for (k, v) in patch {
+        if v.is_null() {
+            target.remove(&k);
+        } else {
+            merge_patch(target.entry(k).or_insert(serde_json::Value::Null), v);
+        }
+    }
+}
+
+enum WriteOp {
+    /// Restore the data as of the version in the query
+    Rollback,
+    Replace(serde_json::Value),
+    MergePatch(serde_json::Value),
+    JsonPatch(Vec<JsonPatchOp>),
+}
+
+/// A JSON patch (RFC 6902) operation. Pointers are relative to the request path.
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case", tag = "op")]
+enum JsonPatchOp {
+    Add {
+        path: String,
+        value: serde_json::Value,
+    },
+    Remove {
+        path: String,
+    },
+    Replace {
+        path: String,
+        value: serde_json::Value,
+    },
+    Move {
+        from: String,
+        path: String,
+    },
+    Copy {
+        from: String,
+        path: String,
+    },
+    Test {
+        path: String,
+        value: serde_json::Value,
+    },
+}
+
+enum JsonPatchError {
+    Forbidden(DataPath),
+    TestFailed(String),
+    Invalid(String),
+}
+
+/// Checks whether the token may read (`false`) or write (`true`) at a path relative
+/// to the patch root.
+type JsonPatchCheck<'a> = dyn 'a + Fn(&[String], bool) -> Result<(), JsonPatchError>;
+
+fn parse_pointer(pointer: &str) -> Result<Vec<String>, JsonPatchError> {
+    if pointer.is_empty() {
+        return Ok(vec![]);
+    }
+    let Some(pointer) = pointer.strip_prefix("/") else {
+        return Err(JsonPatchError::Invalid(format!("JSON pointer {:?} must be empty or start with `/`", pointer)));
+    };
+    return Ok(pointer.split("/").map(|seg| seg.replace("~1", "/").replace("~0", "~")).collect());
+}
+
+/// Parse an array index segment - digits only, no leading zeros.
+fn parse_array_index(seg: &str) -> Option<usize> {
+    if seg.is_empty() || !seg.bytes().all(|c| c.is_ascii_digit()) || (seg.len() > 1 && seg.starts_with("0")) {
+        return None;
+    }
+    return seg.parse().ok();
+}
+
+fn pointer_get<'a>(mut at: &'a serde_json::Value, path: &[String]) -> Option<&'a serde_json::Value> {
+    for seg in path {
+        at = match at {
+            serde_json::Value::Object(m) => m.get(seg)?,
+            serde_json::Value::Array(a) => a.get(parse_array_index(seg)?)?,
+            _ => return None,
+        };
+    }
+    return Some(at);
+}
+
+fn pointer_get_mut<'a>(mut at: &'a mut serde_json::Value, path: &[String]) -> Option<&'a mut serde_json::Value> {
+    for seg in path {
+        at = match at {
+            serde_json::Value::Object(m) => m.get_mut(seg)?,
+            serde_json::Value::Array(a) => a.get_mut(parse_array_index(seg)?)?,
+            _ => return None,
+        };
+    }
+    return Some(at);
+}
+
+fn json_patch_add(
+    root: &mut serde_json::Value,
+    path: &[String],
+    value: serde_json::Value,
+    check: &JsonPatchCheck,
+) -> Result<(), JsonPatchError> {
+    let Some((last, parent_path)) = path.split_last() else {
+        check(path, true)?;
+        *root = value;
+        return Ok(());
+    };
+    match pointer_get_mut(root, parent_path) {
+        Some(serde_json::Value::Object(m)) => {
+            check(path, true)?;
+            m.insert(last.clone(), value);
+        },
+        Some(serde_json::Value::Array(a)) => {
+            // Inserting shifts the other elements, so this modifies the whole array
+            check(parent_path, true)?;
+            if last == "-" {
+                a.push(value);
+            } else {
+                let Some(i) = parse_array_index(last).filter(|i| *i <= a.len()) else {
+                    return Err(JsonPatchError::Invalid(format!("Array index {:?} is invalid or out of bounds", path)));
+                };
+                a.insert(i, value);
+            }
+        },
+        Some(at) => {
+            return Err(
+                JsonPatchError::Invalid(
+                    format!("Data at {:?} is a {}, not an object or array", parent_path, json_type(at)),
+                ),
+            );
+        },
+        None => {
+            return Err(JsonPatchError::Invalid(format!("Parent of {:?} doesn't exist", path)));
+        },
+    }
+    return Ok(());
+}
+
+fn json_patch_remove(
+    root: &mut serde_json::Value,
+    path: &[String],
+    check: &JsonPatchCheck,
+) -> Result<serde_json::Value, JsonPatchError> {
+    let Some((last, parent_path)) = path.split_last() else {
+        check(path, true)?;
+        return Ok(std::mem::replace(root, serde_json::Value::Null));
+    };
+    let missing = || JsonPatchError::Invalid(format!("Data at {:?} doesn't exist", path));
+    match pointer_get_mut(root, parent_path) {
+        Some(serde_json::Value::Object(m)) => {
+            check(path, true)?;
+            return m.remove(last).ok_or_else(missing);
+        },
+        Some(serde_json::Value::Array(a)) => {
+            // Removing shifts the other elements, so this modifies the whole array
+            check(parent_path, true)?;
+            let i = parse_array_index(last).filter(|i| *i < a.len()).ok_or_else(missing)?;
+            return Ok(a.remove(i));
+        },
+        _ => {
+            return Err(missing());
+        },
+    }
+}
+
+/// Apply JSON patch operations in order, stopping at the first failure. The caller
+/// should discard `root` if this fails.
+fn apply_json_patch(
+    root: &mut serde_json::Value,
+    ops: Vec<JsonPatchOp>,
+    check: &JsonPatchCheck,
+) -> Result<(), JsonPatchError> {
+    for op in ops {
+        match op {
+            JsonPatchOp::Add { path, value } => {
+                json_patch_add(root, &parse_pointer(&path)?, value, check)?;
+            },
+            JsonPatchOp::Remove { path } => {
+                json_patch_remove(root, &parse_pointer(&path)?, check)?;
+            },
+            JsonPatchOp::Replace { path, value } => {
+                let path = parse_pointer(&path)?;
+                check(&path, true)?;
+                let Some(at) = pointer_get_mut(root, &path) else {
+                    return Err(JsonPatchError::Invalid(format!("Data at {:?} doesn't exist", path)));
+                };
+                *at = value;
+            },
+            JsonPatchOp::Move { from, path } => {
+                let from = parse_pointer(&from)?;
+                let path = parse_pointer(&path)?;
+                if path.len() > from.len() && path.starts_with(&from) {
+                    return Err(
+                        JsonPatchError::Invalid(format!("Can't move {:?} into its own child {:?}", from, path)),
+                    );
+                }
+
+                // The value is revealed at the destination
+                check(&from, false)?;
+                let value = json_patch_remove(root, &from, check)?;
+                json_patch_add(root, &path, value, check)?;
+            },
+            JsonPatchOp::Copy { from, path } => {
+                let from = parse_pointer(&from)?;
+                let path = parse_pointer(&path)?;
+                check(&from, false)?;
+                let Some(value) = pointer_get(root, &from) else {
+                    return Err(JsonPatchError::Invalid(format!("Data at {:?} doesn't exist", from)));
+                };
+                json_patch_add(root, &path, value.clone(), check)?;
+            },
+            JsonPatchOp::Test { path, value } => {
+                let path = parse_pointer(&path)?;
+                check(&path, false)?;
+                if pointer_get(root, &path) != Some(&value) {
+                    return Err(JsonPatchError::TestFailed(format!("Test failed for data at {:?}", path)));
+                }
+            },
+        }
+    }
+    return Ok(());
+}
+
+/// Walk to `path` for writing. Missing keys and null values along the way are
+/// created as objects, or as arrays if the next segment is `-`. A `-` segment
+/// appends a new element to an array and is replaced in `path` with the new index.
+fn walk_create<'a>(mut at: &'a mut serde_json::Value, path: &mut DataPath) -> Result<&'a mut serde_json::Value, String> {
+    for i in 0 .. path.len() {
+        if at.is_null() {
+            if path[i] == "-" {
+                *at = serde_json::Value::Array(vec![]);
+            } else {
+                *at = serde_json::Value::Object(serde_json::Map::new());
+            }
+        }
+        match at {
+            serde_json::Value::Object(map) => {
+                at = map.entry(path[i].clone()).or_insert_with(|| serde_json::Value::Null);
+            },
+            serde_json::Value::Array(a) => {
+                if path[i] == "-" {
+                    path[i] = a.len().to_string();
+                    a.push(serde_json::Value::Null);
+                    at = a.last_mut().unwrap();
+                } else {
+                    let Some(v) = parse_array_index(&path[i]).and_then(|j| a.get_mut(j)) else {
+                        return Err(format!("Array index at path segment {:?} is invalid or out of bounds", &path[..=i]));
+                    };
+                    at = v;
+                }
+            },
+            _ => {
+                return Err(
+                    format!(
+                        "Data at path segment {:?} is a {}, not null, an object, or an array",
+                        &path[..=i],
+                        json_type(at)
+                    ),
+                );
+            },
+        }
+    }
+    return Ok(at);
+}
+
+/// Resolve `path` for writing, like `walk_create`, returning the path with `-`
+/// segments replaced by indexes and the length of the prefix to write at. Data
+/// below the prefix is all created.
+fn resolve_create<
+    R: StorageRead + ?Sized,
+>(db: &R, path: &DataPath) -> Result<Result<(DataPath, usize), String>, loga::Error> {
+    let mut resolved = vec![];
+    for i in 0 .. path.len() {
+        match db.kind(&resolved)? {
+            None | Some(NodeKind::Null) => {
+                let write_len = resolved.len();
+                resolved.extend(path[i..].iter().map(|seg| if seg == "-" {
+                    "0".to_string()
+                } else {
+                    seg.clone()
+                }));
+                return Ok(Ok((resolved, write_len)));
+            },
+            Some(NodeKind::Object) => {
+                resolved.push(path[i].clone());
+            },
+            Some(NodeKind::Array(len)) => {
+                if path[i] == "-" {
+                    resolved.push(len.to_string());
+                } else if parse_array_index(&path[i]).is_some_and(|j| j < len) {
+                    resolved.push(path[i].clone());
+                } else {
+                    return Ok(
+                        Err(format!("Array index at path segment {:?} is invalid or out of bounds", &path[..=i])),
+                    );
+                }
+            },
+            Some(kind) => {
+                return Ok(
+                    Err(
+                        format!(
+                            "Data at path segment {:?} is a {}, not null, an object, or an array",
+                            &path[..=i],
+                            kind.name()
+                        ),
+                    ),
+                );
+            },
+        }
+    }
+    let write_len = resolved.len();
+    return Ok(Ok((resolved, write_len)));
+}
+
+/// Wrap `data` in new objects for the segments of `path`, or arrays for `-`
+/// segments.
+fn nest(path: &[String], mut data: serde_json::Value) -> serde_json::Value {
+    for seg in path.iter().rev() {
+        if seg == "-" {
+            data = serde_json::Value::Array(vec![data]);
+        } else {
+            let mut map = serde_json::Map::new();
+            map.insert(seg.clone(), data);
+            data = serde_json::Value::Object(map);
+        }
+    }
+    return data;
+}
+
+/// Check that deleting `path` will succeed, without modifying anything.
+fn delete_check<R: StorageRead + ?Sized>(db: &R, path: &DataPath) -> Result<Result<(), String>, loga::Error> {
+    let Some((last, parent_path)) = path.split_last() else {
+        return Ok(Ok(()));
+    };
+    match db.kind(parent_path)? {
+        None => {
+            return Ok(Err(format!("Data at path segment {:?} is missing", parent_path)));
+        },
+        Some(NodeKind::Object) => {
+            return Ok(Ok(()));
+        },
+        Some(NodeKind::Array(_)) => {
+            if parse_array_index(last).is_none() {
+                return Ok(Err(format!("Path segment {:?} is not an array index", last)));
+            }
+            return Ok(Ok(()));
+        },
+        Some(kind) => {
+            return Ok(
+                Err(format!("Data at path segment {:?} is a {}, not an object or array", parent_path, kind.name())),
+            );
+        },
+    }
+}
+
+/// Delete the data at `path`. Deleting an array element shifts later elements down.
+fn delete_at(root: &mut serde_json::Value, path: &DataPath) -> Result<(), String> {
+    // Reading JSON values can't fail
+    delete_check(&*root, path).unwrap()?;
+    let Some((last, parent_path)) = path.split_last() else {
+        *root = serde_json::Value::Null;
+        return Ok(());
+    };
+    match pointer_get_mut(root, parent_path) {
+        Some(serde_json::Value::Object(map)) => {
+            map.remove(last);
+        },
+        Some(serde_json::Value::Array(a)) => {
+            let j = parse_array_index(last).unwrap();
+            if j < a.len() {
+                a.remove(j);
+            }
+        },
+        _ => unreachable!(),
+    }
+    return Ok(());
+}
+
+/// Apply a mutation in a transaction, returning the path whose cached etags need
+/// to be wiped. If the mutation is invalid nothing is changed and the inner result
+/// is the reason.
+fn apply_mutation<
+    T: Transaction + ?Sized,
+>(tx: &mut T, mutation: dbv1::Mutation) -> Result<Result<Option<DataPath>, String>, loga::Error> {
+    match mutation {
+        dbv1::Mutation::Set { path, data } => {
+            let (resolved, write_len) = match resolve_create(&*tx, &path)? {
+                Ok(r) => r,
+                Err(e) => return Ok(Err(e)),
+            };
+            tx.replace(&resolved[..write_len], nest(&path[write_len..], data))?;
+            return Ok(Ok(Some(resolved)));
+        },
+        dbv1::Mutation::MergePatch { path, patch } => {
+            let (resolved, write_len) = match resolve_create(&*tx, &path)? {
+                Ok(r) => r,
+                Err(e) => return Ok(Err(e)),
+            };
+            let mut data = if write_len == resolved.len() {
+                tx.get(&resolved)?.unwrap_or(serde_json::Value::Null)
+            } else {
+                serde_json::Value::Null
+            };
+            merge_patch(&mut data, patch);
+            tx.replace(&resolved[..write_len], nest(&path[write_len..], data))?;
+            return Ok(Ok(Some(resolved)));
+        },
+        dbv1::Mutation::Delete { mut path } => {
+            if let Err(e) = delete_check(&*tx, &path)? {
+                return Ok(Err(e));
+            }
+            tx.delete(&path)?;
+
+            // Array elements after the deleted one shift, so wipe from the parent
+            path.pop();
+            return Ok(Ok(Some(path)));
+        },
+        dbv1::Mutation::Noop => {
+            return Ok(Ok(None));
+        },
+    }
+}
+
+/// Whether one path is within the other.
+fn paths_overlap(a: &[String], b: &[String]) -> bool {
+    return a.iter().zip(b.iter()).all(|(a, b)| a == b);
+}
+
+fn parse_schema_entry(entry: &serde_json::Value) -> Result<(AccessPath, Schema), String> {
+    let entry =
+        serde_json::from_value::<SchemaEntry>(entry.clone()).map_err(|e| format!("Invalid schema entry: {}", e))?;
+    if entry.path.iter().any(|s| matches!(s, AccessPathSeg::Variable(_))) {
+        return Err(format!("Schema entry paths can't have `variable` segments"));
+    }
+    let schema = Schema::compile(&entry.schema).map_err(|e| format!("Invalid schema: {}", e))?;
+    return Ok((entry.path, schema));
+}
+
+/// A registration in `fdap_schema`, or why it's invalid.
+struct RegisteredSchema {
+    name: String,
+    parsed: Result<(AccessPath, Schema), String>,
+}
+
+fn parse_registered_schemas(entries: Option<serde_json::Value>) -> Vec<RegisteredSchema> {
+    let Some(serde_json::Value::Object(entries)) = entries else {
+        return vec![];
+    };
+    return entries.into_iter().map(|(name, entry)| RegisteredSchema {
+        parsed: parse_schema_entry(&entry),
+        name: name,
+    }).collect();
+}
+
+/// The compiled registrations in `fdap_schema`, compiling them again only if
+/// `fdap_schema` changed since last time.
+fn registered_schemas(self0: &State, db: &dyn Storage) -> Result<Arc<Vec<RegisteredSchema>>, loga::Error> {
+    let root = vec![SCHEMA_ROOT.to_string()];
+    let Some(version) = etag_version(db, &self0.etags, &root)? else {
+        return Ok(Arc::new(vec![]));
+    };
+    let mut cache = self0.registered_schemas.lock().unwrap();
+    if let Some((cached_version, registered)) = &*cache {
+        if *cached_version == version {
+            return Ok(registered.clone());
+        }
+    }
+    let registered = Arc::new(parse_registered_schemas(db.get(&root)?));
+    *cache = Some((version, registered.clone()));
+    return Ok(registered);
+}
+
+/// Check the schemas registered in `fdap_schema` against data after `change`.
+/// Only data matching a registration under or containing the change is checked
+/// (see `Schema::validate_change`), or all data for a registration if the
+/// registration itself changed. `registered` is from before the change, and is
+/// ignored if the change was to `fdap_schema`.
+fn check_registered_schemas<
+    R: StorageRead + ?Sized,
+>(
+    registered: Arc<Vec<RegisteredSchema>>,
+    after: &R,
+    change: &Change,
+) -> Result<Vec<SchemaViolation>, loga::Error> {
+    let schema_root = vec![SCHEMA_ROOT.to_string()];
+    let registered = match paths_overlap(&change.path, &schema_root) {
+        true => Arc::new(parse_registered_schemas(after.get(&schema_root)?)),
+        false => registered,
+    };
+
+    // Deleting an array element shifts the later ones, so matches at any of them may
+    // have changed
+    let mut search = change.path.clone();
+    if change.kind == ChangeKind::Delete {
+        if let Some((_, parent)) = change.path.split_last() {
+            if let Some(NodeKind::Array(_)) = after.kind(parent)? {
+                search = parent.to_vec();
+            }
+        }
+    }
+    let mut violations = vec![];
+    for entry in registered.iter() {
+        let entry_path = vec![SCHEMA_ROOT.to_string(), entry.name.clone()];
+        let entry_changed = paths_overlap(&entry_path, &change.path);
+        let (pattern, schema) = match &entry.parsed {
+            Ok(s) => s,
+            Err(e) => {
+                // Only reject changes to the broken entry itself, so one bad entry (ex: from
+                // before schemas were checked) doesn't block unrelated writes
+                if entry_changed {
+                    violations.push(SchemaViolation {
+                        pointer: format_pointer(&entry_path),
+                        message: e.clone(),
+                    });
+                }
+                continue;
+            },
+        };
+        if entry_changed {
+            for path in find_overlapping(pattern, &pattern_prefix(pattern), after)? {
+                let Some(data) = after.get(&path)? else {
+                    continue;
+                };
+                violations.extend(schema.validate_at(&path, &data));
+            }
+        } else {
+            for path in find_overlapping(pattern, &search, after)? {
+                violations.extend(schema.validate_change(&path, change, after)?);
+            }
+        }
+    }
+    return Ok(violations);
+}
+
+/// Check that the database would still match the schemas after `mutation`, both
+/// the configured root schema and those in `fdap_schema`, returning a 422 response
+/// listing the violations if not.
+fn check_schema(
+    self0: &State,
+    db: &mut dyn Storage,
+    path: &DataPath,
+    mutation: &dbv1::Mutation,
+) -> Result<Option<Response<Body>>, loga::Error> {
+    if self0.schema.is_none() && db.kind(&[SCHEMA_ROOT.to_string()])?.is_none() &&
+        !paths_overlap(path, &[SCHEMA_ROOT.to_string()]) {
+        // Nothing to check
+        return Ok(None);
+    }
+    let change = match mutation {
+        dbv1::Mutation::Set { path, .. } | dbv1::Mutation::MergePatch { path, .. } => {
+            let Ok((resolved, write_len)) = resolve_create(&*db, path)? else {
+                // Will fail the same way when committed
+                return Ok(None);
+            };
+            let write_path = resolved[..write_len].to_vec();
+            Change {
+                kind: match db.kind(&write_path)? {
+                    Some(_) => ChangeKind::Replace,
+                    None => ChangeKind::Create,
+                },
+                path: write_path,
+            }
+        },
+        dbv1::Mutation::Delete { path } => {
+            if db.kind(path)?.is_none() {
+                return Ok(None);
+            }
+            Change {
+                path: path.clone(),
+                kind: ChangeKind::Delete,
+            }
+        },
+        dbv1::Mutation::Noop => {
+            return Ok(None);
+        },
+    };
+    let registered = registered_schemas(self0, &*db)?;
+
+    // Make the change in a transaction that's rolled back, so the schemas are checked
+    // against the new data while only reading the parts they need
+    let mut tx = db.transaction()?;
+    if apply_mutation(&mut *tx, mutation.clone())?.is_err() {
+        return Ok(None);
+    }
+    let mut violations = vec![];
+    if let Some(schema) = &self0.schema {
+        violations.extend(schema.validate_change(&vec![], &change, &*tx)?);
+    }
+    violations.extend(check_registered_schemas(registered, &*tx, &change)?);
+    drop(tx);
+    if violations.is_empty() {
+        return Ok(None);
+    }
+    return Ok(
+        Some(
+            Response::builder()
+                .status(ErrorCode::SchemaViolation.status())
+                .header(CONTENT_TYPE, "application/json")
+                .body(body_json(ErrorBody {
+                    code: ErrorCode::SchemaViolation,
+                    message: "The change would make the database not match the schema".to_string(),
+                    path: Some(path),
+                    violations: violations,
+                }))
+                .unwrap(),
+        ),
+    );
+}
+
+/// Who is making a change, for the audit log.
+struct Writer<'a> {
+    token_id: &'a str,
+    method: &'a str,
+    path: &'a DataPath,
+}
+
+/// Durably log a mutation then apply it, returning the new version. The mutation
+/// should be checked first (`resolve_create`, `delete_check`) - if it fails to apply
+/// it's also skipped during log replay, but the request will fail as an internal
+/// error.
+fn apply_logged(
+    db: &mut dyn Storage,
+    version: DbVersion,
+    mutation: dbv1::Mutation,
+) -> Result<Option<DataPath>, loga::Error> {
+    let mut tx = db.transaction()?;
+    let changed_path =
+        apply_mutation(
+            &mut *tx,
+            mutation,
+        )?.map_err(|e| loga::err_with("Logged mutation failed to apply", ea!(err = e)))?;
+    tx.commit(version)?;
+    return Ok(changed_path);
+}
+
+fn commit(
+    self0: &State,
+    db: &mut dyn Storage,
+    writer: &Writer,
+    mutation: dbv1::Mutation,
+) -> Result<DbVersion, loga::Error> {
+    let mut history = self0.history.lock().unwrap();
+    let mut audit = self0.audit.lock().unwrap();
+    let old_version = db.version();
+    let old_data = if audit.include_diff && !matches!(mutation, dbv1::Mutation::Noop) {
+        Some(db.get(writer.path)?)
+    } else {
+        None
+    };
+    let mut record = dbv1::WalRecord {
+        version: old_version + 1,
+        timestamp: Utc::now(),
+        undo: if history.enabled() {
+            mutation_undo(&*db, &mutation)?
+        } else {
+            None
+        },
+        mutation: mutation,
+    };
+    let wal_start = self0.wal.lock().unwrap().append(&record).context("Failed to log database changes")?;
+    let history_entry = take_history_entry(&mut record);
+    let changed_path = match apply_logged(db, record.version, record.mutation) {
+        Ok(p) => p,
+        Err(e) => {
+            // Don't replay a change that was never made
+            if let Err(e) = self0.wal.lock().unwrap().unappend(wal_start) {
+                self0.log.log_err(loga::WARN, e.context("Error removing unapplied change from write-ahead log"));
+            }
+            return Err(e);
+        },
+    };
+    if let Some(entry) = history_entry {
+        history.push(entry);
+    }
+    let Some(changed_path) = changed_path else {
+        // Nothing to audit
+        return Ok(record.version);
+    };
+    wipe_etags(self0, &changed_path, None);
+
+    // The change is committed at this point so failing to audit can't fail the request
+    match audit.append(&AuditEntry {
+        timestamp: record.timestamp,
+        token: writer.token_id.to_string(),
+        method: writer.method.to_string(),
+        path: writer.path.clone(),
+        old_version: old_version,
+        new_version: record.version,
+        diff: match old_data {
+            Some(old) => Some(AuditDiff {
+                old: old,
+                new: db.get(writer.path)?,
+            }),
+            None => None,
+        },
+    }) {
+        Ok(_) => { },
+        Err(e) => {
+            self0.log.log_err(loga::ERROR, e.context("Failed to write audit log entry"));
+        },
+    }
+    return Ok(record.version);
+}
+
+/// Make the database durable without the logs (ex: write a snapshot) and delete
+/// the logs it replaces that aren't needed for history.
+fn compact(self0: &State) -> Result<(), loga::Error> {
+    let (checkpoint, old_logs) = {
+        let db = self0.database.read().unwrap();
+        let history = self0.history.lock().unwrap();
+        let mut wal = self0.wal.lock().unwrap();
+        let checkpoint = db.checkpoint();
+        let old_logs = wal.rotate(db.version(), history.oldest_retained(db.version()))?;
+        (checkpoint, old_logs)
+    };
+    checkpoint().context("Failed to write database snapshot")?;
+    for path in old_logs {
+        std::fs::remove_file(&path).context_with("Error deleting compacted log", ea!(path = path.display()))?;
+    }
+    return Ok(());
+}
+
+fn json_type(v: &serde_json::Value) -> &str {
+    return match v {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "bool",
+        serde_json::Value::Number(_) => "number",
+        serde_json::Value::String(_) => "string",
+        serde_json::Value::Array(_) => "array",
+        serde_json::Value::Object(_) => "object",
+    };
+}
+
+fn atomic_write(path: &Path, data: &[u8]) -> Result<(), loga::Error> {
+    let dir = path.parent().unwrap();
+    let mut temp = NamedTempFile::new_in(dir).context("Error creating temp file for atomic write")?;
+    temp.write_all(data).context_with("Error writing temp file", ea!(path = temp.path().display()))?;
+    temp.as_file().sync_all().context_with("Error syncing temp file", ea!(path = temp.path().display()))?;
+    temp.persist(path).context_with("Error atomically replacing file", ea!(path = path.display()))?;
+    sync_dir(dir)?;
+    return Ok(());
+}
+
+/// Make file creations, renames and deletions in `dir` durable.
+fn sync_dir(dir: &Path) -> Result<(), loga::Error> {
+    std::fs::File::open(dir)
+        .and_then(|d| d.sync_all())
+        .context_with("Error syncing directory", ea!(path = dir.display()))?;
+    return Ok(());
+}
+
+#[derive(Debug)]
+struct TlsCertResolver(RwLock<Arc<CertifiedKey>>);
+
+impl ResolvesServerCert for TlsCertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        return Some(self.0.read().unwrap().clone());
+    }
+}
+
+fn load_tls_cert(tls: &TlsConfig) -> Result<Arc<CertifiedKey>, loga::Error> {
+    let certs =
+        rustls_pemfile::certs(
+            &mut std::fs::read(
+                &tls.cert,
+            ).context_with("Error reading TLS cert", ea!(path = tls.cert.display()))?.as_slice(),
+        )
+            .collect::<Result<Vec<_>, _>>()
+            .context_with("Error parsing TLS cert PEM", ea!(path = tls.cert.display()))?;
+    if certs.is_empty() {
+        return Err(loga::err_with("No certificates found in TLS cert PEM", ea!(path = tls.cert.display())));
+    }
+    let key =
+        rustls_pemfile::private_key(
+            &mut std::fs::read(
+                &tls.key,
+            ).context_with("Error reading TLS key", ea!(path = tls.key.display()))?.as_slice(),
+        )
+            .context_with("Error parsing TLS key PEM", ea!(path = tls.key.display()))?
+            .ok_or_else(|| loga::err_with("No private key found in TLS key PEM", ea!(path = tls.key.display())))?;
+    let key =
+        rustls::crypto::aws_lc_rs::sign::any_supported_type(
+            &key,
+        ).context_with("Unsupported TLS private key type", ea!(path = tls.key.display()))?;
+    return Ok(Arc::new(CertifiedKey::new(certs, key)));
+}
+
+fn tls_mtimes(tls: &TlsConfig) -> Option<(SystemTime, SystemTime)> {
+    let cert = std::fs::metadata(&tls.cert).and_then(|m| m.modified()).ok()?;
+    let key = std::fs::metadata(&tls.key).and_then(|m| m.modified()).ok()?;
+    return Some((cert, key));
+}
+
+async fn inner(log: &Log, tm: &TaskManager, args: Args) -> Result<(), loga::Error> {
+    // Get config (fallback to env, for use in ex: docker)
+    let config = if let Some(p) = args.config {
+        p.value
+    } else if let Some(c) = match std::env::var(ENV_CONFIG) {
+        Ok(c) => Some(c),
+        Err(e) => match e {
+            std::env::VarError::NotPresent => None,
+            std::env::VarError::NotUnicode(_) => {
+                return Err(loga::err_with("Error parsing env var as unicode", ea!(env = ENV_CONFIG)))
+            },
+        },
+    } {
+        serde_json::from_str::<Config>(&c).context("Parsing config")?
+    } else {
+        return Err(
+            loga::err_with("No config passed on command line, and no config set in env var", ea!(env = ENV_CONFIG)),
+        );
+    };
+    serve(log, tm, config).await?;
+    return Ok(());
+}
+
+async fn serve(log: &Log, tm: &TaskManager, config: Config) -> Result<Arc<State>, loga::Error> {
+    // Setup state
+    if config.follow.is_some() && config.cluster.is_some() {
+        return Err(loga::err("`follow` and `cluster` can't both be set"));
+    }
+    create_dir_all(&config.data_dir).await.context("Error creating data dir")?;
+    let json_path = config.data_dir.join("db.json");
+    let mut database: Box<dyn Storage> = match config.storage {
+        StorageBackend::Json => Box::new(JsonStorage::open(&json_path)?),
+        StorageBackend::Sqlite => {
+            let sqlite_path = config.data_dir.join("db.sqlite");
+            let import = if sqlite_path.exists() {
+                None
+            } else {
+                // Switching from `json`
+                read_json_database(&json_path)?
+            };
+            Box::new(SqliteStorage::open(&sqlite_path, import)?)
+        },
+    };
+    let mut history = History::new(config.history);
+    let wal = Wal::open(log, &config.data_dir, &mut *database, &mut history)?;
+    let version = database.version();
+    let state = Arc::new(State {
+        log: log.clone(),
+        database: RwLock::new(database),
+        history: Mutex::new(history),
+        wal: Mutex::new(wal),
+        audit: Mutex::new(Audit::open(&config.data_dir, config.audit_diff)?),
+        schema: match &config.schema {
+            Some(p) => Some(Schema::load(p)?),
+            None => None,
+        },
+        registered_schemas: Mutex::new(None),
+        following: RwLock::new(match config.follow {
+            Some(f) => Some(Follow::new(f)?),
+            None => None,
+        }),
+        cluster: match config.cluster {
+            Some(c) => Some(Cluster::open(log, &config.data_dir, c, version)?),
+            None => None,
+        },
+        users: config
+            .users
+            .into_iter()
+            .map(|(k, v)| (k, User::new(v.into_details())))
+            .collect(),
+        etags: Default::default(),
+        changes: watch::channel(version).0,
+    });
+
+    // Warn about tokens that will expire soon
+    tm.task("Token expiry check", {
+        let tm = tm.clone();
+        let state = state.clone();
+        let log = log.clone();
+        async move {
+            loop {
+                let now = Utc::now();
+                let mut users = vec![];
+                for (key, user) in &state.users {
+                    users.push((key.clone(), user.expires_at));
+                }
+                shed!{
+                    let db = state.database.read().unwrap();
+                    let Ok(Some(fdap_users)) = db.get(&vec![format!("fdap_user")]) else {
+                        break;
+                    };
+                    let Ok(fdap_users) = serde_json::from_value::<HashMap<String, UserEntry>>(fdap_users) else {
+                            break;
+                        };
+                    for (key, user) in fdap_users {
+                        users.push((key, user.into_details().expires_at));
+                    }
+                }
+                for (key, expires_at) in users {
+                    let Some(expires_at) = expires_at else {
+                        continue;
+                    };
+                    if expires_at <= now || expires_at - now > chrono::Duration::seconds(TOKEN_EXPIRY_WARN_SECS) {
+                        continue;
+                    }
+
+                    // Don't log plaintext tokens
+                    let id = match key.strip_prefix(TOKEN_HASH_PREFIX) {
+                        Some(hash) => hash.to_string(),
+                        None => token_id(&key),
+                    };
+                    log.log_with(
+                        loga::WARN,
+                        "Token expires soon",
+                        ea!(token = id, expires_at = expires_at.to_rfc3339()),
+                    );
+                }
+                let Some(_) = tm.if_alive(sleep(TOKEN_EXPIRY_CHECK_PERIOD)).await else {
+                    break;
+                };
+            }
+        }
+    });
+
+    // Elect a leader and replicate changes between cluster nodes
+    if state.cluster.is_some() {
+        cluster::start(tm, &state);
+    }
+
+    // Copy changes from the leader
+    if state.following.read().unwrap().is_some() {
+        tm.task("Replication", replicate::follow(log.clone(), tm.clone(), state.clone()));
+    }
+
+    // Compact the write-ahead log into a snapshot once it gets long
+    tm.task("Database compaction", {
+        let tm = tm.clone();
+        let state = state.clone();
+        let log = log.clone();
+        async move {
+            let mut changes = state.changes.subscribe();
+            loop {
+                let Some(_) = tm.if_alive(changes.changed()).await else {
+                    break;
+                };
+                if state.wal.lock().unwrap().records < WAL_COMPACT_RECORDS {
+                    continue;
+                }
+                match tokio::task::spawn_blocking({
+                    let state = state.clone();
+                    move || compact(&state)
+                }).await {
+                    Ok(Ok(())) => { },
+                    Ok(Err(e)) => {
+                        log.log_err(loga::WARN, e.context("Error compacting database"));
+                    },
+                    Err(e) => {
+                        log.log_err(loga::WARN, e.context("Database compaction task failed"));
+                    },
+                }
+            }
+        }
+    });
+
+    // Set up tls, reloading the cert on sighup or when the files change
+    let tls_acceptor = match config.tls {
+        Some(tls) => {
+            let resolver = Arc::new(TlsCertResolver(RwLock::new(load_tls_cert(&tls)?)));
+            let mut server_config =
+                ServerConfig::builder_with_provider(Arc::new(rustls::crypto::aws_lc_rs::default_provider()))
+                    .with_safe_default_protocol_versions()
+                    .context("Error setting up TLS protocol versions")?
+                    .with_no_client_auth()
+                    .with_cert_resolver(resolver.clone());
+            server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+            tm.task("Tls cert reloader", {
+                let tm = tm.clone();
+                let log = log.clone();
+                async move {
+                    let mut sighup = match signal(SignalKind::hangup()) {
+                        Ok(s) => s,
+                        Err(e) => {
+                            log.log_err(loga::WARN, e.context("Error listening for SIGHUP, TLS reload disabled"));
+                            return;
+                        },
+                    };
+                    let mut check = interval(TLS_CHECK_PERIOD);
+                    let mut last_mtimes = tls_mtimes(&tls);
+                    loop {
+                        let Some(sighup) = tm.if_alive(async {
+                            select!{
+                                _ = sighup.recv() => true,
+                                _ = check.tick() => false,
+                            }
+                        }).await else {
+                            break;
+                        };
+                        let mtimes = tls_mtimes(&tls);
+                        if !sighup && mtimes == last_mtimes {
+                            continue;
+                        }
+                        last_mtimes = mtimes;
+                        match load_tls_cert(&tls) {
+                            Ok(cert) => {
+                                *resolver.0.write().unwrap() = cert;
+                                log.log(loga::INFO, "Reloaded TLS cert");
+                            },
+                            Err(e) => {
+                                log.log_err(loga::WARN, e.context("Error reloading TLS cert, keeping previous cert"));
+                            },
+                        }
+                    }
+                }
+            });
+            Some(TlsAcceptor::from(Arc::new(server_config)))
+        },
+        None => None,
+    };
+
+    // Start server
+    tm.critical_stream(
+        format!("Http server - {}", config.bind_addr),
+        TcpListenerStream::new(
+            TcpListener::bind(&config.bind_addr)
+                .await
+                .context_with("Error binding to address", ea!(addr = config.bind_addr))?,
+        ),
+        {
+            let tm = tm.clone();
+            let state = state.clone();
+            let log = log.clone();
+            move |stream| {
+                let tm = tm.clone();
+                let state = state.clone();
+                let log = log.clone();
+                let tls_acceptor = tls_acceptor.clone();
+                async move {
+                    let stream = match stream {
+                        Ok(s) => s,
+                        Err(e) => {
+                            log.log(loga::DEBUG, e.context("Error opening peer stream"));
+                            return Ok(());
+                        },
+                    };
+                    tokio::task::spawn({
+                        async move {
+                            // Close open connections on shutdown too
+                            let Some(res) = tm.if_alive(async {
+                                match tls_acceptor {
+                                    Some(tls_acceptor) => return htserve::handler::root_handle_https(
+                                        &log,
+                                        tls_acceptor,
+                                        state,
+                                        stream,
+                                    ).await,
+                                    None => return htserve::handler::root_handle_http(&log, state, stream).await,
+                                }
+                            }).await else {
+                                return;
+                            };
+                            match res {
+                                Ok(_) => (),
+                                Err(e) => {
+                                    log.log_err(loga::DEBUG, e.context("Error serving connection"));
+                                },
+                            }
+                        }
+                    });
+                    return Ok(());
+                }
+            }
+        },
+    );
+    return Ok(state);
+}
+
+#[tokio::main]
+async fn main() {
+    let args = aargvark::vark::<Args>();
+    if args.validate.is_some() {
+        return;
+    }
+    if args.generate_token.is_some() {
+        let token = zbase32::encode_full_bytes(&rand::random::<[u8; 32]>());
+        println!("Token: {}", token);
+        println!("Hashed: {}{}", TOKEN_HASH_PREFIX, token_id(&token));
+        return;
+    }
+    let log = Log::new_root(match args.debug.is_some() {
+        true => loga::DEBUG,
+        false => loga::INFO,
+    });
+    let tm = taskmanager::TaskManager::new();
+    match inner(&log, &tm, args).await.map_err(|e| {
+        tm.terminate();
+        return e;
+    }).also({
+        tm.join(&log).await.context("Critical services failed")
+    }) {
+        Ok(_) => { },
+        Err(e) => {
+            loga::fatal(e);
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::{
+            storage::Storage,
+            test_util::{
+                free_addr,
+                test_config,
+                wait_for,
+                TestNode,
+                TEST_TOKEN,
+            },
+        },
+        http::Method,
+        rustls::{
+            pki_types::{
+                CertificateDer,
+                ServerName,
+            },
+            ClientConfig,
+            RootCertStore,
+        },
+        serde_json::json,
+        std::{
+            path::Path,
+            sync::Arc,
+        },
+        tokio::net::TcpStream,
+        tokio_rustls::TlsConnector,
+    };
+
+    /// Write a new self-signed cert for `localhost`, returning it.
+    fn write_cert(dir: &Path) -> CertificateDer<'static> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert.cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), cert.key_pair.serialize_pem()).unwrap();
+        return cert.cert.der().clone();
+    }
+
+    /// Connect, trusting any of `certs`, and return the cert the server presented.
+    async fn served_cert(addr: &str, certs: &[CertificateDer<'static>]) -> CertificateDer<'static> {
+        let mut roots = RootCertStore::empty();
+        for cert in certs {
+            roots.add(cert.clone()).unwrap();
+        }
+        let config =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::aws_lc_rs::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let stream =
+            TlsConnector::from(Arc::new(config))
+                .connect(ServerName::try_from("localhost").unwrap(), stream)
+                .await
+                .unwrap();
+        return stream.get_ref().1.peer_certificates().unwrap()[0].clone();
+    }
+
+    const LIMITED_TOKEN: &str = "limited-token";
+
+    /// A node with data in `a`, `b`, `list` and `hidden`, and a `LIMITED_TOKEN` that
+    /// can write `a` and elements of `list`, read `b` and `list`, and can't access
+    /// `hidden`.
+    async fn start_patch_node(dir: &Path) -> TestNode {
+        let mut config = test_config(dir, &free_addr());
+        config["users"][LIMITED_TOKEN] = json!([
+            { "path": [{ "string": "a" }], "action": { "read": true, "write": true } },
+            { "path": [{ "string": "b" }], "action": { "read": true, "write": false } },
+            { "path": [{ "string": "list" }], "action": { "read": true, "write": false } },
+            { "path": [{ "string": "list" }, "wildcard"], "action": { "read": true, "write": true } },
+        ]);
+        let node = TestNode::start(config).await;
+        for (path, value) in [
+            ("a", json!({
+                "x": 1
+            })),
+            ("b", json!({
+                "y": 2
+            })),
+            ("list", json!([1, 2, 3])),
+            ("hidden", json!("secret")),
+        ] {
+            assert_eq!(node.request(Method::POST, path, Some(value)).await.unwrap().0, 200);
+        }
+        return node;
+    }
+
+    async fn json_patch(node: &TestNode, token: &str, ops: serde_json::Value) -> (u16, serde_json::Value) {
+        return node
+            .request_as(token, Method::PATCH, "", Some(super::CONTENT_TYPE_JSON_PATCH), Some(ops))
+            .await
+            .unwrap();
+    }
+
+    async fn get(node: &TestNode, path: &str) -> serde_json::Value {
+        let (status, body) = node.request(Method::GET, path, None).await.unwrap();
+        assert_eq!(status, 200);
+        return body;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_json_patch_atomic() {
+        let dir = tempfile::tempdir().unwrap();
+        let node = start_patch_node(dir.path()).await;
+
+        // Failed test after a change
+        let (status, _) = json_patch(&node, TEST_TOKEN, json!([
+            { "op": "replace", "path": "/a/x", "value": 5 },
+            { "op": "test", "path": "/b/y", "value": 999 },
+        ])).await;
+        assert_eq!(status, 409);
+        assert_eq!(get(&node, "a/x").await, json!(1));
+
+        // Invalid op after a change
+        let (status, _) = json_patch(&node, TEST_TOKEN, json!([
+            { "op": "replace", "path": "/a/x", "value": 5 },
+            { "op": "remove", "path": "/missing" },
+        ])).await;
+        assert_eq!(status, 400);
+        assert_eq!(get(&node, "a/x").await, json!(1));
+
+        // All succeed
+        let (status, _) = json_patch(&node, TEST_TOKEN, json!([
+            { "op": "test", "path": "/b/y", "value": 2 },
+            { "op": "replace", "path": "/a/x", "value": 5 },
+            { "op": "copy", "from": "/b/y", "path": "/a/y" },
+        ])).await;
+        assert_eq!(status, 200);
+        assert_eq!(get(&node, "a").await, json!({
+            "x": 5,
+            "y": 2
+        }));
+        node.kill().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_json_patch_access() {
+        let dir = tempfile::tempdir().unwrap();
+        let node = start_patch_node(dir.path()).await;
+
+        // Each op is checked, reporting the denied path
+        let (status, body) = json_patch(&node, LIMITED_TOKEN, json!([
+            { "op": "replace", "path": "/a/x", "value": 2 },
+            { "op": "replace", "path": "/b/y", "value": 3 },
+        ])).await;
+        assert_eq!(status, 403);
+        assert_eq!(body["path"], json!(["b", "y"]));
+        assert_eq!(get(&node, "a/x").await, json!(1));
+
+        // Moving reads the source
+        let (status, body) = json_patch(&node, LIMITED_TOKEN, json!([
+            { "op": "move", "from": "/hidden", "path": "/a/h" },
+        ])).await;
+        assert_eq!(status, 403);
+        assert_eq!(body["path"], json!(["hidden"]));
+        assert_eq!(get(&node, "hidden").await, json!("secret"));
+
+        // Allowed ops across subtrees from the root
+        let (status, _) = json_patch(&node, LIMITED_TOKEN, json!([
+            { "op": "test", "path": "/b/y", "value": 2 },
+            { "op": "replace", "path": "/a/x", "value": 2 },
+        ])).await;
+        assert_eq!(status, 200);
+        assert_eq!(get(&node, "a/x").await, json!(2));
+        node.kill().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_json_patch_array_access() {
+        let dir = tempfile::tempdir().unwrap();
+        let node = start_patch_node(dir.path()).await;
+
+        // Replacing an element only needs access to the element
+        let (status, _) = json_patch(&node, LIMITED_TOKEN, json!([
+            { "op": "replace", "path": "/list/0", "value": 7 },
+        ])).await;
+        assert_eq!(status, 200);
+
+        // Inserting or removing shifts the other elements, so needs the whole array
+        for op in [
+            json!({ "op": "add", "path": "/list/1", "value": 9 }),
+            json!({ "op": "add", "path": "/list/-", "value": 9 }),
+            json!({ "op": "remove", "path": "/list/0" }),
+        ] {
+            let (status, body) = json_patch(&node, LIMITED_TOKEN, json!([op])).await;
+            assert_eq!(status, 403);
+            assert_eq!(body["path"], json!(["list"]));
+        }
+        assert_eq!(get(&node, "list").await, json!([7, 2, 3]));
+
+        // Allowed with full access
+        let (status, _) = json_patch(&node, TEST_TOKEN, json!([
+            { "op": "add", "path": "/list/1", "value": 9 },
+            { "op": "remove", "path": "/list/0" },
+        ])).await;
+        assert_eq!(status, 200);
+        assert_eq!(get(&node, "list").await, json!([9, 2, 3]));
+        node.kill().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_history_rollback() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), &free_addr());
+        config["history"] = json!({
+            "max_versions": 2
+        });
+        let node = TestNode::start(config).await;
+        for i in 1 ..= 4 {
+            assert_eq!(node.request(Method::POST, "a", Some(json!(i))).await.unwrap().0, 200);
+        }
+        assert_eq!(node.request(Method::GET, "a?version=1", None).await.unwrap().0, 410);
+        assert_eq!(node.request(Method::GET, "a?version=3", None).await.unwrap(), (200, json!(3)));
+
+        // Rolling back makes a new version, so the rolled back version is still retained
+        assert_eq!(node.request(Method::POST, "a?version=3", None).await.unwrap().0, 200);
+        assert_eq!(get(&node, "a").await, json!(3));
+        assert_eq!(node.state.database.read().unwrap().version(), 5);
+        assert_eq!(node.request(Method::GET, "a?version=4", None).await.unwrap(), (200, json!(4)));
+        node.kill().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_audit_diff_redaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), &free_addr());
+        config["audit_diff"] = json!(true);
+        config["users"][LIMITED_TOKEN] = json!([
+            { "path": [{ "string": "fdap_audit" }, "recursive_wildcard"], "action": { "read": true, "write": false } },
+            { "path": [{ "string": "a" }, "recursive_wildcard"], "action": { "read": true, "write": false } },
+            {
+                "path": [{ "string": "a" }, { "string": "secret" }],
+                "action": { "read": false, "write": false, "deny": true },
+            },
+        ]);
+        let node = TestNode::start(config).await;
+        assert_eq!(node.request(Method::POST, "a", Some(json!({
+            "public": 1,
+            "secret": 2
+        }))).await.unwrap().0, 200);
+        assert_eq!(node.request(Method::POST, "b", Some(json!(3))).await.unwrap().0, 200);
+        assert_eq!(node.request(Method::POST, "", Some(json!({
+            "a": {
+                "public": 4,
+                "secret": 5
+            }
+        }))).await.unwrap().0, 200);
+        let (status, entries) =
+            node.request_as(LIMITED_TOKEN, Method::GET, "fdap_audit/a", None, None).await.unwrap();
+        assert_eq!(status, 200);
+        let entries = entries.as_array().unwrap();
+
+        // The write to the sibling `b` is excluded
+        assert_eq!(
+            entries.iter().map(|e| e["path"].clone()).collect::<Vec<_>>(),
+            vec![json!(["a"]), json!([])]
+        );
+
+        // Denied data is removed from the diff
+        assert_eq!(entries[0]["diff"], json!({
+            "old": null,
+            "new": {
+                "public": 1
+            }
+        }));
+
+        // The write to the root includes data the token can't read at all
+        assert_eq!(entries[1]["diff"], json!(null));
+        node.kill().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        let schema_path = dir.path().join("schema.json");
+        std::fs::write(&schema_path, json!({
+            "type": ["object", "null"],
+            "properties": {
+                "a": {
+                    "type": "object",
+                    "properties": {
+                        "x": {
+                            "type": "integer"
+                        }
+                    }
+                }
+            }
+        }).to_string()).unwrap();
+        let mut config = test_config(&dir.path().join("data"), &free_addr());
+        config["schema"] = json!(schema_path);
+        let node = TestNode::start(config).await;
+        assert_eq!(node.request(Method::POST, "a", Some(json!({
+            "x": 1
+        }))).await.unwrap().0, 200);
+
+        // Violations are reported from the database root, not the request path
+        let (status, body) = node.request(Method::POST, "a/x", Some(json!("one"))).await.unwrap();
+        assert_eq!(status, 422);
+        assert_eq!(body["path"], json!(["a", "x"]));
+        assert_eq!(
+            body["violations"].as_array().unwrap().iter().map(|v| v["pointer"].clone()).collect::<Vec<_>>(),
+            vec![json!("/a/x")]
+        );
+        assert_eq!(get(&node, "a/x").await, json!(1));
+        node.kill().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_registered_schemas() {
+        let dir = tempfile::tempdir().unwrap();
+        let node = TestNode::start(test_config(dir.path(), &free_addr())).await;
+        let entry = |schema: serde_json::Value| json!({
+            "path": [{ "string": "user" }, "wildcard", { "string": "app" }],
+            "schema": schema,
+        });
+        let integer = json!({
+            "type": "object",
+            "properties": {
+                "x": {
+                    "type": "integer"
+                }
+            }
+        });
+        assert_eq!(node.request(Method::POST, "user/a/app", Some(json!({
+            "x": "one"
+        }))).await.unwrap().0, 200);
+
+        // Invalid schemas and schemas existing data doesn't match are rejected
+        let (status, body) =
+            node
+                .request(Method::POST, "fdap_schema/app", Some(entry(json!({
+                    "type": "not_a_type"
+                }))))
+                .await
+                .unwrap();
+        assert_eq!(status, 422);
+        assert_eq!(body["violations"][0]["pointer"], json!("/fdap_schema/app"));
+        let (status, body) = node.request(Method::POST, "fdap_schema/app", Some(entry(integer.clone()))).await.unwrap();
+        assert_eq!(status, 422);
+        assert_eq!(body["violations"][0]["pointer"], json!("/user/a/app/x"));
+        assert_eq!(node.request(Method::POST, "user/a/app/x", Some(json!(1))).await.unwrap().0, 200);
+        assert_eq!(node.request(Method::POST, "fdap_schema/app", Some(entry(integer))).await.unwrap().0, 200);
+
+        // Writes to and above matching subtrees are checked, others aren't
+        let (status, body) = node.request(Method::POST, "user/b/app/x", Some(json!("one"))).await.unwrap();
+        assert_eq!(status, 422);
+        assert_eq!(body["violations"][0]["pointer"], json!("/user/b/app/x"));
+        let (status, body) = node.request(Method::POST, "user/b", Some(json!({
+            "app": {
+                "x": "one"
+            }
+        }))).await.unwrap();
+        assert_eq!(status, 422);
+        assert_eq!(body["violations"][0]["pointer"], json!("/user/b/app/x"));
+        assert_eq!(node.request(Method::POST, "user/b/other/x", Some(json!("one"))).await.unwrap().0, 200);
+
+        // Changing the registration replaces the schema used for later writes
+        let (status, _) =
+            node
+                .request(Method::POST, "fdap_schema/app/schema/properties/x/type", Some(json!(["integer", "string"])))
+                .await
+                .unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(node.request(Method::POST, "user/b/app/x", Some(json!("one"))).await.unwrap().0, 200);
+        assert_eq!(node.request(Method::DELETE, "fdap_schema/app", None).await.unwrap().0, 200);
+        assert_eq!(node.request(Method::POST, "user/b/app/x", Some(json!(null))).await.unwrap().0, 200);
+        node.kill().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_tls_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = write_cert(dir.path());
+        let addr = free_addr();
+        let mut config = test_config(&dir.path().join("data"), &addr);
+        config["tls"] = json!({
+            "cert": dir.path().join("cert.pem"),
+            "key": dir.path().join("key.pem"),
+        });
+        let node = TestNode::start(config).await;
+        assert_eq!(served_cert(&addr, &[first.clone()]).await, first);
+
+        // Replace the files, the server should switch at the next check
+        let second = write_cert(dir.path());
+        let certs = &[first.clone(), second.clone()];
+        let addr = &addr;
+        let second = &second;
+        wait_for(super::TLS_CHECK_PERIOD * 3, move || async move {
+            let served = served_cert(addr, certs).await;
+            if served != *second {
+                return None;
+            }
+            return Some(());
+        }).await;
+        node.kill().await;
+    }
+}
@@ -0,0 +1,465 @@
+use {
+    crate::{
+        apply_mutation,
+        dball::DbVersion,
+        dbv1,
+        history::{
+            mutation_undo,
+            take_history_entry,
+        },
+        latest,
+        storage::Storage,
+        wipe_etags,
+        State,
+    },
+    http::{
+        header::{
+            AUTHORIZATION,
+            CONTENT_TYPE,
+            ETAG,
+            IF_MATCH,
+        },
+        Method,
+        Response,
+        Uri,
+    },
+    htwrap::{
+        htreq,
+        htserve::responses::{
+            body_full,
+            Body,
+        },
+        url::UriJoin,
+    },
+    loga::{
+        ea,
+        ErrContext,
+        Log,
+        ResultContext,
+    },
+    openfdap::interface::config::FollowConfig,
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    std::{
+        collections::HashMap,
+        sync::Arc,
+        time::Duration,
+    },
+    taskmanager::TaskManager,
+    tokio::time::{
+        sleep,
+        timeout,
+    },
+};
+
+/// Root key of the endpoint followers read changes from.
+pub const REPLICATE_ROOT: &str = "fdap_replicate";
+
+/// Subpath of `REPLICATE_ROOT` to stop following and accept writes.
+pub const REPLICATE_PROMOTE: &str = "promote";
+
+/// Most records sent in one response.
+pub const REPLICATE_MAX_RECORDS: usize = 1000;
+
+/// How long followers wait for new changes per request.
+const FOLLOW_WAIT: Duration = Duration::from_secs(60);
+const FOLLOW_TIMEOUT_MARGIN: Duration = Duration::from_secs(10);
+const FOLLOW_RETRY: Duration = Duration::from_secs(5);
+
+/// Longest to wait for the leader to handle a forwarded write.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Changes after the version a follower has. If the leader no longer has the
+/// logged changes, or the follower is somehow ahead, it sends a snapshot of the
+/// whole database instead.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ReplicateResponse {
+    pub snapshot: Option<latest::Database>,
+    /// Applied in order, after the snapshot if there is one.
+    pub records: Vec<dbv1::WalRecord>,
+}
+
+#[derive(Clone)]
+pub struct Follow {
+    pub leader: Uri,
+    pub token: String,
+    pub forward_writes: bool,
+}
+
+impl Follow {
+    pub fn new(config: FollowConfig) -> Result<Follow, loga::Error> {
+        return Ok(Follow {
+            leader: Uri::try_from(
+                &config.leader,
+            ).context_with("Leader URL in `follow` config is invalid", ea!(url = config.leader))?,
+            token: config.token,
+            forward_writes: config.forward_writes,
+        });
+    }
+}
+
+/// Get the changes for a follower at `since`. The database lock is held so the
+/// write-ahead log isn't written while it's being read.
+pub fn read_since(
+    self0: &State,
+    db: &dyn Storage,
+    since: DbVersion,
+) -> Result<ReplicateResponse, loga::Error> {
+    if since == db.version() {
+        return Ok(ReplicateResponse {
+            snapshot: None,
+            records: vec![],
+        });
+    }
+    if since < db.version() {
+        if let Some(mut records) = self0.wal.lock().unwrap().read_since(since)? {
+            records.truncate(REPLICATE_MAX_RECORDS);
+            for record in &mut records {
+                // Followers keep their own history
+                record.undo = None;
+            }
+            return Ok(ReplicateResponse {
+                snapshot: None,
+                records: records,
+            });
+        }
+    }
+    return Ok(ReplicateResponse {
+        snapshot: Some(db.snapshot()?),
+        records: vec![],
+    });
+}
+
+/// Replace the database entirely with a snapshot from the leader.
+pub fn install_snapshot(self0: &State, db: &mut dyn Storage, snapshot: latest::Database) -> Result<(), loga::Error> {
+    let mut history = self0.history.lock().unwrap();
+    let mut wal = self0.wal.lock().unwrap();
+    let mut tx = db.transaction()?;
+    tx.replace(&[], snapshot.data)?;
+    tx.commit(snapshot.version)?;
+
+    // The snapshot must be durable before the old logs are removed. Old logs left by
+    // an interruption are ignored when replaying, since they start before the
+    // snapshot's log.
+    db.checkpoint()().context("Failed to write replicated database snapshot")?;
+    wal.reset(snapshot.version).context("Error clearing write-ahead log for snapshot")?;
+    history.clear();
+    wipe_etags(self0, &vec![], None);
+    return Ok(());
+}
+
+/// Log and apply a record from the leader. Like `commit` but with the leader's
+/// version and timestamp, and no audit log entry.
+fn apply_record(self0: &State, db: &mut dyn Storage, record: dbv1::WalRecord) -> Result<(), loga::Error> {
+    if record.version <= db.version() {
+        // Already have it
+        return Ok(());
+    }
+    if record.version != db.version() + 1 {
+        return Err(
+            loga::err_with(
+                "Leader sent changes out of order",
+                ea!(have_version = db.version(), next_version = record.version),
+            ),
+        );
+    }
+    let mut history = self0.history.lock().unwrap();
+    let mut record = dbv1::WalRecord {
+        undo: if history.enabled() {
+            mutation_undo(&*db, &record.mutation)?
+        } else {
+            None
+        },
+        ..record
+    };
+    self0.wal.lock().unwrap().append(&record).context("Failed to log replicated database changes")?;
+    let history_entry = take_history_entry(&mut record);
+    let mut tx = db.transaction()?;
+    match apply_mutation(&mut *tx, record.mutation)? {
+        Ok(changed_path) => {
+            tx.commit(record.version)?;
+            if let Some(entry) = history_entry {
+                history.push(entry);
+            }
+            if let Some(changed_path) = changed_path {
+                wipe_etags(self0, &changed_path, None);
+            }
+        },
+        Err(e) => {
+            // Also failed on the leader, and will be followed by another record for the same
+            // version
+            self0
+                .log
+                .log_with(
+                    loga::DEBUG,
+                    "Skipping replicated mutation that failed to apply",
+                    ea!(version = record.version, err = e),
+                );
+        },
+    }
+    return Ok(());
+}
+
+/// Apply changes from the leader, unless this has been promoted in the meantime.
+fn apply(self0: &State, resp: ReplicateResponse) -> Result<(), loga::Error> {
+    let mut db = self0.database.write().unwrap();
+    if self0.following.read().unwrap().is_none() {
+        return Ok(());
+    }
+    let changed = resp.snapshot.is_some() || !resp.records.is_empty();
+    let res = apply_locked(self0, &mut **db, resp);
+    let version = db.version();
+    drop(db);
+    if changed {
+        self0.changes.send_replace(version);
+    }
+    return res;
+}
+
+fn apply_locked(self0: &State, db: &mut dyn Storage, resp: ReplicateResponse) -> Result<(), loga::Error> {
+    if let Some(snapshot) = resp.snapshot {
+        self0.log.log_with(loga::INFO, "Replacing database with snapshot from leader", ea!(version = snapshot.version));
+        install_snapshot(self0, db, snapshot)?;
+    }
+    for record in resp.records {
+        apply_record(self0, db, record)?;
+    }
+    return Ok(());
+}
+
+/// Stop following the leader and start accepting writes. Returns false if this
+/// wasn't following.
+pub fn promote(self0: &State) -> bool {
+    let _db = self0.database.write().unwrap();
+    return self0.following.write().unwrap().take().is_some();
+}
+
+async fn fetch(
+    log: &Log,
+    conn: &mut Option<htreq::Conn>,
+    follow: &Follow,
+    since: DbVersion,
+) -> Result<ReplicateResponse, loga::Error> {
+    let url =
+        Uri::try_from(
+            format!("{}?since={}&wait={}", follow.leader.join(REPLICATE_ROOT), since, FOLLOW_WAIT.as_secs()),
+        ).context("Error building replication url")?;
+    let limits = htreq::Limits::default();
+    let headers = HashMap::from([(AUTHORIZATION.to_string(), format!("Bearer {}", follow.token))]);
+    return timeout(FOLLOW_WAIT + FOLLOW_TIMEOUT_MARGIN, async {
+        if conn.is_none() {
+            *conn = Some(htreq::connect(limits, &url).await.context("Error connecting to leader")?);
+        }
+        let conn = conn.as_mut().unwrap();
+        let resp = htreq::send(log, limits, conn, &url, Method::GET, &headers, vec![]).await?;
+        let code = resp.code;
+        let body = htreq::receive(resp.body, limits).await?;
+        if !code.is_success() {
+            return Err(
+                loga::err_with(
+                    "Leader rejected replication request",
+                    ea!(status = code, body = String::from_utf8_lossy(&body)),
+                ),
+            );
+        }
+        return Ok(
+            serde_json::from_slice::<ReplicateResponse>(
+                &body,
+            ).context("Leader sent invalid replication response")?,
+        );
+    })
+        .await
+        .unwrap_or_else(|_| Err(loga::err("Replication request to leader timed out")));
+}
+
+/// Copy changes from the leader until shut down or promoted.
+pub async fn follow(log: Log, tm: TaskManager, state: Arc<State>) {
+    let mut conn = None;
+    loop {
+        let Some(follow) = state.following.read().unwrap().clone() else {
+            log.log(loga::INFO, "Promoted, no longer following leader");
+            break;
+        };
+        let since = state.database.read().unwrap().version();
+        let Some(res) = tm.if_alive(fetch(&log, &mut conn, &follow, since)).await else {
+            break;
+        };
+        let res = match res {
+            Ok(resp) => apply(&state, resp).context("Error applying changes from leader"),
+            Err(e) => {
+                // Reconnect in case the connection is broken
+                conn = None;
+                Err(e.context_with("Error getting changes from leader", ea!(leader = follow.leader)))
+            },
+        };
+        if let Err(e) = res {
+            log.log_err(loga::WARN, e);
+            let Some(_) = tm.if_alive(sleep(FOLLOW_RETRY)).await else {
+                break;
+            };
+        }
+    }
+}
+
+/// Send a write to the leader and relay its response.
+pub async fn forward_write(
+    log: &Log,
+    leader: &Uri,
+    head: &http::request::Parts,
+    subpath: &str,
+    body: Vec<u8>,
+) -> Result<Response<Body>, loga::Error> {
+    let mut url = leader.join(subpath).to_string();
+    if let Some(query) = head.uri.query() {
+        url = format!("{}?{}", url, query);
+    }
+    let url = Uri::try_from(url).context("Error building url to forward write to leader")?;
+    let mut headers = HashMap::new();
+    for key in [AUTHORIZATION, CONTENT_TYPE, IF_MATCH] {
+        if let Some(v) = head.headers.get(&key).and_then(|v| v.to_str().ok()) {
+            headers.insert(key.to_string(), v.to_string());
+        }
+    }
+    let limits = htreq::Limits::default();
+    return timeout(FORWARD_TIMEOUT, async {
+        let mut conn = htreq::connect(limits, &url).await.context("Error connecting to leader to forward write")?;
+        let resp = htreq::send(log, limits, &mut conn, &url, head.method.clone(), &headers, body).await?;
+        let mut out = Response::builder().status(resp.code);
+        for key in [CONTENT_TYPE, ETAG] {
+            if let Some(v) = resp.headers.get(&key) {
+                out = out.header(key, v.clone());
+            }
+        }
+        let body = htreq::receive(resp.body, limits).await?;
+        return Ok(out.body(body_full(body)).unwrap());
+    })
+        .await
+        .unwrap_or_else(|_| Err(loga::err("Forwarded write to leader timed out")));
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::{
+            compact,
+            test_util::{
+                free_addr,
+                test_config,
+                wait_value,
+                TestNode,
+                TEST_TOKEN,
+            },
+        },
+        http::Method,
+        serde_json::{
+            json,
+            Value,
+        },
+        std::path::Path,
+    };
+
+    async fn start_follower(dir: &Path, leader: &TestNode, forward_writes: bool) -> TestNode {
+        let mut config = test_config(dir, &free_addr());
+        config["follow"] = json!({
+            "leader": leader.url,
+            "token": TEST_TOKEN,
+            "forward_writes": forward_writes,
+        });
+        return TestNode::start(config).await;
+    }
+
+    async fn set(node: &TestNode, path: &str, value: Value) {
+        assert_eq!(node.request(Method::POST, path, Some(value)).await.unwrap().0, 200);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_follow_catch_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let leader = TestNode::start(test_config(&dir.path().join("leader"), &free_addr())).await;
+        set(&leader, "a", json!(1)).await;
+        set(&leader, "b", json!({
+            "c": 2
+        })).await;
+        let follower = start_follower(&dir.path().join("follower"), &leader, false).await;
+        wait_value(&follower, "a", json!(1)).await;
+        wait_value(&follower, "b/c", json!(2)).await;
+
+        // New changes
+        set(&leader, "a", json!(3)).await;
+        wait_value(&follower, "a", json!(3)).await;
+
+        // Followers are read only
+        assert_eq!(follower.request(Method::POST, "a", Some(json!(4))).await.unwrap().0, 503);
+
+        // Changes while the follower is down
+        follower.kill().await;
+        set(&leader, "d", json!(4)).await;
+        let follower = start_follower(&dir.path().join("follower"), &leader, false).await;
+        wait_value(&follower, "d", json!(4)).await;
+        wait_value(&follower, "a", json!(3)).await;
+        assert_eq!(
+            follower.state.database.read().unwrap().version(),
+            leader.state.database.read().unwrap().version()
+        );
+        follower.kill().await;
+        leader.kill().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_follow_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let leader = TestNode::start(test_config(&dir.path().join("leader"), &free_addr())).await;
+        set(&leader, "a", json!(1)).await;
+        set(&leader, "b", json!(2)).await;
+
+        // The logged changes from the start are gone so the follower needs a snapshot
+        compact(&leader.state).unwrap();
+        set(&leader, "c", json!(3)).await;
+        let follower = start_follower(&dir.path().join("follower"), &leader, false).await;
+        wait_value(&follower, "c", json!(3)).await;
+        wait_value(&follower, "", json!({
+            "a": 1,
+            "b": 2,
+            "c": 3
+        })).await;
+
+        // Followed by normal changes
+        set(&leader, "a", json!(4)).await;
+        wait_value(&follower, "a", json!(4)).await;
+        follower.kill().await;
+        leader.kill().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_follow_promote() {
+        let dir = tempfile::tempdir().unwrap();
+        let leader = TestNode::start(test_config(&dir.path().join("leader"), &free_addr())).await;
+        set(&leader, "a", json!(1)).await;
+        let follower = start_follower(&dir.path().join("follower"), &leader, false).await;
+        wait_value(&follower, "a", json!(1)).await;
+        assert_eq!(follower.request(Method::POST, "fdap_replicate/promote", None).await.unwrap().0, 200);
+        assert!(follower.state.following.read().unwrap().is_none());
+
+        // Now independent of the leader
+        set(&follower, "a", json!(2)).await;
+        wait_value(&follower, "a", json!(2)).await;
+        wait_value(&leader, "a", json!(1)).await;
+        follower.kill().await;
+        leader.kill().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_follow_forward_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let leader = TestNode::start(test_config(&dir.path().join("leader"), &free_addr())).await;
+        let follower = start_follower(&dir.path().join("follower"), &leader, true).await;
+        set(&follower, "a", json!(1)).await;
+        wait_value(&leader, "a", json!(1)).await;
+        wait_value(&follower, "a", json!(1)).await;
+        follower.kill().await;
+        leader.kill().await;
+    }
+}
@@ -0,0 +1,666 @@
+use {
+    crate::{
+        grants::glob_matches,
+        storage::{
+            NodeKind,
+            StorageRead,
+        },
+        DataPath,
+    },
+    loga::{
+        ea,
+        ResultContext,
+    },
+    openfdap::interface::config::AccessPathSeg,
+    serde::Serialize,
+    std::{
+        collections::{
+            BTreeMap,
+            BTreeSet,
+            HashMap,
+        },
+        path::{
+            Path,
+            PathBuf,
+        },
+        sync::{
+            Arc,
+            Mutex,
+        },
+    },
+};
+
+/// Where data doesn't match a schema.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct SchemaViolation {
+    /// JSON pointer to the invalid data, from the root of the database.
+    pub pointer: String,
+    pub message: String,
+}
+
+/// Resolves `$ref`s to files in a directory, refusing anything else. With no
+/// directory, refuses everything.
+struct LocalRetriever {
+    dir: Option<PathBuf>,
+}
+
+impl jsonschema::Retrieve for LocalRetriever {
+    fn retrieve(
+        &self,
+        uri: &jsonschema::Uri<&str>,
+    ) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
+        let Some(dir) = &self.dir else {
+            return Err(format!("External schemas can't be referenced, got [{}]", uri).into());
+        };
+        if uri.scheme().as_str() != "file" {
+            return Err(format!("Only local schema files can be referenced, got [{}]", uri).into());
+        }
+        let path = PathBuf::from(urlencoding::decode(uri.path().as_str())?.as_ref()).canonicalize()?;
+        if !path.starts_with(dir) {
+            return Err(
+                format!("Referenced schema [{}] is outside the schema directory [{}]", uri, dir.display()).into(),
+            );
+        }
+        return Ok(serde_json::from_slice(&std::fs::read(&path)?)?);
+    }
+}
+
+/// What a write did to the data at its path.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ChangeKind {
+    Replace,
+    Create,
+    Delete,
+}
+
+/// Where a write changed data. Nothing outside `path` changed, except that the
+/// parent gained or lost a child if it was created or deleted (and later array
+/// elements shifted, for deletes).
+pub struct Change {
+    pub path: DataPath,
+    pub kind: ChangeKind,
+}
+
+/// Keywords that only constrain a value's type and each of its children on their
+/// own, so a change to one child only needs that child checked. `required`,
+/// `minItems` and `maxItems` are checked without reading the other children.
+const CHILD_ONLY_KEYWORDS: &[&str] = &[
+    "$schema",
+    "$comment",
+    "$anchor",
+    "$defs",
+    "definitions",
+    "title",
+    "description",
+    "default",
+    "examples",
+    "deprecated",
+    "readOnly",
+    "writeOnly",
+    "type",
+    "properties",
+    "additionalProperties",
+    "required",
+    "items",
+    "minItems",
+    "maxItems",
+];
+
+/// The URI schemas without an `$id` are registered as when compiling parts of
+/// them.
+const DEFAULT_SCHEMA_ID: &str = "urn:openfdap:schema";
+
+pub struct Schema {
+    validator: Arc<jsonschema::Validator>,
+    /// The schema document, for finding the parts of it that apply to changed data.
+    document: serde_json::Value,
+    /// Where `$ref`s can be loaded from, see `LocalRetriever`.
+    dir: Option<PathBuf>,
+    /// Validators for parts of the schema by JSON pointer, or nothing if the part
+    /// can't be compiled on its own.
+    parts: Mutex<HashMap<String, Option<Arc<jsonschema::Validator>>>>,
+}
+
+impl Schema {
+    /// Load a schema from a file. `$ref`s can refer to other files in the same
+    /// directory or below, but nothing else.
+    pub fn load(path: &Path) -> Result<Schema, loga::Error> {
+        let path = path.canonicalize().context_with("Error finding schema", ea!(path = path.display()))?;
+        let dir = path.parent().unwrap().to_path_buf();
+        let mut schema =
+            serde_json::from_slice::<serde_json::Value>(
+                &std::fs::read(&path).context_with("Error reading schema", ea!(path = path.display()))?,
+            ).context_with("Schema isn't valid JSON", ea!(path = path.display()))?;
+
+        // Resolve relative `$ref`s against the schema's location
+        if let serde_json::Value::Object(m) = &mut schema {
+            if !m.contains_key("$id") {
+                m.insert("$id".to_string(), serde_json::Value::String(format!("file://{}", path.display())));
+            }
+        }
+        let validator =
+            jsonschema::options()
+                .with_retriever(LocalRetriever { dir: Some(dir.clone()) })
+                .build(&schema)
+                .map_err(|e| loga::err_with("Error compiling schema", ea!(path = path.display(), err = e)))?;
+        return Ok(Schema::new(validator, schema, Some(dir)));
+    }
+
+    /// Compile a schema stored in the database. `$ref`s can only refer to the schema
+    /// itself.
+    pub fn compile(schema: &serde_json::Value) -> Result<Schema, String> {
+        let validator =
+            jsonschema::options()
+                .with_retriever(LocalRetriever { dir: None })
+                .build(schema)
+                .map_err(|e| e.to_string())?;
+        return Ok(Schema::new(validator, schema.clone(), None));
+    }
+
+    fn new(validator: jsonschema::Validator, document: serde_json::Value, dir: Option<PathBuf>) -> Schema {
+        return Schema {
+            validator: Arc::new(validator),
+            document: document,
+            dir: dir,
+            parts: Default::default(),
+        };
+    }
+
+    /// A validator for the part of the schema at `pointer`, compiled as a `$ref` to
+    /// it so references within the document still resolve.
+    fn part(&self, pointer: &str) -> Option<Arc<jsonschema::Validator>> {
+        if pointer.is_empty() {
+            return Some(self.validator.clone());
+        }
+        let mut parts = self.parts.lock().unwrap();
+        if let Some(v) = parts.get(pointer) {
+            return v.clone();
+        }
+        let id = match self.document.get("$id") {
+            Some(serde_json::Value::String(id)) => id.clone(),
+            _ => DEFAULT_SCHEMA_ID.to_string(),
+        };
+        let validator = (|| {
+            let resource = jsonschema::Resource::from_contents(self.document.clone()).ok()?;
+            return jsonschema::options()
+                .with_retriever(LocalRetriever { dir: self.dir.clone() })
+                .with_resource(id.clone(), resource)
+                .build(&serde_json::json!({
+                    "$ref": format!("{}#{}", id, pointer)
+                }))
+                .ok()
+                .map(Arc::new);
+        })();
+        parts.insert(pointer.to_string(), validator.clone());
+        return validator;
+    }
+
+    /// Validate the data at `root` (where the schema applies) after `change`. Where
+    /// the schema allows, only the changed data is read and checked against the
+    /// part of the schema for it, otherwise the data at the deepest level the
+    /// change could make invalid.
+    pub fn validate_change<
+        R: StorageRead + ?Sized,
+    >(&self, root: &DataPath, change: &Change, db: &R) -> Result<Vec<SchemaViolation>, loga::Error> {
+        let mut violations = vec![];
+        let mut at = root.clone();
+        let mut schema = &self.document;
+        let mut pointer = String::new();
+        while at.len() < change.path.len() && change.path.starts_with(&at) {
+            let serde_json::Value::Object(keywords) = schema else {
+                break;
+            };
+            if !keywords.keys().all(|k| CHILD_ONLY_KEYWORDS.contains(&k.as_str()) || (k == "$id" && at == *root)) {
+                break;
+            }
+            let seg = &change.path[at.len()];
+
+            // Whether this level gains or loses a child
+            let resized = at.len() + 1 == change.path.len() && change.kind != ChangeKind::Replace;
+            let (child_pointer, child) = match db.kind(&at)? {
+                Some(NodeKind::Object) => {
+                    if resized && change.kind == ChangeKind::Delete &&
+                        keywords
+                            .get("required")
+                            .and_then(|r| r.as_array())
+                            .is_some_and(|r| r.iter().any(|k| k.as_str() == Some(seg))) {
+                        violations.push(SchemaViolation {
+                            pointer: format_pointer(&at),
+                            message: format!("{} is a required property", serde_json::Value::String(seg.clone())),
+                        });
+                    }
+                    match keywords.get("properties").and_then(|p| p.get(seg)) {
+                        Some(child) => (format!("/properties/{}", pointer_fragment_segment(seg)), child),
+                        None => match keywords.get("additionalProperties") {
+                            Some(child) => ("/additionalProperties".to_string(), child),
+                            None => return Ok(violations),
+                        },
+                    }
+                },
+                Some(NodeKind::Array(len)) => {
+                    if resized {
+                        if let Some(min) = keywords.get("minItems").and_then(|m| m.as_u64()) {
+                            if (len as u64) < min {
+                                violations.push(SchemaViolation {
+                                    pointer: format_pointer(&at),
+                                    message: format!("Array has {} items, fewer than the minimum {}", len, min),
+                                });
+                            }
+                        }
+                        if let Some(max) = keywords.get("maxItems").and_then(|m| m.as_u64()) {
+                            if (len as u64) > max {
+                                violations.push(SchemaViolation {
+                                    pointer: format_pointer(&at),
+                                    message: format!("Array has {} items, more than the maximum {}", len, max),
+                                });
+                            }
+                        }
+                    }
+                    match keywords.get("items") {
+                        Some(child @ (serde_json::Value::Object(_) | serde_json::Value::Bool(_))) => (
+                            "/items".to_string(),
+                            child,
+                        ),
+                        // Tuple validation, depends on the position
+                        Some(_) => break,
+                        None => return Ok(violations),
+                    }
+                },
+                _ => {
+                    // Can't have children, so the changed data is gone
+                    return Ok(violations);
+                },
+            };
+            at.push(seg.clone());
+            pointer.push_str(&child_pointer);
+            schema = child;
+        }
+        if *schema == serde_json::Value::Bool(true) {
+            return Ok(violations);
+        }
+        if change.kind == ChangeKind::Delete && at == change.path {
+            // Nothing left to check, or array elements that shifted down which were
+            // already checked against the same schema
+            return Ok(violations);
+        }
+        let Some(data) = db.get(&at)? else {
+            return Ok(violations);
+        };
+        let Some(validator) = self.part(&pointer) else {
+            // Fall back to checking everything the schema applies to
+            let Some(data) = db.get(root)? else {
+                return Ok(vec![]);
+            };
+            return Ok(self.validate_at(root, &data));
+        };
+        violations.extend(validator.iter_errors(&data).map(|e| SchemaViolation {
+            pointer: format!("{}{}", format_pointer(&at), e.instance_path),
+            message: e.to_string(),
+        }));
+        return Ok(violations);
+    }
+
+    /// Validate `data`, which is at `path` in the database.
+    pub fn validate_at(&self, path: &DataPath, data: &serde_json::Value) -> Vec<SchemaViolation> {
+        let prefix = format_pointer(path);
+        return self.validator.iter_errors(data).map(|e| SchemaViolation {
+            pointer: format!("{}{}", prefix, e.instance_path),
+            message: e.to_string(),
+        }).collect();
+    }
+}
+
+/// A JSON pointer segment, escaped for a URI fragment.
+fn pointer_fragment_segment(seg: &str) -> String {
+    return urlencoding::encode(&seg.replace("~", "~0").replace("/", "~1")).into_owned();
+}
+
+pub fn format_pointer(path: &DataPath) -> String {
+    let mut out = String::new();
+    for seg in path {
+        out.push('/');
+        out.push_str(&seg.replace("~", "~0").replace("/", "~1"));
+    }
+    return out;
+}
+
+/// Find all data matching `pattern`, keyed by path. `pattern` must not have
+/// `variable` segments.
+pub fn find_subtrees<'a>(
+    pattern: &[AccessPathSeg],
+    data: &'a serde_json::Value,
+) -> BTreeMap<DataPath, &'a serde_json::Value> {
+    fn children<'a>(data: &'a serde_json::Value) -> Vec<(String, &'a serde_json::Value)> {
+        match data {
+            serde_json::Value::Object(m) => return m.iter().map(|(k, v)| (k.clone(), v)).collect(),
+            serde_json::Value::Array(a) => return a.iter().enumerate().map(|(i, v)| (i.to_string(), v)).collect(),
+            _ => return vec![],
+        }
+    }
+
+    fn visit<'a>(
+        pattern: &[AccessPathSeg],
+        data: &'a serde_json::Value,
+        at: &mut DataPath,
+        out: &mut BTreeMap<DataPath, &'a serde_json::Value>,
+    ) {
+        let Some((seg, rest)) = pattern.split_first() else {
+            out.insert(at.clone(), data);
+            return;
+        };
+        for (key, child) in children(data) {
+            let matches = match seg {
+                AccessPathSeg::RecursiveWildcard => {
+                    // Consume the segment, keeping the wildcard for deeper matches
+                    at.push(key);
+                    visit(pattern, child, at, out);
+                    at.pop();
+                    continue;
+                },
+                AccessPathSeg::Wildcard => true,
+                AccessPathSeg::Glob(g) => glob_matches(g, &key),
+                AccessPathSeg::Index(i) => data.is_array() && key == i.to_string(),
+                AccessPathSeg::String(s) => *s == key,
+                AccessPathSeg::Variable(_) => unreachable!(),
+            };
+            if matches {
+                at.push(key);
+                visit(rest, child, at, out);
+                at.pop();
+            }
+        }
+        if let AccessPathSeg::RecursiveWildcard = seg {
+            // Or match no segments
+            visit(rest, data, at, out);
+        }
+    }
+
+    let mut out = BTreeMap::new();
+    visit(pattern, data, &mut vec![], &mut out);
+    return out;
+}
+
+/// Find the paths of all data matching `pattern` at, above or below `at`. Only the
+/// data at `at` is read (to find matches below it), and only if the pattern can
+/// match below it. `pattern` must not have `variable` segments.
+pub fn find_overlapping<
+    R: StorageRead + ?Sized,
+>(pattern: &[AccessPathSeg], at: &DataPath, db: &R) -> Result<BTreeSet<DataPath>, loga::Error> {
+    fn visit<'a, R: StorageRead + ?Sized>(
+        pattern: &'a [AccessPathSeg],
+        at: &DataPath,
+        depth: usize,
+        db: &R,
+        below: &mut Vec<&'a [AccessPathSeg]>,
+        out: &mut BTreeSet<DataPath>,
+    ) -> Result<(), loga::Error> {
+        if depth == at.len() {
+            below.push(pattern);
+            return Ok(());
+        }
+        let Some((seg, rest)) = pattern.split_first() else {
+            out.insert(at[..depth].to_vec());
+            return Ok(());
+        };
+        let key = &at[depth];
+        let matches = match seg {
+            AccessPathSeg::RecursiveWildcard => {
+                // Consume the segment, keeping the wildcard for deeper matches, or match no
+                // segments
+                visit(pattern, at, depth + 1, db, below, out)?;
+                visit(rest, at, depth, db, below, out)?;
+                return Ok(());
+            },
+            AccessPathSeg::Wildcard => true,
+            AccessPathSeg::Glob(g) => glob_matches(g, key),
+            AccessPathSeg::Index(i) => *key == i.to_string() &&
+                matches!(db.kind(&at[..depth])?, Some(NodeKind::Array(_))),
+            AccessPathSeg::String(s) => s == key,
+            AccessPathSeg::Variable(_) => unreachable!(),
+        };
+        if matches {
+            visit(rest, at, depth + 1, db, below, out)?;
+        }
+        return Ok(());
+    }
+
+    let mut below = vec![];
+    let mut out = BTreeSet::new();
+    visit(pattern, at, 0, db, &mut below, &mut out)?;
+    if !below.is_empty() {
+        if let Some(data) = db.get(at)? {
+            for pattern in below {
+                for (path, _) in find_subtrees(pattern, &data) {
+                    let mut full_path = at.clone();
+                    full_path.extend(path);
+                    out.insert(full_path);
+                }
+            }
+        }
+    }
+    return Ok(out);
+}
+
+/// The leading `string` segments of `pattern`, which all matches are at or below.
+pub fn pattern_prefix(pattern: &[AccessPathSeg]) -> DataPath {
+    let mut out = vec![];
+    for seg in pattern {
+        let AccessPathSeg::String(s) = seg else {
+            break;
+        };
+        out.push(s.clone());
+    }
+    return out;
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            format_pointer,
+            Change,
+            ChangeKind,
+            Schema,
+        },
+        crate::{
+            storage::{
+                NodeKind,
+                StorageRead,
+            },
+            DataPath,
+        },
+        serde_json::json,
+        std::cell::RefCell,
+    };
+
+    /// Data that records which paths are read in full.
+    struct RecordReads {
+        data: serde_json::Value,
+        reads: RefCell<Vec<DataPath>>,
+    }
+
+    impl StorageRead for RecordReads {
+        fn get(&self, path: &[String]) -> Result<Option<serde_json::Value>, loga::Error> {
+            self.reads.borrow_mut().push(path.to_vec());
+            return StorageRead::get(&self.data, path);
+        }
+
+        fn kind(&self, path: &[String]) -> Result<Option<NodeKind>, loga::Error> {
+            return StorageRead::kind(&self.data, path);
+        }
+    }
+
+    fn p(path: &[&str]) -> DataPath {
+        return path.iter().map(|s| s.to_string()).collect();
+    }
+
+    #[test]
+    fn test_violation_pointer() {
+        let schema = Schema::compile(&json!({
+            "type": "object",
+            "properties": {
+                "x": {
+                    "type": "integer"
+                }
+            }
+        })).unwrap();
+        assert!(schema.validate_at(&vec!["a".to_string()], &json!({
+            "x": 1
+        })).is_empty());
+        let violations = schema.validate_at(&vec!["a/b".to_string(), "c".to_string()], &json!({
+            "x": "1"
+        }));
+        assert_eq!(violations.iter().map(|v| v.pointer.as_str()).collect::<Vec<_>>(), vec!["/a~1b/c/x"]);
+        assert_eq!(format_pointer(&vec!["~".to_string()]), "/~0");
+    }
+
+    #[test]
+    fn test_load_refs() {
+        let dir = tempfile::tempdir().unwrap();
+        let schema_dir = dir.path().join("schema");
+        std::fs::create_dir_all(schema_dir.join("sub")).unwrap();
+        std::fs::write(dir.path().join("x.json"), json!({
+            "type": "integer"
+        }).to_string()).unwrap();
+        std::fs::write(schema_dir.join("sub/x.json"), json!({
+            "type": "integer"
+        }).to_string()).unwrap();
+        let write_root = |r: &str| {
+            let path = schema_dir.join("root.json");
+            std::fs::write(&path, json!({
+                "properties": {
+                    "x": {
+                        "$ref": r
+                    }
+                }
+            }).to_string()).unwrap();
+            return Schema::load(&path);
+        };
+
+        // Files in the schema directory can be referenced
+        let schema = write_root("sub/x.json").unwrap();
+        assert!(schema.validate_at(&vec![], &json!({
+            "x": 1
+        })).is_empty());
+        assert_eq!(schema.validate_at(&vec![], &json!({
+            "x": "1"
+        })).len(), 1);
+
+        // Nothing else can
+        assert!(write_root("../x.json").is_err());
+        assert!(write_root(&format!("file://{}", dir.path().join("x.json").display())).is_err());
+        assert!(write_root("http://127.0.0.1:1/x.json").is_err());
+    }
+
+    #[test]
+    fn test_compile_refs() {
+        assert!(Schema::compile(&json!({
+            "$defs": {
+                "x": {
+                    "type": "integer"
+                }
+            },
+            "properties": {
+                "x": {
+                    "$ref": "#/$defs/x"
+                }
+            }
+        })).is_ok());
+        assert!(Schema::compile(&json!({
+            "$ref": "http://127.0.0.1:1/x.json"
+        })).is_err());
+        assert!(Schema::compile(&json!({
+            "$ref": "file:///etc/passwd"
+        })).is_err());
+    }
+
+    #[test]
+    fn test_validate_change() {
+        let schema = Schema::compile(&json!({
+            "$defs": {
+                "n": {
+                    "type": "integer"
+                }
+            },
+            "type": "object",
+            "required": ["a"],
+            "properties": {
+                "a": {
+                    "type": "object",
+                    "additionalProperties": {
+                        "$ref": "#/$defs/n"
+                    }
+                },
+                "l": {
+                    "type": "array",
+                    "items": {
+                        "type": "string"
+                    },
+                    "maxItems": 2
+                },
+                "o": {
+                    "anyOf": [{
+                        "type": "object"
+                    }]
+                }
+            },
+            "additionalProperties": false
+        })).unwrap();
+
+        // Returns the violation pointers and the paths read
+        let check = |data: serde_json::Value, path: &[&str], kind: ChangeKind| {
+            let db = RecordReads {
+                data: data,
+                reads: Default::default(),
+            };
+            let violations = schema.validate_change(&vec![], &Change {
+                path: p(path),
+                kind: kind,
+            }, &db).unwrap();
+            return (violations.into_iter().map(|v| v.pointer).collect::<Vec<_>>(), db.reads.into_inner());
+        };
+
+        // Only the changed data is read, and checked against the part of the schema for
+        // it
+        assert_eq!(check(json!({
+            "a": {
+                "x": 1,
+                "y": "2"
+            }
+        }), &["a", "x"], ChangeKind::Replace), (vec![], vec![p(&["a", "x"])]));
+        assert_eq!(check(json!({
+            "a": {
+                "x": 1,
+                "y": "2"
+            }
+        }), &["a", "y"], ChangeKind::Create), (vec!["/a/y".to_string()], vec![p(&["a", "y"])]));
+
+        // Parents are checked for the child being added or removed without reading
+        // them
+        assert_eq!(check(json!({
+            "l": []
+        }), &["a"], ChangeKind::Delete), (vec!["".to_string()], vec![]));
+        assert_eq!(check(json!({
+            "a": {},
+            "l": ["a", "b", 3]
+        }), &["l", "2"], ChangeKind::Create), (vec!["/l".to_string(), "/l/2".to_string()], vec![p(&["l", "2"])]));
+        assert_eq!(check(json!({
+            "a": {},
+            "b": 1
+        }), &["b"], ChangeKind::Create), (vec!["/b".to_string()], vec![p(&["b"])]));
+
+        // Schemas that depend on more than the changed child are checked at their level
+        assert_eq!(check(json!({
+            "a": {},
+            "o": {
+                "z": 1
+            }
+        }), &["o", "z"], ChangeKind::Replace), (vec![], vec![p(&["o"])]));
+
+        // Writes at or above the schema root check everything
+        assert_eq!(check(json!({
+            "a": {}
+        }), &[], ChangeKind::Replace), (vec![], vec![p(&[])]));
+    }
+}
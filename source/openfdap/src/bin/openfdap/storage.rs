@@ -0,0 +1,991 @@
+use {
+    crate::{
+        atomic_write,
+        dball::DbVersion,
+        delete_at,
+        json_type,
+        latest,
+        parse_array_index,
+        pointer_get,
+        pointer_get_mut,
+        Database,
+    },
+    loga::{
+        ea,
+        DebugDisplay,
+        ErrContext,
+        ResultContext,
+    },
+    rusqlite::{
+        params,
+        Connection,
+        OptionalExtension,
+    },
+    std::{
+        borrow::Cow,
+        io::ErrorKind,
+        path::{
+            Path,
+            PathBuf,
+        },
+        sync::Mutex,
+    },
+};
+
+/// The type of some data, without the data.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NodeKind {
+    Null,
+    Bool,
+    Number,
+    String,
+    /// With the number of elements
+    Array(usize),
+    Object,
+}
+
+impl NodeKind {
+    pub fn of(v: &serde_json::Value) -> NodeKind {
+        match v {
+            serde_json::Value::Null => return NodeKind::Null,
+            serde_json::Value::Bool(_) => return NodeKind::Bool,
+            serde_json::Value::Number(_) => return NodeKind::Number,
+            serde_json::Value::String(_) => return NodeKind::String,
+            serde_json::Value::Array(a) => return NodeKind::Array(a.len()),
+            serde_json::Value::Object(_) => return NodeKind::Object,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            NodeKind::Null => return "null",
+            NodeKind::Bool => return "bool",
+            NodeKind::Number => return "number",
+            NodeKind::String => return "string",
+            NodeKind::Array(_) => return "array",
+            NodeKind::Object => return "object",
+        }
+    }
+}
+
+pub trait StorageRead {
+    /// A copy of the data at `path`, or nothing if there's no data there.
+    fn get(&self, path: &[String]) -> Result<Option<serde_json::Value>, loga::Error>;
+
+    /// The type of the data at `path`, without reading all of it.
+    fn kind(&self, path: &[String]) -> Result<Option<NodeKind>, loga::Error>;
+}
+
+/// Where the database is kept. The root always exists (it's `null` in a new
+/// database).
+pub trait Storage: StorageRead + Send + Sync {
+    /// The version of the data, set by the last committed transaction.
+    fn version(&self) -> DbVersion;
+
+    /// Start changing the data. Changes are discarded if the transaction is dropped
+    /// without committing.
+    fn transaction(&mut self) -> Result<Box<dyn Transaction + '_>, loga::Error>;
+
+    /// Get a function that makes all committed changes durable, after which logged
+    /// changes up to the current version aren't needed to recover. The function can
+    /// be called without holding the database lock.
+    fn checkpoint(&self) -> Box<dyn FnOnce() -> Result<(), loga::Error> + Send>;
+
+    /// The whole database, for sending to other servers.
+    fn snapshot(&self) -> Result<latest::Database, loga::Error> {
+        return Ok(latest::Database {
+            version: self.version(),
+            data: self.get(&[])?.unwrap_or(serde_json::Value::Null),
+        });
+    }
+}
+
+/// Changes to the data, visible in reads through the transaction but not
+/// elsewhere until committed.
+pub trait Transaction: StorageRead {
+    /// Replace the data at `path`. The parent must be an object, or an array and
+    /// `path` an existing index or the index after the last element (to append).
+    fn replace(&mut self, path: &[String], data: serde_json::Value) -> Result<(), loga::Error>;
+
+    /// Delete the data at `path`. Deleting an array element shifts later elements
+    /// down. The parent must be an object or array, and deleting the root sets it to
+    /// `null`.
+    fn delete(&mut self, path: &[String]) -> Result<(), loga::Error>;
+
+    /// Make the changes visible as `version`.
+    fn commit(self: Box<Self>, version: DbVersion) -> Result<(), loga::Error>;
+}
+
+impl StorageRead for serde_json::Value {
+    fn get(&self, path: &[String]) -> Result<Option<serde_json::Value>, loga::Error> {
+        return Ok(pointer_get(self, path).cloned());
+    }
+
+    fn kind(&self, path: &[String]) -> Result<Option<NodeKind>, loga::Error> {
+        return Ok(pointer_get(self, path).map(NodeKind::of));
+    }
+}
+
+/// Read the database from a JSON file, if it exists.
+pub fn read_json_database(path: &Path) -> Result<Option<latest::Database>, loga::Error> {
+    match std::fs::read(path) {
+        Ok(db) => {
+            match serde_json::from_slice::<Database>(
+                &db,
+            ).context_with("Error parsing database", ea!(path = path.display()))? {
+                Database::V1(db) => return Ok(Some(db.into_owned())),
+            }
+        },
+        Err(e) => {
+            if e.kind() != ErrorKind::NotFound {
+                return Err(e.context_with("Error opening existing database", ea!(path = path.display())));
+            }
+            return Ok(None);
+        },
+    }
+}
+
+/// Replace the data at `path` in a JSON value, see `Transaction::replace`.
+fn replace_at(root: &mut serde_json::Value, path: &[String], data: serde_json::Value) -> Result<(), String> {
+    let Some((last, parent_path)) = path.split_last() else {
+        *root = data;
+        return Ok(());
+    };
+    match pointer_get_mut(root, parent_path) {
+        None => {
+            return Err(format!("Data at path segment {:?} is missing", parent_path));
+        },
+        Some(serde_json::Value::Object(map)) => {
+            map.insert(last.clone(), data);
+        },
+        Some(serde_json::Value::Array(a)) => {
+            match parse_array_index(last) {
+                Some(i) if i < a.len() => {
+                    a[i] = data;
+                },
+                Some(i) if i == a.len() => {
+                    a.push(data);
+                },
+                _ => {
+                    return Err(format!("Array index at path segment {:?} is invalid or out of bounds", path));
+                },
+            }
+        },
+        Some(at) => {
+            return Err(
+                format!("Data at path segment {:?} is a {}, not an object or array", parent_path, json_type(at)),
+            );
+        },
+    }
+    return Ok(());
+}
+
+/// A transaction on data in memory. Changes are made in place, and undone if the
+/// transaction isn't committed.
+pub struct MemoryTransaction<'a> {
+    db: &'a mut latest::Database,
+    /// Paths and the data there before each change, newest last. Nothing means the
+    /// path was created.
+    undo: Vec<(Vec<String>, Option<serde_json::Value>)>,
+}
+
+impl<'a> MemoryTransaction<'a> {
+    pub fn new(db: &'a mut latest::Database) -> MemoryTransaction<'a> {
+        return MemoryTransaction {
+            db: db,
+            undo: vec![],
+        };
+    }
+}
+
+impl<'a> StorageRead for MemoryTransaction<'a> {
+    fn get(&self, path: &[String]) -> Result<Option<serde_json::Value>, loga::Error> {
+        return StorageRead::get(&self.db.data, path);
+    }
+
+    fn kind(&self, path: &[String]) -> Result<Option<NodeKind>, loga::Error> {
+        return StorageRead::kind(&self.db.data, path);
+    }
+}
+
+impl<'a> Transaction for MemoryTransaction<'a> {
+    fn replace(&mut self, path: &[String], data: serde_json::Value) -> Result<(), loga::Error> {
+        let old = pointer_get(&self.db.data, path).cloned();
+        replace_at(
+            &mut self.db.data,
+            path,
+            data,
+        ).map_err(|e| loga::err_with("Error replacing data", ea!(path = path.dbg_str(), err = e)))?;
+        self.undo.push((path.to_vec(), old));
+        return Ok(());
+    }
+
+    fn delete(&mut self, path: &[String]) -> Result<(), loga::Error> {
+        let undo = match path.split_last() {
+            Some((_, parent_path)) if matches!(
+                pointer_get(&self.db.data, parent_path),
+                Some(serde_json::Value::Array(_))
+            ) => {
+                // Later elements shift, so restore the whole array
+                (parent_path.to_vec(), pointer_get(&self.db.data, parent_path).cloned())
+            },
+            _ => (path.to_vec(), pointer_get(&self.db.data, path).cloned()),
+        };
+        delete_at(
+            &mut self.db.data,
+            &path.to_vec(),
+        ).map_err(|e| loga::err_with("Error deleting data", ea!(path = path.dbg_str(), err = e)))?;
+        if undo.1.is_some() {
+            self.undo.push(undo);
+        }
+        return Ok(());
+    }
+
+    fn commit(mut self: Box<Self>, version: DbVersion) -> Result<(), loga::Error> {
+        self.undo.clear();
+        self.db.version = version;
+        return Ok(());
+    }
+}
+
+impl<'a> Drop for MemoryTransaction<'a> {
+    fn drop(&mut self) {
+        for (path, old) in self.undo.drain(..).rev() {
+            match old {
+                Some(old) => {
+                    _ = replace_at(&mut self.db.data, &path, old);
+                },
+                None => {
+                    _ = delete_at(&mut self.db.data, &path);
+                },
+            }
+        }
+    }
+}
+
+/// The whole database in memory, saved to a JSON file when checkpointing.
+pub struct JsonStorage {
+    path: PathBuf,
+    db: latest::Database,
+}
+
+impl JsonStorage {
+    pub fn open(path: &Path) -> Result<JsonStorage, loga::Error> {
+        return Ok(JsonStorage {
+            path: path.to_path_buf(),
+            db: read_json_database(path)?.unwrap_or(latest::Database {
+                version: 0,
+                data: serde_json::Value::Null,
+            }),
+        });
+    }
+}
+
+impl StorageRead for JsonStorage {
+    fn get(&self, path: &[String]) -> Result<Option<serde_json::Value>, loga::Error> {
+        return StorageRead::get(&self.db.data, path);
+    }
+
+    fn kind(&self, path: &[String]) -> Result<Option<NodeKind>, loga::Error> {
+        return StorageRead::kind(&self.db.data, path);
+    }
+}
+
+impl Storage for JsonStorage {
+    fn version(&self) -> DbVersion {
+        return self.db.version;
+    }
+
+    fn transaction(&mut self) -> Result<Box<dyn Transaction + '_>, loga::Error> {
+        return Ok(Box::new(MemoryTransaction::new(&mut self.db)));
+    }
+
+    fn checkpoint(&self) -> Box<dyn FnOnce() -> Result<(), loga::Error> + Send> {
+        let path = self.path.clone();
+        let snapshot = serde_json::to_vec(&Database::V1(Cow::Borrowed(&self.db))).unwrap();
+        return Box::new(move || {
+            return atomic_write(&path, &snapshot);
+        });
+    }
+
+    fn snapshot(&self) -> Result<latest::Database, loga::Error> {
+        return Ok(self.db.clone());
+    }
+}
+
+/// The root node's id.
+const SQLITE_ROOT: i64 = 1;
+
+// Values of the `kind` column. Scalars (including `null`) are stored as JSON in
+// `value`.
+const SQLITE_SCALAR: i64 = 0;
+const SQLITE_ARRAY: i64 = 1;
+const SQLITE_OBJECT: i64 = 2;
+
+/// Each node has its parent and either a key (object children) or position
+/// (array children).
+const SQLITE_SCHEMA: &str = r#"
+    CREATE TABLE IF NOT EXISTS meta (
+        version INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS nodes (
+        id INTEGER PRIMARY KEY,
+        parent INTEGER,
+        key TEXT,
+        pos INTEGER,
+        kind INTEGER NOT NULL,
+        value TEXT
+    );
+    CREATE UNIQUE INDEX IF NOT EXISTS nodes_key ON nodes (parent, key);
+    CREATE INDEX IF NOT EXISTS nodes_pos ON nodes (parent, pos);
+"#;
+
+const SQLITE_NODE_COLUMNS: &str = "id, kind, value";
+
+struct SqliteNode {
+    id: i64,
+    kind: i64,
+    /// For scalars
+    value: Option<serde_json::Value>,
+}
+
+/// Parse a node from a row starting with `SQLITE_NODE_COLUMNS`.
+fn sqlite_node(row: &rusqlite::Row) -> rusqlite::Result<SqliteNode> {
+    let value = match row.get::<_, Option<String>>(2)? {
+        Some(v) => Some(
+            serde_json::from_str(
+                &v,
+            ).map_err(|e| rusqlite::Error::FromSqlConversionFailure(2, rusqlite::types::Type::Text, Box::new(e)))?,
+        ),
+        None => None,
+    };
+    return Ok(SqliteNode {
+        id: row.get(0)?,
+        kind: row.get(1)?,
+        value: value,
+    });
+}
+
+fn sqlite_kind(data: &serde_json::Value) -> (i64, Option<String>) {
+    match data {
+        serde_json::Value::Array(_) => return (SQLITE_ARRAY, None),
+        serde_json::Value::Object(_) => return (SQLITE_OBJECT, None),
+        v => return (SQLITE_SCALAR, Some(v.to_string())),
+    }
+}
+
+fn sqlite_child(conn: &Connection, parent: &SqliteNode, seg: &str) -> rusqlite::Result<Option<SqliteNode>> {
+    match parent.kind {
+        SQLITE_OBJECT => {
+            return conn
+                .prepare_cached(&format!("SELECT {} FROM nodes WHERE parent = ?1 AND key = ?2", SQLITE_NODE_COLUMNS))?
+                .query_row(params![parent.id, seg], sqlite_node)
+                .optional();
+        },
+        SQLITE_ARRAY => {
+            let Some(i) = parse_array_index(seg) else {
+                return Ok(None);
+            };
+            return conn
+                .prepare_cached(&format!("SELECT {} FROM nodes WHERE parent = ?1 AND pos = ?2", SQLITE_NODE_COLUMNS))?
+                .query_row(params![parent.id, i as i64], sqlite_node)
+                .optional();
+        },
+        _ => return Ok(None),
+    }
+}
+
+fn sqlite_find(conn: &Connection, path: &[String]) -> rusqlite::Result<Option<SqliteNode>> {
+    let mut at =
+        conn
+            .prepare_cached(&format!("SELECT {} FROM nodes WHERE id = ?1", SQLITE_NODE_COLUMNS))?
+            .query_row(params![SQLITE_ROOT], sqlite_node)?;
+    for seg in path {
+        let Some(next) = sqlite_child(conn, &at, seg)? else {
+            return Ok(None);
+        };
+        at = next;
+    }
+    return Ok(Some(at));
+}
+
+fn sqlite_len(conn: &Connection, id: i64) -> rusqlite::Result<usize> {
+    return conn
+        .prepare_cached("SELECT COUNT(*) FROM nodes WHERE parent = ?1")?
+        .query_row(params![id], |r| r.get::<_, i64>(0))
+        .map(|n| n as usize);
+}
+
+fn sqlite_node_kind(conn: &Connection, node: &SqliteNode) -> rusqlite::Result<NodeKind> {
+    match node.kind {
+        SQLITE_ARRAY => return Ok(NodeKind::Array(sqlite_len(conn, node.id)?)),
+        SQLITE_OBJECT => return Ok(NodeKind::Object),
+        _ => return Ok(NodeKind::of(node.value.as_ref().unwrap_or(&serde_json::Value::Null))),
+    }
+}
+
+/// Read a node and everything below it.
+fn sqlite_read(conn: &Connection, node: SqliteNode) -> rusqlite::Result<serde_json::Value> {
+    match node.kind {
+        SQLITE_OBJECT => {
+            let children =
+                conn
+                    .prepare_cached(&format!("SELECT {}, key FROM nodes WHERE parent = ?1", SQLITE_NODE_COLUMNS))?
+                    .query_map(params![node.id], |r| Ok((r.get::<_, String>(3)?, sqlite_node(r)?)))?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+            let mut out = serde_json::Map::new();
+            for (key, child) in children {
+                out.insert(key, sqlite_read(conn, child)?);
+            }
+            return Ok(serde_json::Value::Object(out));
+        },
+        SQLITE_ARRAY => {
+            let children =
+                conn
+                    .prepare_cached(
+                        &format!("SELECT {} FROM nodes WHERE parent = ?1 ORDER BY pos", SQLITE_NODE_COLUMNS),
+                    )?
+                    .query_map(params![node.id], sqlite_node)?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+            let mut out = vec![];
+            for child in children {
+                out.push(sqlite_read(conn, child)?);
+            }
+            return Ok(serde_json::Value::Array(out));
+        },
+        _ => return Ok(node.value.unwrap_or(serde_json::Value::Null)),
+    }
+}
+
+fn sqlite_insert_children(conn: &Connection, id: i64, data: serde_json::Value) -> rusqlite::Result<()> {
+    match data {
+        serde_json::Value::Object(m) => {
+            for (k, v) in m {
+                sqlite_insert(conn, id, Some(&k), None, v)?;
+            }
+        },
+        serde_json::Value::Array(a) => {
+            for (i, v) in a.into_iter().enumerate() {
+                sqlite_insert(conn, id, None, Some(i), v)?;
+            }
+        },
+        _ => { },
+    }
+    return Ok(());
+}
+
+/// Add a node for `data` and everything below it.
+fn sqlite_insert(
+    conn: &Connection,
+    parent: i64,
+    key: Option<&str>,
+    pos: Option<usize>,
+    data: serde_json::Value,
+) -> rusqlite::Result<()> {
+    let (kind, value) = sqlite_kind(&data);
+    conn
+        .prepare_cached("INSERT INTO nodes (parent, key, pos, kind, value) VALUES (?1, ?2, ?3, ?4, ?5)")?
+        .execute(params![parent, key, pos.map(|p| p as i64), kind, value])?;
+    return sqlite_insert_children(conn, conn.last_insert_rowid(), data);
+}
+
+fn sqlite_delete_children(conn: &Connection, id: i64) -> rusqlite::Result<()> {
+    conn
+        .prepare_cached(
+            "WITH RECURSIVE below(id) AS (SELECT id FROM nodes WHERE parent = ?1 UNION ALL SELECT nodes.id FROM nodes JOIN below ON nodes.parent = below.id) DELETE FROM nodes WHERE id IN (SELECT id FROM below)",
+        )?
+        .execute(params![id])?;
+    return Ok(());
+}
+
+/// Replace an existing node's data in place.
+fn sqlite_set(conn: &Connection, id: i64, data: serde_json::Value) -> rusqlite::Result<()> {
+    sqlite_delete_children(conn, id)?;
+    let (kind, value) = sqlite_kind(&data);
+    conn.prepare_cached("UPDATE nodes SET kind = ?2, value = ?3 WHERE id = ?1")?.execute(params![id, kind, value])?;
+    return sqlite_insert_children(conn, id, data);
+}
+
+fn sqlite_replace(conn: &Connection, path: &[String], data: serde_json::Value) -> Result<(), loga::Error> {
+    let Some((last, parent_path)) = path.split_last() else {
+        sqlite_set(conn, SQLITE_ROOT, data).context("Error writing to SQLite database")?;
+        return Ok(());
+    };
+    let Some(parent) = sqlite_find(conn, parent_path).context("Error reading SQLite database")? else {
+        return Err(loga::err_with("Parent of replaced data is missing", ea!(path = path.dbg_str())));
+    };
+    let pos = match parent.kind {
+        SQLITE_OBJECT => None,
+        SQLITE_ARRAY => {
+            let len = sqlite_len(conn, parent.id).context("Error reading SQLite database")?;
+            match parse_array_index(last) {
+                Some(i) if i <= len => Some(i),
+                _ => {
+                    return Err(
+                        loga::err_with("Array index of replaced data is invalid or out of bounds", ea!(path = path.dbg_str())),
+                    );
+                },
+            }
+        },
+        _ => {
+            return Err(loga::err_with("Parent of replaced data isn't an object or array", ea!(path = path.dbg_str())));
+        },
+    };
+    let res = match sqlite_child(conn, &parent, last).context("Error reading SQLite database")? {
+        Some(child) => sqlite_set(conn, child.id, data),
+        None => sqlite_insert(conn, parent.id, pos.is_none().then_some(last.as_str()), pos, data),
+    };
+    res.context("Error writing to SQLite database")?;
+    return Ok(());
+}
+
+fn sqlite_delete(conn: &Connection, path: &[String]) -> Result<(), loga::Error> {
+    let Some((last, parent_path)) = path.split_last() else {
+        sqlite_set(conn, SQLITE_ROOT, serde_json::Value::Null).context("Error writing to SQLite database")?;
+        return Ok(());
+    };
+    let Some(parent) = sqlite_find(conn, parent_path).context("Error reading SQLite database")? else {
+        return Err(loga::err_with("Parent of deleted data is missing", ea!(path = path.dbg_str())));
+    };
+    match parent.kind {
+        SQLITE_OBJECT => { },
+        SQLITE_ARRAY => {
+            if parse_array_index(last).is_none() {
+                return Err(loga::err_with("Deleted array element index is invalid", ea!(path = path.dbg_str())));
+            }
+        },
+        _ => {
+            return Err(loga::err_with("Parent of deleted data isn't an object or array", ea!(path = path.dbg_str())));
+        },
+    }
+    let Some(child) = sqlite_child(conn, &parent, last).context("Error reading SQLite database")? else {
+        return Ok(());
+    };
+    sqlite_remove(conn, &parent, child, last).context("Error writing to SQLite database")?;
+    return Ok(());
+}
+
+/// Delete `child` of `parent`, at `seg`, and everything below it. Later array
+/// elements shift down.
+fn sqlite_remove(conn: &Connection, parent: &SqliteNode, child: SqliteNode, seg: &str) -> rusqlite::Result<()> {
+    sqlite_delete_children(conn, child.id)?;
+    conn.prepare_cached("DELETE FROM nodes WHERE id = ?1")?.execute(params![child.id])?;
+    if parent.kind == SQLITE_ARRAY {
+        let pos = parse_array_index(seg).unwrap() as i64;
+        conn
+            .prepare_cached("UPDATE nodes SET pos = pos - 1 WHERE parent = ?1 AND pos > ?2")?
+            .execute(params![parent.id, pos])?;
+    }
+    return Ok(());
+}
+
+/// The database in SQLite with a row per node, so changes and reads only touch
+/// the nodes involved. Commits are only durable after a checkpoint, until then
+/// the server's write-ahead log has the changes.
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+    version: DbVersion,
+}
+
+impl SqliteStorage {
+    /// Open the database at `path`, creating it if it doesn't exist. A new database
+    /// starts with the data in `import`, if any.
+    pub fn open(path: &Path, import: Option<latest::Database>) -> Result<SqliteStorage, loga::Error> {
+        let mut conn =
+            Connection::open(path).context_with("Error opening SQLite database", ea!(path = path.display()))?;
+        conn
+            .pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))
+            .context_with("Error enabling SQLite write-ahead log", ea!(path = path.display()))?;
+        // Every change is already synced to the server's write-ahead log before it's
+        // committed here, so syncing each commit again is wasted. With `NORMAL`, SQLite
+        // only syncs when it checkpoints its own log, and commits can't be corrupted by
+        // a crash, just lost - they're replayed from the server's log on startup.
+        conn
+            .pragma_update(None, "synchronous", "NORMAL")
+            .context_with("Error setting SQLite sync mode", ea!(path = path.display()))?;
+        conn.execute_batch(SQLITE_SCHEMA).context_with("Error setting up SQLite database", ea!(path = path.display()))?;
+        let version =
+            conn
+                .query_row("SELECT version FROM meta", [], |r| r.get::<_, i64>(0))
+                .optional()
+                .context_with("Error reading SQLite database version", ea!(path = path.display()))?;
+        let version = match version {
+            Some(v) => v as DbVersion,
+            None => {
+                let import = import.unwrap_or(latest::Database {
+                    version: 0,
+                    data: serde_json::Value::Null,
+                });
+                let tx = conn.transaction().context("Error starting SQLite transaction")?;
+                tx
+                    .execute("INSERT INTO meta (version) VALUES (?1)", params![import.version as i64])
+                    .context("Error initializing SQLite database version")?;
+                tx
+                    .execute(
+                        "INSERT INTO nodes (id, parent, key, pos, kind, value) VALUES (?1, NULL, NULL, NULL, ?2, 'null')",
+                        params![SQLITE_ROOT, SQLITE_SCALAR],
+                    )
+                    .context("Error initializing SQLite database root")?;
+                sqlite_set(&tx, SQLITE_ROOT, import.data).context("Error writing initial data to SQLite database")?;
+                tx.commit().context_with("Error initializing SQLite database", ea!(path = path.display()))?;
+                import.version
+            },
+        };
+        return Ok(SqliteStorage {
+            conn: Mutex::new(conn),
+            version: version,
+        });
+    }
+}
+
+fn sqlite_get(conn: &Connection, path: &[String]) -> Result<Option<serde_json::Value>, loga::Error> {
+    let Some(node) = sqlite_find(conn, path).context("Error reading SQLite database")? else {
+        return Ok(None);
+    };
+    return Ok(Some(sqlite_read(conn, node).context("Error reading SQLite database")?));
+}
+
+fn sqlite_get_kind(conn: &Connection, path: &[String]) -> Result<Option<NodeKind>, loga::Error> {
+    let Some(node) = sqlite_find(conn, path).context("Error reading SQLite database")? else {
+        return Ok(None);
+    };
+    return Ok(Some(sqlite_node_kind(conn, &node).context("Error reading SQLite database")?));
+}
+
+impl StorageRead for SqliteStorage {
+    fn get(&self, path: &[String]) -> Result<Option<serde_json::Value>, loga::Error> {
+        return sqlite_get(&self.conn.lock().unwrap(), path);
+    }
+
+    fn kind(&self, path: &[String]) -> Result<Option<NodeKind>, loga::Error> {
+        return sqlite_get_kind(&self.conn.lock().unwrap(), path);
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn version(&self) -> DbVersion {
+        return self.version;
+    }
+
+    fn transaction(&mut self) -> Result<Box<dyn Transaction + '_>, loga::Error> {
+        let tx = self.conn.get_mut().unwrap().transaction().context("Error starting SQLite transaction")?;
+        return Ok(Box::new(SqliteTransaction {
+            tx: tx,
+            version: &mut self.version,
+        }));
+    }
+
+    fn checkpoint(&self) -> Box<dyn FnOnce() -> Result<(), loga::Error> + Send> {
+        // Done now, since it needs the connection. It syncs SQLite's log and copies it
+        // into the database file.
+        let res = (|| {
+            let (busy, _, _) =
+                self
+                    .conn
+                    .lock()
+                    .unwrap()
+                    .query_row(
+                        "PRAGMA wal_checkpoint(FULL)",
+                        [],
+                        |r| Ok((r.get::<_, i64>(0)?, r.get::<_, i64>(1)?, r.get::<_, i64>(2)?)),
+                    )
+                    .context("Error checkpointing SQLite database")?;
+            if busy != 0 {
+                return Err(loga::err("SQLite database was busy, checkpoint didn't complete"));
+            }
+            return Ok(());
+        })();
+        return Box::new(move || res);
+    }
+}
+
+struct SqliteTransaction<'a> {
+    tx: rusqlite::Transaction<'a>,
+    version: &'a mut DbVersion,
+}
+
+impl<'a> StorageRead for SqliteTransaction<'a> {
+    fn get(&self, path: &[String]) -> Result<Option<serde_json::Value>, loga::Error> {
+        return sqlite_get(&self.tx, path);
+    }
+
+    fn kind(&self, path: &[String]) -> Result<Option<NodeKind>, loga::Error> {
+        return sqlite_get_kind(&self.tx, path);
+    }
+}
+
+impl<'a> Transaction for SqliteTransaction<'a> {
+    fn replace(&mut self, path: &[String], data: serde_json::Value) -> Result<(), loga::Error> {
+        return sqlite_replace(&self.tx, path, data);
+    }
+
+    fn delete(&mut self, path: &[String]) -> Result<(), loga::Error> {
+        return sqlite_delete(&self.tx, path);
+    }
+
+    fn commit(self: Box<Self>, version: DbVersion) -> Result<(), loga::Error> {
+        let SqliteTransaction { tx, version: version_out } = *self;
+        tx
+            .execute("UPDATE meta SET version = ?1", params![version as i64])
+            .context("Error updating SQLite database version")?;
+        tx.commit().context("Error committing SQLite transaction")?;
+        *version_out = version;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            read_json_database,
+            JsonStorage,
+            SqliteStorage,
+            Storage,
+            StorageRead,
+            Transaction,
+        },
+        crate::latest,
+        serde_json::json,
+    };
+
+    /// The same data in each kind of storage.
+    struct TestStorages {
+        json: JsonStorage,
+        sqlite: SqliteStorage,
+        _dir: tempfile::TempDir,
+    }
+
+    impl TestStorages {
+        fn open(data: serde_json::Value) -> TestStorages {
+            let dir = tempfile::tempdir().unwrap();
+            let mut out = TestStorages {
+                json: JsonStorage::open(&dir.path().join("db.json")).unwrap(),
+                sqlite: SqliteStorage::open(&dir.path().join("db.sqlite"), None).unwrap(),
+                _dir: dir,
+            };
+            assert!(out.write(|tx| tx.replace(&[], data.clone())));
+            return out;
+        }
+
+        /// Make the same changes in each storage, committing them if they succeed.
+        /// Returns whether they succeeded, which must be the same for all storages.
+        fn write(&mut self, f: impl Fn(&mut dyn Transaction) -> Result<(), loga::Error>) -> bool {
+            let version = self.json.version() + 1;
+            let mut results = vec![];
+            for db in [&mut self.json as &mut dyn Storage, &mut self.sqlite] {
+                let mut tx = db.transaction().unwrap();
+                let res = f(&mut *tx);
+                if res.is_ok() {
+                    tx.commit(version).unwrap();
+                }
+                results.push(res.is_ok());
+            }
+            assert_eq!(results[0], results[1]);
+            return results[0];
+        }
+
+        /// Check each storage has the same data, and return it.
+        fn data(&self) -> serde_json::Value {
+            let json = self.json.snapshot().unwrap();
+            let sqlite = self.sqlite.snapshot().unwrap();
+            assert_eq!(json.version, sqlite.version);
+            assert_eq!(json.data, sqlite.data);
+
+            // Nothing is left behind by deletes
+            let rows =
+                self
+                    .sqlite
+                    .conn
+                    .lock()
+                    .unwrap()
+                    .query_row("SELECT count(*) FROM nodes", [], |r| r.get::<_, i64>(0))
+                    .unwrap();
+            assert_eq!(rows, count_nodes(&json.data));
+            return json.data;
+        }
+    }
+
+    fn count_nodes(data: &serde_json::Value) -> i64 {
+        return 1 + match data {
+            serde_json::Value::Array(a) => a.iter().map(count_nodes).sum(),
+            serde_json::Value::Object(o) => o.values().map(count_nodes).sum(),
+            _ => 0,
+        };
+    }
+
+    fn p(path: &[&str]) -> Vec<String> {
+        return path.iter().map(|s| s.to_string()).collect();
+    }
+
+    #[test]
+    fn test_array_shift() {
+        let mut dbs = TestStorages::open(json!({
+            "l": [0, {
+                "x": [1, 2]
+            }, 2, 3]
+        }));
+
+        // Later elements shift down, along with their children
+        assert!(dbs.write(|tx| tx.delete(&p(&["l", "0"]))));
+        assert_eq!(dbs.data(), json!({
+            "l": [{
+                "x": [1, 2]
+            }, 2, 3]
+        }));
+        assert!(dbs.write(|tx| tx.delete(&p(&["l", "0", "x", "0"]))));
+        assert!(dbs.write(|tx| tx.delete(&p(&["l", "1"]))));
+        assert_eq!(dbs.data(), json!({
+            "l": [{
+                "x": [2]
+            }, 3]
+        }));
+
+        // And are still addressed by position afterwards
+        assert!(dbs.write(|tx| tx.replace(&p(&["l", "1"]), json!(4))));
+        assert!(dbs.write(|tx| tx.replace(&p(&["l", "0", "x", "1"]), json!(5))));
+        assert_eq!(dbs.data(), json!({
+            "l": [{
+                "x": [2, 5]
+            }, 4]
+        }));
+
+        // Deleting past the end changes nothing
+        assert!(dbs.write(|tx| tx.delete(&p(&["l", "5"]))));
+        assert!(!dbs.write(|tx| tx.delete(&p(&["l", "x"]))));
+        assert_eq!(dbs.data(), json!({
+            "l": [{
+                "x": [2, 5]
+            }, 4]
+        }));
+    }
+
+    #[test]
+    fn test_replace_or_append() {
+        let mut dbs = TestStorages::open(json!({
+            "l": [0, 1],
+            "o": {
+                "a": {
+                    "b": [1, 2]
+                }
+            },
+            "s": "x"
+        }));
+
+        // Replacing existing data replaces everything below it
+        assert!(dbs.write(|tx| tx.replace(&p(&["l", "1"]), json!({
+            "y": [3]
+        }))));
+        assert!(dbs.write(|tx| tx.replace(&p(&["o", "a"]), json!(2))));
+
+        // One past the end of an array appends, and new keys are added
+        assert!(dbs.write(|tx| tx.replace(&p(&["l", "2"]), json!(4))));
+        assert!(dbs.write(|tx| tx.replace(&p(&["o", "c"]), json!([]))));
+        assert!(dbs.write(|tx| tx.replace(&p(&["o", "c", "0"]), json!(5))));
+        assert_eq!(dbs.data(), json!({
+            "l": [0, {
+                "y": [3]
+            }, 4],
+            "o": {
+                "a": 2,
+                "c": [5]
+            },
+            "s": "x"
+        }));
+
+        // Further past the end, missing parents, and non-container parents are errors
+        assert!(!dbs.write(|tx| tx.replace(&p(&["l", "4"]), json!(6))));
+        assert!(!dbs.write(|tx| tx.replace(&p(&["l", "-"]), json!(6))));
+        assert!(!dbs.write(|tx| tx.replace(&p(&["m", "a"]), json!(6))));
+        assert!(!dbs.write(|tx| tx.replace(&p(&["s", "a"]), json!(6))));
+
+        // Replacing the root replaces everything
+        assert!(dbs.write(|tx| tx.replace(&[], json!([1]))));
+        assert_eq!(dbs.data(), json!([1]));
+        assert!(dbs.write(|tx| tx.delete(&[])));
+        assert_eq!(dbs.data(), json!(null));
+    }
+
+    #[test]
+    fn test_delete_subtree() {
+        let mut dbs = TestStorages::open(json!({
+            "a": {
+                "b": {
+                    "c": [{
+                        "d": 1
+                    }, [2, [3]]]
+                },
+                "e": 4
+            },
+            "f": 5
+        }));
+        assert!(dbs.write(|tx| tx.delete(&p(&["a", "b"]))));
+        assert_eq!(dbs.data(), json!({
+            "a": {
+                "e": 4
+            },
+            "f": 5
+        }));
+        assert!(dbs.write(|tx| tx.delete(&p(&["a"]))));
+        assert_eq!(dbs.data(), json!({
+            "f": 5
+        }));
+    }
+
+    #[test]
+    fn test_rollback() {
+        let mut dbs = TestStorages::open(json!({
+            "a": 1,
+            "l": [1, 2]
+        }));
+        for db in [&mut dbs.json as &mut dyn Storage, &mut dbs.sqlite] {
+            let mut tx = db.transaction().unwrap();
+            tx.replace(&p(&["a"]), json!({
+                "b": 2
+            })).unwrap();
+            tx.delete(&p(&["l", "0"])).unwrap();
+            tx.replace(&p(&["c"]), json!(3)).unwrap();
+
+            // Changes are visible in the transaction
+            assert_eq!(tx.get(&p(&["a", "b"])).unwrap(), Some(json!(2)));
+            assert_eq!(tx.get(&p(&["l"])).unwrap(), Some(json!([2])));
+        }
+        assert_eq!(dbs.data(), json!({
+            "a": 1,
+            "l": [1, 2]
+        }));
+        assert_eq!(dbs.json.version(), 1);
+    }
+
+    #[test]
+    fn test_sqlite_import() {
+        let dir = tempfile::tempdir().unwrap();
+        let json_path = dir.path().join("db.json");
+        let sqlite_path = dir.path().join("db.sqlite");
+        let data = json!({
+            "a": [1, {
+                "b": null
+            }],
+            "c": "d"
+        });
+        let mut json = JsonStorage::open(&json_path).unwrap();
+        let mut tx = json.transaction().unwrap();
+        tx.replace(&[], data.clone()).unwrap();
+        tx.commit(7).unwrap();
+        json.checkpoint()().unwrap();
+
+        // A new database starts from the JSON database
+        let sqlite = SqliteStorage::open(&sqlite_path, read_json_database(&json_path).unwrap()).unwrap();
+        assert_eq!(sqlite.version(), 7);
+        assert_eq!(sqlite.snapshot().unwrap().data, data);
+        drop(sqlite);
+
+        // But only when it's created
+        let sqlite = SqliteStorage::open(&sqlite_path, Some(latest::Database {
+            version: 1,
+            data: json!(null),
+        })).unwrap();
+        assert_eq!(sqlite.version(), 7);
+        assert_eq!(sqlite.snapshot().unwrap().data, data);
+    }
+}
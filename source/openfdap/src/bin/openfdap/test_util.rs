@@ -0,0 +1,162 @@
+use {
+    crate::{
+        serve,
+        State,
+    },
+    http::{
+        header::{
+            AUTHORIZATION,
+            CONTENT_TYPE,
+        },
+        Method,
+        Uri,
+    },
+    htwrap::htreq,
+    loga::{
+        Log,
+        ResultContext,
+    },
+    openfdap::interface::config::Config,
+    serde_json::{
+        json,
+        Value,
+    },
+    std::{
+        collections::HashMap,
+        future::Future,
+        net::TcpListener,
+        path::Path,
+        sync::Arc,
+        time::Duration,
+    },
+    taskmanager::TaskManager,
+    tokio::time::{
+        sleep,
+        Instant,
+    },
+};
+
+/// Token with full access in `test_config`.
+pub const TEST_TOKEN: &str = "test-token";
+
+/// How long to wait for changes to reach other nodes, or for elections.
+pub const TEST_WAIT: Duration = Duration::from_secs(20);
+
+/// Pick an unused localhost address to serve a test node on.
+pub fn free_addr() -> String {
+    return TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+}
+
+/// Minimal config for a node, as JSON so tests can add to it.
+pub fn test_config(data_dir: &Path, bind_addr: &str) -> Value {
+    return json!({
+        "bind_addr": bind_addr,
+        "data_dir": data_dir,
+        "users": {
+            TEST_TOKEN: [{
+                "path": ["recursive_wildcard"],
+                "action": { "read": true, "write": true },
+            }],
+        },
+    });
+}
+
+/// A server running in the test process, with its own task manager so it can be
+/// stopped without affecting other nodes.
+pub struct TestNode {
+    pub log: Log,
+    pub tm: TaskManager,
+    pub state: Arc<State>,
+    pub url: String,
+}
+
+impl TestNode {
+    pub async fn start(config: Value) -> TestNode {
+        let config = serde_json::from_value::<Config>(config).unwrap();
+        let url = format!("http://{}/", config.bind_addr);
+        let log = Log::new_root(loga::INFO);
+        let tm = TaskManager::new();
+        let state = serve(&log, &tm, config).await.unwrap();
+        return TestNode {
+            log: log,
+            tm: tm,
+            state: state,
+            url: url,
+        };
+    }
+
+    /// Stop the server and all its tasks, closing open connections.
+    pub async fn kill(self) {
+        self.tm.terminate();
+        self.tm.join(&self.log).await.unwrap();
+    }
+
+    pub async fn request(&self, method: Method, path: &str, body: Option<Value>) -> Result<(u16, Value), loga::Error> {
+        return request(&self.url, TEST_TOKEN, method, path, None, body).await;
+    }
+
+    /// Make a request with another token or content type.
+    pub async fn request_as(
+        &self,
+        token: &str,
+        method: Method,
+        path: &str,
+        content_type: Option<&str>,
+        body: Option<Value>,
+    ) -> Result<(u16, Value), loga::Error> {
+        return request(&self.url, token, method, path, content_type, body).await;
+    }
+}
+
+/// Make a request on a new connection, returning the status and the body (`null`
+/// if empty or not JSON).
+pub async fn request(
+    base: &str,
+    token: &str,
+    method: Method,
+    path: &str,
+    content_type: Option<&str>,
+    body: Option<Value>,
+) -> Result<(u16, Value), loga::Error> {
+    let log = Log::new_root(loga::INFO);
+    let url = Uri::try_from(format!("{}{}", base, path)).context("Error building url")?;
+    let limits = htreq::Limits::default();
+    let mut headers = HashMap::from([(AUTHORIZATION.to_string(), format!("Bearer {}", token))]);
+    if let Some(content_type) = content_type {
+        headers.insert(CONTENT_TYPE.to_string(), content_type.to_string());
+    }
+    let body = match body {
+        Some(b) => serde_json::to_vec(&b).unwrap(),
+        None => vec![],
+    };
+    let mut conn = htreq::connect(limits, &url).await.context("Error connecting")?;
+    let resp = htreq::send(&log, limits, &mut conn, &url, method, &headers, body).await?;
+    let code = resp.code.as_u16();
+    let body = htreq::receive(resp.body, limits).await?;
+    return Ok((code, serde_json::from_slice(&body).unwrap_or(Value::Null)));
+}
+
+/// Wait until `node` has `value` at `path`.
+pub async fn wait_value(node: &TestNode, path: &str, value: Value) {
+    let value = &value;
+    wait_for(TEST_WAIT, move || async move {
+        match node.request(Method::GET, path, None).await {
+            Ok((200, v)) if v == *value => return Some(()),
+            _ => return None,
+        }
+    }).await;
+}
+
+/// Retry `f` until it returns `Some` or the time runs out.
+pub async fn wait_for<T, F: Future<Output = Option<T>>>(limit: Duration, mut f: impl FnMut() -> F) -> T {
+    let deadline = Instant::now() + limit;
+    loop {
+        if let Some(v) = f().await {
+            return v;
+        }
+        if Instant::now() >= deadline {
+            panic!("Timed out waiting for condition");
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+}
@@ -0,0 +1,462 @@
+use {
+    crate::{
+        apply_mutation,
+        dball::DbVersion,
+        dbv1,
+        history::{
+            take_history_entry,
+            History,
+        },
+        storage::Storage,
+        sync_dir,
+    },
+    loga::{
+        ea,
+        ErrContext,
+        Log,
+        ResultContext,
+    },
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    std::{
+        borrow::Cow,
+        fs::{
+            File,
+            OpenOptions,
+        },
+        io::{
+            ErrorKind,
+            Write,
+        },
+        path::{
+            Path,
+            PathBuf,
+        },
+    },
+};
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum WalRecord<'a> {
+    V1(Cow<'a, dbv1::WalRecord>),
+}
+
+/// Write-ahead log of mutations since the last snapshot, plus older mutations
+/// retained for history. Logs are split into files named by the database version at
+/// the time the file was started - every record in a file is newer than that
+/// version. Records are only replayed from the newest file started at or before
+/// the database version, and later files.
+pub struct Wal {
+    dir: PathBuf,
+    path: PathBuf,
+    file: File,
+    /// Number of records since the last snapshot.
+    pub records: usize,
+}
+
+fn wal_path(dir: &Path, start: DbVersion) -> PathBuf {
+    return dir.join(format!("wal.{}.jsonl", start));
+}
+
+/// All log files in `dir` with their start versions, oldest first.
+fn list_wal_files(dir: &Path) -> Result<Vec<(DbVersion, PathBuf)>, loga::Error> {
+    let mut out = vec![];
+    for entry in std::fs::read_dir(dir).context_with("Error listing data dir", ea!(path = dir.display()))? {
+        let entry = entry.context_with("Error listing data dir", ea!(path = dir.display()))?;
+        let name = entry.file_name();
+        let Some(start) =
+            name
+                .to_str()
+                .and_then(|n| n.strip_prefix("wal."))
+                .and_then(|n| n.strip_suffix(".jsonl"))
+                .and_then(|n| n.parse::<DbVersion>().ok()) else {
+                continue;
+            };
+        out.push((start, entry.path()));
+    }
+    out.sort();
+    return Ok(out);
+}
+
+fn open_append(dir: &Path, path: &Path) -> Result<File, loga::Error> {
+    let file =
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context_with("Error opening write-ahead log", ea!(path = path.display()))?;
+    sync_dir(dir)?;
+    return Ok(file);
+}
+
+impl Wal {
+    /// Replay logged mutations newer than the data in `db` into it, then open the
+    /// log for new records. All logged undo information is loaded into `history`.
+    pub fn open(
+        log: &Log,
+        dir: &Path,
+        db: &mut dyn Storage,
+        history: &mut History,
+    ) -> Result<Wal, loga::Error> {
+        let files = list_wal_files(dir)?;
+        let replay_from = files.iter().rposition(|(start, _)| *start <= db.version()).unwrap_or(0);
+        let mut records = 0;
+        for (i, (_, path)) in files.iter().enumerate() {
+            let data = std::fs::read(path).context_with("Error reading write-ahead log", ea!(path = path.display()))?;
+            let mut offset = 0;
+            while offset < data.len() {
+                let Some(end) = data[offset..].iter().position(|c| *c == b'\n').map(|len| offset + len) else {
+                    if i + 1 != files.len() {
+                        return Err(
+                            loga::err_with(
+                                "Write-ahead log has an unterminated record",
+                                ea!(path = path.display(), offset = offset),
+                            ),
+                        );
+                    }
+
+                    // From a crash while appending, so the write was never acknowledged - safe to
+                    // drop.
+                    log.log_with(
+                        loga::WARN,
+                        "Discarding incomplete final log record",
+                        ea!(path = path.display(), offset = offset),
+                    );
+                    OpenOptions::new()
+                        .write(true)
+                        .open(path)
+                        .and_then(|f| {
+                            f.set_len(offset as u64)?;
+                            return f.sync_all();
+                        })
+                        .context_with("Error truncating write-ahead log", ea!(path = path.display()))?;
+                    break;
+                };
+                let mut record = match serde_json::from_slice::<WalRecord>(&data[offset .. end]) {
+                    Ok(WalRecord::V1(r)) => r.into_owned(),
+                    Err(e) => {
+                        return Err(
+                            e.context_with("Write-ahead log is corrupt", ea!(path = path.display(), offset = offset)),
+                        );
+                    },
+                };
+                offset = end + 1;
+                let history_entry = take_history_entry(&mut record);
+                if record.version <= db.version() {
+                    // Already in the snapshot, retained for history
+                    if let Some(entry) = history_entry {
+                        history.push(entry);
+                    }
+                    continue;
+                }
+                if i < replay_from {
+                    // Left from before a snapshot replaced the database
+                    log.log_with(
+                        loga::DEBUG,
+                        "Ignoring log record older than the database snapshot",
+                        ea!(path = path.display(), version = record.version),
+                    );
+                    continue;
+                }
+                if record.version != db.version() + 1 {
+                    return Err(
+                        loga::err_with(
+                            "Write-ahead log is missing records",
+                            ea!(path = path.display(), have_version = db.version(), next_version = record.version),
+                        ),
+                    );
+                }
+                let mut tx = db.transaction()?;
+                match apply_mutation(&mut *tx, record.mutation)? {
+                    Ok(_) => {
+                        tx.commit(record.version)?;
+                        records += 1;
+                        if let Some(entry) = history_entry {
+                            history.push(entry);
+                        }
+                    },
+                    Err(e) => {
+                        // Also failed when originally committed, so was never visible
+                        log.log_with(
+                            loga::WARN,
+                            "Skipping logged mutation that failed to apply",
+                            ea!(path = path.display(), version = record.version, err = e),
+                        );
+                    },
+                }
+            }
+        }
+        history.end_at(db.version());
+        let path = wal_path(dir, db.version());
+        return Ok(Wal {
+            file: open_append(dir, &path)?,
+            dir: dir.to_path_buf(),
+            path: path,
+            records: records,
+        });
+    }
+
+    /// Durably append a record, returning the length of the log before it for
+    /// `unappend`.
+    pub fn append(&mut self, record: &dbv1::WalRecord) -> Result<u64, loga::Error> {
+        let mut line = serde_json::to_vec(&WalRecord::V1(Cow::Borrowed(record))).unwrap();
+        line.push(b'\n');
+        let start =
+            self
+                .file
+                .metadata()
+                .context_with("Error reading write-ahead log metadata", ea!(path = self.path.display()))?
+                .len();
+        match self.file.write_all(&line).and_then(|_| self.file.sync_data()) {
+            Ok(_) => { },
+            Err(e) => {
+                // Remove any partial record so later records aren't appended to it
+                _ = self.file.set_len(start);
+                return Err(e.context_with("Error writing write-ahead log", ea!(path = self.path.display())));
+            },
+        }
+        self.records += 1;
+        return Ok(start);
+    }
+
+    /// Remove the last record appended, if it couldn't be applied.
+    pub fn unappend(&mut self, start: u64) -> Result<(), loga::Error> {
+        self
+            .file
+            .set_len(start)
+            .and_then(|_| self.file.sync_data())
+            .context_with("Error removing record from write-ahead log", ea!(path = self.path.display()))?;
+        self.records -= 1;
+        return Ok(());
+    }
+
+    /// Logged records newer than `since`, oldest first, or `None` if some have been
+    /// compacted away. There may be records for versions that failed to apply,
+    /// followed by another record for the same version.
+    pub fn read_since(&self, since: DbVersion) -> Result<Option<Vec<dbv1::WalRecord>>, loga::Error> {
+        let files = list_wal_files(&self.dir)?;
+        if !files.first().is_some_and(|(start, _)| *start <= since) {
+            return Ok(None);
+        }
+        let mut out = vec![];
+        for (i, (_, path)) in files.iter().enumerate() {
+            if files.get(i + 1).is_some_and(|(next_start, _)| *next_start <= since) {
+                // All records are at or before `since`
+                continue;
+            }
+            let data = match std::fs::read(path) {
+                Ok(d) => d,
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    // Deleted by a compaction in progress
+                    return Ok(None);
+                },
+                Err(e) => {
+                    return Err(e.context_with("Error reading write-ahead log", ea!(path = path.display())));
+                },
+            };
+            for line in data.split(|c| *c == b'\n') {
+                if line.is_empty() {
+                    continue;
+                }
+                let record = match serde_json::from_slice::<WalRecord>(line) {
+                    Ok(WalRecord::V1(r)) => r.into_owned(),
+                    Err(e) => {
+                        return Err(e.context_with("Write-ahead log is corrupt", ea!(path = path.display())));
+                    },
+                };
+                if record.version > since {
+                    out.push(record);
+                }
+            }
+        }
+        return Ok(Some(out));
+    }
+
+    /// Start a new log file for records after `version` and delete all others, for
+    /// when the database is replaced entirely. A snapshot at `version` must already be
+    /// durable.
+    pub fn reset(&mut self, version: DbVersion) -> Result<(), loga::Error> {
+        let path = wal_path(&self.dir, version);
+        self.file = open_append(&self.dir, &path)?;
+        self.path = path;
+        self.records = 0;
+        for (_, old_path) in list_wal_files(&self.dir)? {
+            if old_path == self.path {
+                continue;
+            }
+            std::fs::remove_file(
+                &old_path,
+            ).context_with("Error deleting write-ahead log", ea!(path = old_path.display()))?;
+        }
+        sync_dir(&self.dir)?;
+        return Ok(());
+    }
+
+    /// Start a new log file for records after `version`. Returns previous log files
+    /// which only have records before `keep_from`, which can be deleted once a
+    /// snapshot at `version` is durable.
+    pub fn rotate(&mut self, version: DbVersion, keep_from: DbVersion) -> Result<Vec<PathBuf>, loga::Error> {
+        let path = wal_path(&self.dir, version);
+        self.file = open_append(&self.dir, &path)?;
+        self.path = path;
+        self.records = 0;
+        let files = list_wal_files(&self.dir)?;
+        let mut out = vec![];
+        for pair in files.windows(2) {
+            let (_, path) = &pair[0];
+            let (next_start, _) = &pair[1];
+
+            // Records in a file are all at or before the next file's start version
+            if *next_start < keep_from {
+                out.push(path.clone());
+            }
+        }
+        return Ok(out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            Wal,
+            WalRecord,
+        },
+        crate::{
+            dball::DbVersion,
+            dbv1,
+            history::History,
+            storage::{
+                JsonStorage,
+                Storage,
+                StorageRead,
+            },
+        },
+        chrono::Utc,
+        loga::Log,
+        serde_json::json,
+        std::{
+            borrow::Cow,
+            path::Path,
+        },
+    };
+
+    fn record(version: DbVersion) -> dbv1::WalRecord {
+        return dbv1::WalRecord {
+            version: version,
+            timestamp: Utc::now(),
+            mutation: dbv1::Mutation::Set {
+                path: vec![format!("k{}", version)],
+                data: json!(version),
+            },
+            undo: None,
+        };
+    }
+
+    fn write_log(dir: &Path, start: DbVersion, versions: &[DbVersion], tail: &[u8]) {
+        let mut data = vec![];
+        for version in versions {
+            data.extend(serde_json::to_vec(&WalRecord::V1(Cow::Owned(record(*version)))).unwrap());
+            data.push(b'\n');
+        }
+        data.extend(tail);
+        std::fs::write(super::wal_path(dir, start), data).unwrap();
+    }
+
+    /// Replay the logs in `dir` into an empty database.
+    fn open(dir: &Path) -> Result<(Wal, JsonStorage), loga::Error> {
+        let mut db = JsonStorage::open(&dir.join("db.json")).unwrap();
+        let wal = Wal::open(&Log::new_root(loga::INFO), dir, &mut db, &mut History::new(None))?;
+        return Ok((wal, db));
+    }
+
+    #[test]
+    fn test_open_truncates_incomplete_final_record() {
+        let dir = tempfile::tempdir().unwrap();
+        write_log(dir.path(), 0, &[1, 2], br#"{"v1":{"vers"#);
+        let (mut wal, db) = open(dir.path()).unwrap();
+        assert_eq!(db.version(), 2);
+        assert_eq!(db.get(&["k2".to_string()]).unwrap(), Some(json!(2)));
+        assert!(
+            std::fs::read(super::wal_path(dir.path(), 0)).unwrap().ends_with(b"\n"),
+            "partial record wasn't removed"
+        );
+
+        // Later records are replayed after the truncated file
+        wal.append(&record(3)).unwrap();
+        drop(wal);
+        let (_, db) = open(dir.path()).unwrap();
+        assert_eq!(db.version(), 3);
+    }
+
+    #[test]
+    fn test_open_rejects_unterminated_non_final_record() {
+        let dir = tempfile::tempdir().unwrap();
+        write_log(dir.path(), 0, &[1], br#"{"v1":{"vers"#);
+        write_log(dir.path(), 1, &[2], b"");
+        assert!(open(dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_open_rejects_missing_records() {
+        let dir = tempfile::tempdir().unwrap();
+        write_log(dir.path(), 0, &[1, 3], b"");
+        assert!(open(dir.path()).is_err());
+
+        // Across files
+        let dir = tempfile::tempdir().unwrap();
+        write_log(dir.path(), 0, &[1], b"");
+        write_log(dir.path(), 2, &[3], b"");
+        assert!(open(dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_open_ignores_logs_before_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // Interrupted while installing a snapshot at 5, after the new log was started
+        write_log(dir.path(), 0, &[1, 2, 3, 4, 5, 6, 7], b"");
+        {
+            let mut db = JsonStorage::open(&dir.path().join("db.json")).unwrap();
+            let tx = db.transaction().unwrap();
+            tx.commit(5).unwrap();
+            db.checkpoint()().unwrap();
+        }
+        write_log(dir.path(), 5, &[6], b"");
+        let (_, db) = open(dir.path()).unwrap();
+        assert_eq!(db.version(), 6);
+        assert_eq!(db.get(&["k6".to_string()]).unwrap(), Some(json!(6)));
+        assert_eq!(db.get(&["k7".to_string()]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut wal, _) = open(dir.path()).unwrap();
+        wal.append(&record(1)).unwrap();
+        wal.append(&record(2)).unwrap();
+        assert_eq!(wal.rotate(2, 0).unwrap(), Vec::<std::path::PathBuf>::new());
+        wal.append(&record(3)).unwrap();
+        wal.append(&record(4)).unwrap();
+
+        // The first file only has records before 3
+        let old = wal.rotate(4, 3).unwrap();
+        assert_eq!(old, vec![super::wal_path(dir.path(), 0)]);
+        wal.append(&record(5)).unwrap();
+        let versions = |since| {
+            return wal
+                .read_since(since)
+                .unwrap()
+                .map(|records| records.iter().map(|r| r.version).collect::<Vec<_>>());
+        };
+        assert_eq!(versions(1), Some(vec![2, 3, 4, 5]));
+        for path in old {
+            std::fs::remove_file(path).unwrap();
+        }
+        assert_eq!(versions(1), None);
+        assert_eq!(versions(2), Some(vec![3, 4, 5]));
+        assert_eq!(versions(5), Some(vec![]));
+    }
+}
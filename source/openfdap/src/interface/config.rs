@@ -1,4 +1,8 @@
 use {
+    chrono::{
+        DateTime,
+        Utc,
+    },
     schemars::JsonSchema,
     serde::{
         Deserialize,
@@ -16,13 +20,30 @@ use {
 pub struct AccessAction {
     pub read: bool,
     pub write: bool,
+    /// Deny all access at this path and everything under it, overriding any other
+    /// (including more specific) grants. Denied data is also removed from the results
+    /// of reading a parent path.
+    #[serde(default)]
+    pub deny: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, JsonSchema, Debug)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub enum AccessPathSeg {
+    /// Matches any number of segments, including none
+    RecursiveWildcard,
+    /// Matches any one segment
     Wildcard,
+    /// Matches a segment using a pattern where `*` matches any characters, like
+    /// `app_*`
+    Glob(String),
+    /// Matches an array index
+    Index(usize),
     String(String),
+    /// Matches the named value from the token's `metadata`, like `subject` - ex: to
+    /// give each user's token access to only their own data. Rules with variables the
+    /// token doesn't have are ignored.
+    Variable(String),
 }
 
 impl PartialOrd for AccessPathSeg {
@@ -33,11 +54,23 @@ impl PartialOrd for AccessPathSeg {
 
 impl Ord for AccessPathSeg {
     fn cmp(&self, other: &Self) -> Ordering {
+        fn rank(s: &AccessPathSeg) -> u8 {
+            match s {
+                AccessPathSeg::RecursiveWildcard => 0,
+                AccessPathSeg::Wildcard => 1,
+                AccessPathSeg::Glob(_) => 2,
+                AccessPathSeg::Index(_) => 3,
+                AccessPathSeg::String(_) => 4,
+                AccessPathSeg::Variable(_) => 5,
+            }
+        }
+
         match (self, other) {
-            (AccessPathSeg::Wildcard, AccessPathSeg::Wildcard) => Ordering::Equal,
-            (AccessPathSeg::Wildcard, AccessPathSeg::String(_)) => Ordering::Less,
-            (AccessPathSeg::String(_), AccessPathSeg::Wildcard) => Ordering::Greater,
+            (AccessPathSeg::Glob(a), AccessPathSeg::Glob(b)) => a.cmp(b),
+            (AccessPathSeg::Index(a), AccessPathSeg::Index(b)) => a.cmp(b),
             (AccessPathSeg::String(a), AccessPathSeg::String(b)) => a.cmp(b),
+            (AccessPathSeg::Variable(a), AccessPathSeg::Variable(b)) => a.cmp(b),
+            (a, b) => rank(a).cmp(&rank(b)),
         }
     }
 }
@@ -51,15 +84,152 @@ pub struct AccessPair {
     pub action: AccessAction,
 }
 
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct UserDetails {
+    pub access: Vec<AccessPair>,
+    /// Values for `variable` segments in access rules.
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    /// The token is rejected before this time.
+    pub not_before: Option<DateTime<Utc>>,
+    /// The token is rejected at and after this time.
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", untagged)]
+pub enum UserEntry {
+    /// Just the access rules.
+    Access(Vec<AccessPair>),
+    Details(UserDetails),
+}
+
+impl UserEntry {
+    pub fn into_details(self) -> UserDetails {
+        match self {
+            UserEntry::Access(access) => return UserDetails {
+                access: access,
+                metadata: Default::default(),
+                not_before: None,
+                expires_at: None,
+            },
+            UserEntry::Details(d) => return d,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct FollowConfig {
+    /// Base URL of the leader, like `https://fdap.example.org/`
+    pub leader: String,
+    /// Token for requests to the leader. It needs read access to `fdap_replicate`
+    /// on the leader.
+    pub token: String,
+    /// Send writes to the leader (with the client's token) rather than rejecting them.
+    #[serde(default)]
+    pub forward_writes: bool,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// This node's ID, a key in `nodes`.
+    pub node_id: String,
+    /// Every node in the cluster including this one, by ID, with its base URL like
+    /// `https://fdap-1.example.org/`. All nodes must have the same `nodes`.
+    pub nodes: HashMap<String, String>,
+    /// Secret shared by all nodes, for authenticating requests between them.
+    pub token: String,
+}
+
+/// A schema in the `fdap_schema` tree of the database, for data at all paths
+/// matching `path`.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct SchemaEntry {
+    /// The paths of the data the schema applies to. `variable` segments aren't
+    /// allowed.
+    pub path: AccessPath,
+    /// A JSON Schema. `$ref`s can only refer to parts of the schema itself.
+    pub schema: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Path to a PEM file containing the certificate chain, leaf first
+    pub cert: PathBuf,
+    /// Path to a PEM file containing the private key for the certificate
+    pub key: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// Keep at most this many previous versions. Unlimited if not set.
+    pub max_versions: Option<usize>,
+    /// Keep previous versions for at most this many seconds. Unlimited if not set.
+    pub max_age_secs: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum StorageBackend {
+    /// Keep the whole database in memory, writing all of it to `db.json` when the
+    /// log is compacted.
+    #[default]
+    Json,
+    /// Store each value separately in `db.sqlite`, so only the data being read or
+    /// changed is loaded or written. For databases too large to keep in memory or
+    /// rewrite whole. If there's no `db.sqlite` yet, the data in `db.json` is copied
+    /// into it.
+    Sqlite,
+}
+
 #[derive(Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct Config {
     /// Address to serve on, like `0.0.0.0:64116`
     pub bind_addr: String,
+    /// Serve HTTPS using this certificate and key rather than plain HTTP. The files
+    /// are reloaded when they change or when the server receives `SIGHUP`.
+    pub tls: Option<TlsConfig>,
+    /// Retain previous versions of the database, to read data as of an earlier
+    /// version or roll it back. If not set, no history is kept.
+    pub history: Option<HistoryConfig>,
+    /// Include the data before and after each change in the audit log. This makes it
+    /// much larger, and it will contain copies of everything written.
+    #[serde(default)]
+    pub audit_diff: bool,
     /// Directory in which to store database, will be created if it doesn't exist
     pub data_dir: PathBuf,
+    /// How to store the database in `data_dir`. Defaults to `json`.
+    #[serde(default)]
+    pub storage: StorageBackend,
+    /// A JSON Schema file that the whole database must match. Writes that would make
+    /// the database invalid are rejected. `$ref`s in the schema can only refer to
+    /// files in the schema's directory (or below), using relative paths or `file://`
+    /// URLs.
+    pub schema: Option<PathBuf>,
+    /// Run as a read-only follower of another openfdap server (the leader), copying
+    /// all its changes. The follower's own data dir should be empty or from
+    /// following the same leader before.
+    pub follow: Option<FollowConfig>,
+    /// Run as a node in a cluster, where writes are committed once a majority of nodes
+    /// have them. Writes to nodes other than the leader are sent to the leader. Can't
+    /// be used with `follow`.
+    pub cluster: Option<ClusterConfig>,
     /// Mapping of application tokens to access - for setting up tokens for
     /// applications to access FDAP. This can also be done (identically) via the
     /// `fdap_user` root key in the FDAP tree.
-    pub users: HashMap<String, Vec<AccessPair>>,
+    ///
+    /// Keys can be the token itself or `sha256:` followed by the zbase32-encoded
+    /// sha256 hash of the token (generate with `--generate-token`). Tokens starting
+    /// with `sha256:` are only matched by their hash.
+    ///
+    /// Values are either a list of access rules, or an object with the rules
+    /// (`access`) and other details about the token.
+    pub users: HashMap<String, UserEntry>,
 }